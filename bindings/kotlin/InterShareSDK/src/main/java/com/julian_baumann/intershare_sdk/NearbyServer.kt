@@ -117,7 +117,7 @@ class NearbyServer(context: Context, myDevice: Device, delegate: NearbyConnectio
         return internal.shareText(text, allowConvenienceDownload)
     }
 
-    suspend fun requestDownload(link: String) {
+    suspend fun requestDownload(link: String): ConnectionRequest {
         return internal.requestDownload(link)
     }
 
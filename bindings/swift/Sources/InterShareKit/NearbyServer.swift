@@ -119,8 +119,8 @@ public class NearbyServer {
         return await internalHandler.shareText(text: text, allowConvenienceShare: allowConvenienceShare)
     }
     
-    public func requestDownload(link: String) async throws {
-        try await internalHandler.requestDownload(link: link)
+    public func requestDownload(link: String) async throws -> ConnectionRequest {
+        return try await internalHandler.requestDownload(link: link)
     }
 
     public func stop() async throws {
@@ -1,24 +1,232 @@
-use crate::encryption::generate_iv;
-use crate::encryption::EncryptedStream;
-use log::info;
+use crate::encryption::{
+    derive_session_keys, generate_iv, generate_verification_nonce, handshake_transcript,
+    verification_code, verification_commitment, AuthenticatedStream, EncryptedReadWrite,
+    EncryptedStream, HandshakeRole,
+};
+use crate::errors::HandshakeError;
+use crate::identity::{verify_identity_proof, IdentityStore};
+use crate::stream::Close;
+use crate::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use log::{info, warn};
 use prost_stream::Stream;
-use protocol::communication::{EncryptionRequest, EncryptionResponse};
+use protocol::communication::{
+    EncryptionMode, EncryptionRequest, EncryptionResponse, Hello, HelloAck, IdentityProof,
+    VerificationNonce,
+};
 use rand_core::OsRng;
 use std::error::Error;
 use std::io::{Read, Write};
 use x25519_dalek::{EphemeralSecret, PublicKey};
 
+/// The sender appends a `TransferTrailer` with a hash of every file to the archive.
+pub const CAPABILITY_FILE_HASHES: u64 = 1 << 0;
+
+/// The sender may compress entries of the archive with zstd, flagged in their PAX headers.
+pub const CAPABILITY_ZSTD: u64 = 1 << 1;
+
+/// Either peer can abort a transfer with a cancel frame, see `EncryptedReadWrite::cancel`.
+pub const CAPABILITY_CANCEL: u64 = 1 << 2;
+
+/// The receiver confirms with a `TransferResult` whether it stored everything it received.
+pub const CAPABILITY_TRANSFER_RESULT: u64 = 1 << 3;
+
+/// The receiver answers text shares with a `TransferRequestResponse`, like file transfers.
+/// The sender then sends the text as a `ClipboardTransferIntent`, instead of inside the request.
+pub const CAPABILITY_TEXT_RESPONSE: u64 = 1 << 4;
+
+/// Capability flags this build supports. Announced in the `Hello` message.
+pub const SUPPORTED_CAPABILITIES: u64 = CAPABILITY_FILE_HASHES
+    | CAPABILITY_ZSTD
+    | CAPABILITY_CANCEL
+    | CAPABILITY_TRANSFER_RESULT
+    | CAPABILITY_TEXT_RESPONSE;
+
+/// Peers which predate the `Hello` exchange speak this protocol version.
+const LEGACY_PROTOCOL_VERSION: u32 = 0;
+
+/// Encrypted stream together with what was negotiated during the handshake.
+pub struct EncryptedConnection {
+    pub stream: Box<dyn EncryptedReadWrite>,
+
+    /// Code both users can compare to detect a man-in-the-middle.
+    /// `None` if the remote peer does not support verification.
+    pub verification_code: Option<String>,
+
+    /// Identity key the remote peer proved ownership of.
+    /// `None` if the remote peer does not support identity keys.
+    pub peer_identity_key: Option<[u8; 32]>,
+
+    /// Capability flags supported by both peers. Optional behaviour depends on these, the
+    /// protocol version only decides whether both peers can talk to each other at all.
+    pub capabilities: u64,
+}
+
+/// Whether the remote peer fell back to the unauthenticated legacy mode, although it
+/// completed the authenticated handshake before. A man-in-the-middle can get there by
+/// stripping `supported_modes` from the `EncryptionRequest`.
+pub fn is_downgrade(
+    connection: &EncryptedConnection,
+    identity: &IdentityStore,
+    peer_device_id: &str,
+) -> bool {
+    if connection.verification_code.is_some() || !identity.has_pinned_key(peer_device_id) {
+        return false;
+    }
+
+    warn!(
+        "Device {} downgraded to an unauthenticated session",
+        peer_device_id
+    );
+    return true;
+}
+
+/// Highest protocol version supported by both this build and the remote peer.
+#[allow(clippy::absurd_extreme_comparisons)]
+pub fn negotiate_protocol_version(remote_min_version: u32, remote_max_version: u32) -> Option<u32> {
+    if remote_max_version < MIN_PROTOCOL_VERSION || remote_min_version > PROTOCOL_VERSION {
+        return None;
+    }
+
+    if remote_max_version < PROTOCOL_VERSION {
+        return Some(remote_max_version);
+    }
+
+    return Some(PROTOCOL_VERSION);
+}
+
+fn check_legacy_protocol_version() -> Result<(), Box<dyn Error>> {
+    if negotiate_protocol_version(LEGACY_PROTOCOL_VERSION, LEGACY_PROTOCOL_VERSION).is_none() {
+        return Err(Box::new(HandshakeError::IncompatibleProtocolVersion));
+    }
+
+    return Ok(());
+}
+
+/// Returns the capabilities both peers support.
+fn send_hello(stream: &mut Box<dyn EncryptedReadWrite>) -> Result<u64, Box<dyn Error>> {
+    let mut prost_stream = Stream::new(stream);
+
+    let _ = prost_stream.send(&Hello {
+        min_protocol_version: MIN_PROTOCOL_VERSION,
+        max_protocol_version: PROTOCOL_VERSION,
+        capabilities: SUPPORTED_CAPABILITIES,
+    });
+
+    let hello_ack = match prost_stream.recv::<HelloAck>() {
+        Ok(message) => message,
+        Err(error) => return Err(Box::new(error)),
+    };
+
+    let Some(protocol_version) = hello_ack.protocol_version else {
+        return Err(Box::new(HandshakeError::IncompatibleProtocolVersion));
+    };
+
+    if negotiate_protocol_version(protocol_version, protocol_version).is_none() {
+        return Err(Box::new(HandshakeError::IncompatibleProtocolVersion));
+    }
+
+    return Ok(hello_ack.capabilities & SUPPORTED_CAPABILITIES);
+}
+
+/// Returns the capabilities both peers support.
+fn answer_hello(stream: &mut Box<dyn EncryptedReadWrite>) -> Result<u64, Box<dyn Error>> {
+    let mut prost_stream = Stream::new(stream);
+
+    let hello = match prost_stream.recv::<Hello>() {
+        Ok(message) => message,
+        Err(error) => return Err(Box::new(error)),
+    };
+
+    let protocol_version =
+        negotiate_protocol_version(hello.min_protocol_version, hello.max_protocol_version);
+    let capabilities = hello.capabilities & SUPPORTED_CAPABILITIES;
+
+    let _ = prost_stream.send(&HelloAck {
+        protocol_version,
+        capabilities,
+    });
+
+    if protocol_version.is_none() {
+        warn!(
+            "Remote peer speaks protocol versions {} to {}",
+            hello.min_protocol_version, hello.max_protocol_version
+        );
+        return Err(Box::new(HandshakeError::IncompatibleProtocolVersion));
+    }
+
+    return Ok(capabilities);
+}
+
+fn read_verification_nonce(
+    stream: &mut Box<dyn EncryptedReadWrite>,
+) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut prost_stream = Stream::new(stream);
+
+    let message = match prost_stream.recv::<VerificationNonce>() {
+        Ok(message) => message,
+        Err(error) => return Err(Box::new(error)),
+    };
+
+    return message
+        .nonce
+        .try_into()
+        .map_err(|_| HandshakeError::InvalidVerificationNonce.into());
+}
+
+fn send_identity_proof(
+    stream: &mut Box<dyn EncryptedReadWrite>,
+    identity: &IdentityStore,
+    transcript: &[u8; 32],
+    role: HandshakeRole,
+) {
+    let _ = Stream::new(stream).send(&IdentityProof {
+        public_key: identity.public_key().to_vec(),
+        signature: identity.sign_transcript(transcript, role).to_vec(),
+    });
+}
+
+fn read_identity_proof(
+    stream: &mut Box<dyn EncryptedReadWrite>,
+    transcript: &[u8; 32],
+    remote_role: HandshakeRole,
+) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut prost_stream = Stream::new(stream);
+
+    let identity_proof = match prost_stream.recv::<IdentityProof>() {
+        Ok(message) => message,
+        Err(error) => return Err(Box::new(error)),
+    };
+
+    let (Ok(public_key), Ok(signature)) = (
+        <[u8; 32]>::try_from(identity_proof.public_key),
+        <[u8; 64]>::try_from(identity_proof.signature),
+    ) else {
+        return Err(Box::new(HandshakeError::InvalidIdentityProof));
+    };
+
+    if !verify_identity_proof(&public_key, transcript, remote_role, &signature) {
+        return Err(Box::new(HandshakeError::InvalidIdentityProof));
+    }
+
+    return Ok(public_key);
+}
+
 pub async fn initiate_sender_communication<T>(
     mut stream: T,
-) -> Result<EncryptedStream<T>, Box<dyn Error>>
+    identity: &IdentityStore,
+) -> Result<EncryptedConnection, Box<dyn Error>>
 where
-    T: Read + Write,
+    T: Read + Write + Send + Close + 'static,
 {
     info!("[Encryption] Initiating sender encryption communication");
     let secret = EphemeralSecret::random_from_rng(OsRng);
     let public_key = PublicKey::from(&secret);
     let encryption_request = EncryptionRequest {
         public_key: public_key.as_bytes().to_vec(),
+        supported_modes: vec![
+            EncryptionMode::Xchacha20Poly1305Stream as i32,
+            EncryptionMode::Xchacha20 as i32,
+        ],
     };
 
     info!("[Encryption] Sending public key");
@@ -32,11 +240,12 @@ where
 
     info!("[Encryption] Received foreign public key");
 
-    let public_key: [u8; 32] = encryption_response
+    let mode = encryption_response.mode();
+    let foreign_public_key: [u8; 32] = encryption_response
         .public_key
         .try_into()
         .expect("Vec length is not 32");
-    let foreign_public_key = PublicKey::from(public_key);
+    let foreign_public_key = PublicKey::from(foreign_public_key);
 
     info!("[Encryption] Doin the diffie hellman. Yeah.");
     let shared_secret = secret.diffie_hellman(&foreign_public_key);
@@ -46,16 +255,76 @@ where
         .try_into()
         .expect("Vec length is not 24");
 
-    let encrypted_stream = EncryptedStream::new(shared_secret.to_bytes(), iv, stream);
+    info!("[Encryption] Using {:?}", mode);
+
+    if mode == EncryptionMode::Xchacha20 {
+        check_legacy_protocol_version()?;
+
+        return Ok(EncryptedConnection {
+            stream: Box::new(EncryptedStream::new(shared_secret.to_bytes(), iv, stream)),
+            verification_code: None,
+            peer_identity_key: None,
+            capabilities: 0,
+        });
+    }
+
+    let transcript = handshake_transcript(
+        public_key.as_bytes(),
+        foreign_public_key.as_bytes(),
+        &iv,
+        mode as i32,
+    );
+    let session_keys = derive_session_keys(
+        shared_secret.as_bytes(),
+        &transcript,
+        HandshakeRole::Initiator,
+    );
+
+    let mut encrypted_stream: Box<dyn EncryptedReadWrite> =
+        Box::new(AuthenticatedStream::new(&session_keys, stream));
+
+    // The responder committed to its nonce in the EncryptionResponse, before it knew ours.
+    let initiator_nonce = generate_verification_nonce();
+    let _ = Stream::new(&mut encrypted_stream).send(&VerificationNonce {
+        nonce: initiator_nonce.to_vec(),
+    });
 
-    return Ok(encrypted_stream);
+    let responder_nonce = read_verification_nonce(&mut encrypted_stream)?;
+
+    if verification_commitment(&transcript, &responder_nonce).as_slice()
+        != encryption_response.verification_commitment.as_slice()
+    {
+        return Err(Box::new(HandshakeError::VerificationCommitmentMismatch));
+    }
+
+    send_identity_proof(
+        &mut encrypted_stream,
+        identity,
+        &transcript,
+        HandshakeRole::Initiator,
+    );
+    let peer_identity_key =
+        read_identity_proof(&mut encrypted_stream, &transcript, HandshakeRole::Responder)?;
+    let capabilities = send_hello(&mut encrypted_stream)?;
+
+    return Ok(EncryptedConnection {
+        stream: encrypted_stream,
+        verification_code: Some(verification_code(
+            &transcript,
+            &initiator_nonce,
+            &responder_nonce,
+        )),
+        peer_identity_key: Some(peer_identity_key),
+        capabilities,
+    });
 }
 
 pub fn initiate_receiver_communication<T>(
     mut stream: T,
-) -> Result<EncryptedStream<T>, Box<dyn Error>>
+    identity: &IdentityStore,
+) -> Result<EncryptedConnection, Box<dyn Error>>
 where
-    T: Read + Write,
+    T: Read + Write + Send + Close + 'static,
 {
     let secret = EphemeralSecret::random_from_rng(OsRng);
     let public_key = PublicKey::from(&secret);
@@ -69,20 +338,89 @@ where
         Err(error) => return Err(Box::new(error)),
     };
 
-    let _ = prost_stream.send(&EncryptionResponse {
-        public_key: public_key.as_bytes().to_vec(),
-        iv: iv.to_vec(),
-    });
+    // Peers which predate the mode negotiation don't send any supported modes and only
+    // understand the unauthenticated XChaCha20 stream.
+    let mode = if encryption_request
+        .supported_modes()
+        .any(|mode| mode == EncryptionMode::Xchacha20Poly1305Stream)
+    {
+        EncryptionMode::Xchacha20Poly1305Stream
+    } else {
+        EncryptionMode::Xchacha20
+    };
 
-    let public_key: [u8; 32] = encryption_request
+    let foreign_public_key: [u8; 32] = encryption_request
         .public_key
         .try_into()
         .expect("Vec length is not 32");
-    let foreign_public_key = PublicKey::from(public_key);
+    let foreign_public_key = PublicKey::from(foreign_public_key);
+
+    let transcript = handshake_transcript(
+        foreign_public_key.as_bytes(),
+        public_key.as_bytes(),
+        &iv,
+        mode as i32,
+    );
+    let responder_nonce = generate_verification_nonce();
+
+    let _ = prost_stream.send(&EncryptionResponse {
+        public_key: public_key.as_bytes().to_vec(),
+        iv: iv.to_vec(),
+        mode: mode as i32,
+        verification_commitment: if mode == EncryptionMode::Xchacha20 {
+            vec![]
+        } else {
+            verification_commitment(&transcript, &responder_nonce).to_vec()
+        },
+    });
 
     let shared_secret = secret.diffie_hellman(&foreign_public_key);
 
-    let encrypted_stream = EncryptedStream::new(shared_secret.to_bytes(), iv, stream);
+    info!("[Encryption] Using {:?}", mode);
+
+    if mode == EncryptionMode::Xchacha20 {
+        check_legacy_protocol_version()?;
+
+        return Ok(EncryptedConnection {
+            stream: Box::new(EncryptedStream::new(shared_secret.to_bytes(), iv, stream)),
+            verification_code: None,
+            peer_identity_key: None,
+            capabilities: 0,
+        });
+    }
+
+    let session_keys = derive_session_keys(
+        shared_secret.as_bytes(),
+        &transcript,
+        HandshakeRole::Responder,
+    );
 
-    return Ok(encrypted_stream);
+    let mut encrypted_stream: Box<dyn EncryptedReadWrite> =
+        Box::new(AuthenticatedStream::new(&session_keys, stream));
+
+    let initiator_nonce = read_verification_nonce(&mut encrypted_stream)?;
+    let _ = Stream::new(&mut encrypted_stream).send(&VerificationNonce {
+        nonce: responder_nonce.to_vec(),
+    });
+
+    let peer_identity_key =
+        read_identity_proof(&mut encrypted_stream, &transcript, HandshakeRole::Initiator)?;
+    send_identity_proof(
+        &mut encrypted_stream,
+        identity,
+        &transcript,
+        HandshakeRole::Responder,
+    );
+    let capabilities = answer_hello(&mut encrypted_stream)?;
+
+    return Ok(EncryptedConnection {
+        stream: encrypted_stream,
+        verification_code: Some(verification_code(
+            &transcript,
+            &initiator_nonce,
+            &responder_nonce,
+        )),
+        peer_identity_key: Some(peer_identity_key),
+        capabilities,
+    });
 }
@@ -1,30 +1,25 @@
-use crate::discovery::get_connection_details;
+use crate::discovery::DiscoveryContext;
 use crate::{
-    communication::initiate_sender_communication,
-    encryption::{EncryptedReadWrite, EncryptedStream},
-    errors::ConnectErrors,
+    communication::{initiate_sender_communication, is_downgrade, EncryptedConnection},
+    errors::{ConnectErrors, HandshakeError},
+    identity::{DeviceTrust, IdentityStore},
     nearby_server::L2CapDelegate,
     share_store::{ConnectionMedium, SendProgressDelegate, SendProgressState},
-    stream::NativeStreamDelegate,
+    stream::{Close, NativeStreamDelegate},
     transmission::tcp::TcpClient,
 };
 use log::{error, info};
 use protocol::discovery::{Device, DeviceConnectionInfo};
 use std::{
-    collections::HashMap,
     io::{Read, Write},
     net::ToSocketAddrs,
-    sync::{Arc, OnceLock},
-};
-use tokio::sync::{
-    oneshot::{self, Sender},
-    RwLock,
+    sync::Arc,
 };
+use tokio::sync::{oneshot, RwLock};
 use uuid::Uuid;
 
-static L2CAP_CONNECTIONS: OnceLock<RwLock<HashMap<String, Sender<Box<dyn NativeStreamDelegate>>>>> =
-    OnceLock::new();
-
+/// Passes an L2CAP connection to the default `DiscoveryContext`. Connections of other contexts
+/// are passed using `DiscoveryContext::handle_incoming_l2cap_connection()`.
 #[uniffi::export]
 pub async fn handle_incoming_l2cap_connection(
     connection_id: String,
@@ -32,20 +27,14 @@ pub async fn handle_incoming_l2cap_connection(
 ) {
     info!("Received incomming L2CAP connection");
 
-    let sender = L2CAP_CONNECTIONS
-        .get_or_init(|| RwLock::new(HashMap::new()))
-        .write()
-        .await
-        .remove(&connection_id);
-
-    if let Some(sender) = sender {
-        info!("Passing incomming L2CAP connection...");
-        let _ = sender.send(native_stream);
-    }
+    DiscoveryContext::default_context()
+        .handle_incoming_l2cap_connection(connection_id, native_stream);
 }
 
 pub struct Connection {
     ble_l2_cap_client: Arc<RwLock<Option<Box<dyn L2CapDelegate>>>>,
+    identity: Arc<IdentityStore>,
+    discovery_context: Arc<DiscoveryContext>,
 }
 
 fn update_progress(
@@ -57,29 +46,73 @@ fn update_progress(
     }
 }
 
+fn update_verification_code(
+    progress_delegate: &Option<Box<dyn SendProgressDelegate>>,
+    encrypted_connection: &EncryptedConnection,
+) {
+    let state = match &encrypted_connection.verification_code {
+        Some(code) => SendProgressState::VerificationCode { code: code.clone() },
+        None => SendProgressState::Unverified,
+    };
+
+    update_progress(progress_delegate, state);
+}
+
 impl Connection {
-    pub fn new(ble_l2_cap_client: Arc<RwLock<Option<Box<dyn L2CapDelegate>>>>) -> Self {
-        return Self { ble_l2_cap_client };
+    pub fn new(
+        ble_l2_cap_client: Arc<RwLock<Option<Box<dyn L2CapDelegate>>>>,
+        identity: Arc<IdentityStore>,
+        discovery_context: Arc<DiscoveryContext>,
+    ) -> Self {
+        return Self {
+            ble_l2_cap_client,
+            identity,
+            discovery_context,
+        };
     }
 
-    async fn initiate_sender<T>(&self, raw_stream: T) -> Result<EncryptedStream<T>, ConnectErrors>
+    async fn initiate_sender<T>(&self, raw_stream: T) -> Result<EncryptedConnection, ConnectErrors>
     where
-        T: Read + Write,
+        T: Read + Write + Send + Close + 'static,
     {
-        return Ok(match initiate_sender_communication(raw_stream).await {
-            Ok(stream) => stream,
-            Err(error) => {
-                return Err(ConnectErrors::FailedToEncryptStream {
+        return initiate_sender_communication(raw_stream, &self.identity)
+            .await
+            .map_err(|error| {
+                if let Some(HandshakeError::IncompatibleProtocolVersion) = error.downcast_ref() {
+                    return ConnectErrors::InvalidProtocolVersion;
+                }
+
+                return ConnectErrors::FailedToEncryptStream {
                     error: error.to_string(),
-                })
-            }
-        });
+                };
+            });
+    }
+
+    /// Refuses receivers which don't prove ownership of the identity key pinned for them.
+    fn check_identity(
+        &self,
+        device: &Device,
+        encrypted_connection: EncryptedConnection,
+    ) -> Result<EncryptedConnection, ConnectErrors> {
+        let peer_identity_key = encrypted_connection.peer_identity_key.as_ref();
+
+        let error = if is_downgrade(&encrypted_connection, &self.identity, &device.id) {
+            ConnectErrors::DowngradeDetected
+        } else if self.identity.get_trust(&device.id, peer_identity_key) == DeviceTrust::KeyChanged
+        {
+            ConnectErrors::KeyChanged
+        } else {
+            return Ok(encrypted_connection);
+        };
+
+        encrypted_connection.stream.close();
+        return Err(error);
     }
 
     pub async fn connect_tcp(
         &self,
         connection_details: &DeviceConnectionInfo,
-    ) -> Result<Box<dyn EncryptedReadWrite>, ConnectErrors> {
+    ) -> Result<EncryptedConnection, ConnectErrors> {
         let Some(tcp_connection_details) = &connection_details.tcp else {
             return Err(ConnectErrors::FailedToGetTcpDetails);
         };
@@ -97,7 +130,7 @@ impl Connection {
             return Err(ConnectErrors::FailedToGetSocketAddress);
         };
 
-        let mut socket_address = socket_address.as_slice()[0].clone();
+        let mut socket_address = socket_address.as_slice()[0];
         socket_address.set_port(tcp_connection_details.port as u16);
 
         let raw_stream = TcpClient::connect(socket_address).map_err(|err| {
@@ -106,36 +139,38 @@ impl Connection {
             }
         })?;
 
-        let encrypted_stream = self.initiate_sender(raw_stream).await?;
-        return Ok(Box::new(encrypted_stream));
+        return self.initiate_sender(raw_stream).await;
     }
 
     pub async fn connect(
         &self,
         device: Device,
         progress_delegate: &Option<Box<dyn SendProgressDelegate>>,
-    ) -> Result<Box<dyn EncryptedReadWrite>, ConnectErrors> {
-        L2CAP_CONNECTIONS.get_or_init(|| RwLock::new(HashMap::new()));
+    ) -> Result<EncryptedConnection, ConnectErrors> {
+        let connection_details = self
+            .discovery_context
+            .get_connection_details(&device.id)
+            .ok_or(ConnectErrors::FailedToGetConnectionDetails)?;
 
-        let connection_details =
-            get_connection_details(device).ok_or(ConnectErrors::FailedToGetConnectionDetails)?;
+        let encrypted_connection = self.connect_tcp(&connection_details).await;
 
-        let encrypted_stream = self.connect_tcp(&connection_details).await;
+        if let Ok(encrypted_connection) = encrypted_connection {
+            let encrypted_connection = self.check_identity(&device, encrypted_connection)?;
 
-        if let Ok(encrypted_stream) = encrypted_stream {
             update_progress(
                 progress_delegate,
                 SendProgressState::ConnectionMediumUpdate {
                     medium: ConnectionMedium::WiFi,
                 },
             );
+            update_verification_code(progress_delegate, &encrypted_connection);
 
-            return Ok(encrypted_stream);
+            return Ok(encrypted_connection);
         }
 
         info!("Could not connect via WiFi");
 
-        if let Err(error) = encrypted_stream {
+        if let Err(error) = encrypted_connection {
             error!("{}", error)
         }
 
@@ -149,12 +184,8 @@ impl Connection {
         let bluetooth_l2cap_id = Uuid::new_v4().to_string();
         let (sender, receiver) = oneshot::channel::<Box<dyn NativeStreamDelegate>>();
 
-        L2CAP_CONNECTIONS
-            .get()
-            .unwrap()
-            .write()
-            .await
-            .insert(bluetooth_l2cap_id.clone(), sender);
+        self.discovery_context
+            .expect_l2cap_connection(bluetooth_l2cap_id.clone(), sender);
 
         if let Some(ble_l2cap_client) = &*self.ble_l2_cap_client.read().await {
             info!("Requesting L2CAP connection...");
@@ -173,7 +204,8 @@ impl Connection {
 
         info!("Opened a L2CAP connection");
 
-        let encrypted_stream = self.initiate_sender(connection).await?;
+        let encrypted_connection = self.initiate_sender(connection).await?;
+        let encrypted_connection = self.check_identity(&device, encrypted_connection)?;
 
         update_progress(
             progress_delegate,
@@ -181,7 +213,8 @@ impl Connection {
                 medium: ConnectionMedium::BLE,
             },
         );
+        update_verification_code(progress_delegate, &encrypted_connection);
 
-        return Ok(Box::new(encrypted_stream));
+        return Ok(encrypted_connection);
     }
 }
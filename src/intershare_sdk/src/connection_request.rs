@@ -1,14 +1,28 @@
-use crate::tar::untar_stream;
+use crate::communication::{
+    EncryptedConnection, CAPABILITY_CANCEL, CAPABILITY_FILE_HASHES, CAPABILITY_TEXT_RESPONSE,
+    CAPABILITY_TRANSFER_RESULT, CAPABILITY_ZSTD,
+};
+use crate::errors::{peer_cancel_reason, FileIntegrityError};
+use crate::identity::{DeviceTrust, IdentityStore};
+use crate::share_store::read_peer_cancel;
+use crate::tar::{
+    has_unique_paths, partial_file_offset, partial_transfer_dir, remove_partial_transfer,
+    remove_stale_partial_transfers, untar_stream, untar_stream_resumable, ArchiveOptions,
+};
+use crate::transmission::http::HttpUpload;
 use crate::{encryption::EncryptedReadWrite, nearby_server::ConnectionIntentType};
-use log::error;
+use log::{error, info};
 use prost_stream::Stream;
 use protocol::communication::request::Intent;
 use protocol::communication::{
-    ClipboardTransferIntent, FileTransferIntent, Request, TransferRequestResponse,
+    transfer_result::Outcome, CancelReason, ClipboardTransferIntent, DeclineReason,
+    FileManifestEntry, FileTransferIntent, Request, ResumeOffset, TransferComplete, TransferFailed,
+    TransferRequestResponse, TransferResult,
 };
 use protocol::discovery::Device;
 use regex::Regex;
 use std::fmt::Debug;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard};
 use tokio::sync::RwLock;
@@ -17,10 +31,19 @@ use tokio::sync::RwLock;
 pub enum ReceiveProgressState {
     Unknown,
     Handshake,
-    Receiving { progress: f64 },
+    Receiving {
+        progress: f64,
+    },
     Extracting,
     Cancelled,
+    CancelledByPeer {
+        reason: CancelReason,
+    },
     Finished,
+    /// The listed files don't match what the sender sent and were discarded.
+    IntegrityCheckFailed {
+        files: Vec<String>,
+    },
 }
 
 #[uniffi::export(callback_interface)]
@@ -32,60 +55,376 @@ struct SharedVariables {
     receive_progress_delegate: Option<Box<dyn ReceiveProgressDelegate>>,
 }
 
+/// Where the files of a request come from.
+enum RequestSource {
+    /// Another device, over an encrypted connection.
+    Peer(Mutex<Box<dyn EncryptedReadWrite>>),
+    /// A browser uploading files. Taken once the request is answered.
+    Upload(Mutex<Option<HttpUpload>>),
+}
+
 #[derive(uniffi::Object)]
 pub struct ConnectionRequest {
     transfer_request: Request,
-    connection: Arc<Mutex<Box<dyn EncryptedReadWrite>>>,
+    source: RequestSource,
+    verification_code: Option<String>,
+    peer_identity_key: Option<[u8; 32]>,
+    capabilities: u64,
+    identity: Arc<IdentityStore>,
     file_storage: String,
     should_cancel: AtomicBool,
+    /// Text the sender sent once the request was accepted. Older senders put it into the request.
+    received_text: Mutex<Option<ClipboardTransferIntent>>,
     variables: Arc<RwLock<SharedVariables>>,
 }
 
 impl ConnectionRequest {
     pub fn new(
         transfer_request: Request,
-        connection: Box<dyn EncryptedReadWrite>,
+        connection: EncryptedConnection,
         file_storage: String,
+        identity: Arc<IdentityStore>,
     ) -> Self {
         Self {
             transfer_request,
-            connection: Arc::new(Mutex::new(connection)),
+            source: RequestSource::Peer(Mutex::new(connection.stream)),
+            verification_code: connection.verification_code,
+            peer_identity_key: connection.peer_identity_key,
+            capabilities: connection.capabilities,
+            identity,
             file_storage,
             should_cancel: AtomicBool::new(false),
+            received_text: Mutex::new(None),
             variables: Arc::new(RwLock::new(SharedVariables {
                 receive_progress_delegate: None,
             })),
         }
     }
 
+    /// A request for files a browser uploads over the HTTP server.
+    pub(crate) fn new_upload(
+        transfer_request: Request,
+        upload: HttpUpload,
+        file_storage: String,
+        identity: Arc<IdentityStore>,
+    ) -> Self {
+        Self {
+            transfer_request,
+            source: RequestSource::Upload(Mutex::new(Some(upload))),
+            verification_code: None,
+            peer_identity_key: None,
+            capabilities: 0,
+            identity,
+            file_storage,
+            should_cancel: AtomicBool::new(false),
+            received_text: Mutex::new(None),
+            variables: Arc::new(RwLock::new(SharedVariables {
+                receive_progress_delegate: None,
+            })),
+        }
+    }
+
+    /// The connection to the sending device. `None` for uploads.
+    fn lock_connection(&self) -> Option<MutexGuard<'_, Box<dyn EncryptedReadWrite>>> {
+        return match &self.source {
+            RequestSource::Peer(connection) => connection.lock().ok(),
+            RequestSource::Upload(_) => None,
+        };
+    }
+
+    /// Takes the upload, so it is answered only once. `None` for requests of other devices.
+    fn take_upload(&self) -> Option<HttpUpload> {
+        return match &self.source {
+            RequestSource::Peer(_) => None,
+            RequestSource::Upload(upload) => upload.lock().ok()?.take(),
+        };
+    }
+
+    fn receive_upload(
+        &self,
+        upload: HttpUpload,
+        selected_entries: Vec<u32>,
+    ) -> Option<Vec<String>> {
+        self.update_progress(ReceiveProgressState::Handshake);
+
+        let upload_result = upload.receive(
+            Path::new(&self.file_storage),
+            &selected_entries,
+            |progress| self.update_progress(ReceiveProgressState::Receiving { progress }),
+            &self.should_cancel,
+        );
+
+        return match upload_result {
+            Ok(files) => {
+                self.update_progress(ReceiveProgressState::Finished);
+                Some(files)
+            }
+            Err(error) => {
+                error!("Error while receiving upload: {}", error);
+                self.update_progress(ReceiveProgressState::Cancelled);
+                None
+            }
+        };
+    }
+
     fn handle_file(
         &self,
         mut stream: MutexGuard<Box<dyn EncryptedReadWrite>>,
-        file_transfer: FileTransferIntent,
+        total_bytes: u64,
+        staging_dir: Option<&Path>,
+        accepted_entries: &[FileManifestEntry],
     ) -> Option<Vec<String>> {
-        match untar_stream(
-            &mut *stream,
-            self.file_storage.as_ref(),
-            file_transfer.file_size,
-            |progress| {
-                self.update_progress(ReceiveProgressState::Receiving { progress: progress });
-            },
-            &self.should_cancel,
-        ) {
+        let progress_cb = |progress| {
+            self.update_progress(ReceiveProgressState::Receiving { progress });
+        };
+
+        let untar_result = match staging_dir {
+            Some(staging_dir) => untar_stream_resumable(
+                &mut stream,
+                self.file_storage.as_ref(),
+                staging_dir,
+                accepted_entries,
+                total_bytes,
+                progress_cb,
+                &self.should_cancel,
+                &self.archive_options(),
+            ),
+            None => untar_stream(
+                &mut stream,
+                self.file_storage.as_ref(),
+                total_bytes,
+                progress_cb,
+                &self.should_cancel,
+            ),
+        };
+
+        match untar_result {
             Ok(files) => {
+                self.send_transfer_result(&mut stream, Outcome::Complete(TransferComplete {}));
                 self.update_progress(ReceiveProgressState::Finished);
                 stream.close();
                 Some(files)
             }
             Err(error) => {
                 error!("Error while unpacking: {}", error);
-                self.update_progress(ReceiveProgressState::Cancelled);
+
+                let integrity_error = error
+                    .get_ref()
+                    .and_then(|inner_error| inner_error.downcast_ref::<FileIntegrityError>());
+                let peer_cancel = peer_cancel_reason(&error);
+
+                if integrity_error.is_some() {
+                    // The whole stream was read, the sender is waiting for the result.
+                    self.send_transfer_result(
+                        &mut stream,
+                        Outcome::Failed(TransferFailed {
+                            error: error.to_string(),
+                        }),
+                    );
+                } else if peer_cancel.is_none() && self.capabilities & CAPABILITY_CANCEL != 0 {
+                    let reason = if self.should_cancel.load(Ordering::Relaxed) {
+                        CancelReason::UserCancelled
+                    } else {
+                        CancelReason::Error
+                    };
+
+                    let _ = stream.cancel(reason);
+                }
+
                 stream.close();
+
+                let abandoned = self.should_cancel.load(Ordering::Relaxed)
+                    || matches!(
+                        peer_cancel,
+                        Some(CancelReason::UserCancelled | CancelReason::VerificationCodeRejected)
+                    );
+
+                if let (true, Some(staging_dir)) = (abandoned, staging_dir) {
+                    remove_partial_transfer(staging_dir);
+                }
+
+                match (peer_cancel, integrity_error) {
+                    (Some(reason), _) => {
+                        self.update_progress(ReceiveProgressState::CancelledByPeer { reason })
+                    }
+                    (None, Some(FileIntegrityError::HashMismatch { files })) => self
+                        .update_progress(ReceiveProgressState::IntegrityCheckFailed {
+                            files: files.clone(),
+                        }),
+                    (None, None) => self.update_progress(ReceiveProgressState::Cancelled),
+                }
+
                 None
             }
         }
     }
 
+    /// Older senders put the text into the request, newer ones send it once it was accepted.
+    fn receive_text(&self) -> Option<Vec<String>> {
+        let Some(mut connection_guard) = self.lock_connection() else {
+            return Some(vec![]);
+        };
+
+        if self.capabilities & CAPABILITY_TEXT_RESPONSE != 0 {
+            let _ = Stream::new(&mut *connection_guard).send(&TransferRequestResponse {
+                accepted: true,
+                ..Default::default()
+            });
+
+            match Stream::new(&mut *connection_guard).recv::<ClipboardTransferIntent>() {
+                Ok(clipboard_intent) => {
+                    *self.received_text.lock().unwrap() = Some(clipboard_intent);
+                }
+                Err(error) => {
+                    error!("Error while receiving text: {}", error);
+
+                    match read_peer_cancel(&mut connection_guard) {
+                        Some(reason) => {
+                            self.update_progress(ReceiveProgressState::CancelledByPeer { reason })
+                        }
+                        None => self.update_progress(ReceiveProgressState::Cancelled),
+                    }
+
+                    connection_guard.close();
+                    return None;
+                }
+            }
+        }
+
+        self.send_transfer_result(
+            &mut connection_guard,
+            Outcome::Complete(TransferComplete {}),
+        );
+        connection_guard.close();
+
+        return Some(vec![]);
+    }
+
+    fn send_transfer_result(&self, stream: &mut Box<dyn EncryptedReadWrite>, outcome: Outcome) {
+        if self.capabilities & CAPABILITY_TRANSFER_RESULT == 0 {
+            return;
+        }
+
+        let _ = Stream::new(&mut *stream).send(&TransferResult {
+            outcome: Some(outcome),
+        });
+        let _ = stream.finish();
+    }
+
+    fn archive_options(&self) -> ArchiveOptions {
+        return ArchiveOptions {
+            file_hashes: self.capabilities & CAPABILITY_FILE_HASHES != 0,
+            compression: self.capabilities & CAPABILITY_ZSTD != 0,
+        };
+    }
+
+    /// Accepts the request. `selected_entries` are indices into the file manifest,
+    /// an empty selection accepts all files.
+    fn accept_entries(&self, selected_entries: Vec<u32>) -> Option<Vec<String>> {
+        if self.get_sender_trust() == DeviceTrust::New {
+            self.trust_sender();
+        }
+
+        if let Some(upload) = self.take_upload() {
+            return self.receive_upload(upload, selected_entries);
+        }
+
+        if self.get_intent_type() == ConnectionIntentType::Clipboard {
+            return self.receive_text();
+        }
+
+        self.update_progress(ReceiveProgressState::Handshake);
+
+        remove_stale_partial_transfers(Path::new(&self.file_storage));
+
+        let manifest = self.get_file_manifest();
+        let accepted_entries: Vec<FileManifestEntry> = manifest
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| {
+                selected_entries.is_empty() || selected_entries.contains(&(*index as u32))
+            })
+            .map(|(_, entry)| entry.clone())
+            .collect();
+        let staging_dir = self.partial_transfer_dir();
+        let resume_offsets = self.resume_offsets(&selected_entries);
+
+        let total_bytes = if manifest.is_empty() {
+            self.get_file_transfer_intent()
+                .map(|file_transfer_intent| file_transfer_intent.file_size)
+                .unwrap_or_default()
+        } else {
+            let resumed_bytes: u64 = resume_offsets
+                .iter()
+                .map(|resume_offset| resume_offset.offset)
+                .sum();
+
+            let selected_bytes: u64 = accepted_entries.iter().map(|entry| entry.size).sum();
+
+            selected_bytes - resumed_bytes
+        };
+
+        if let Some(mut connection_guard) = self.lock_connection() {
+            let mut stream = Stream::new(&mut *connection_guard);
+
+            let _ = stream.send(&TransferRequestResponse {
+                accepted: true,
+                selected_entries,
+                resume_offsets,
+                ..Default::default()
+            });
+
+            match self.get_intent() {
+                Intent::FileTransfer(_) => self.handle_file(
+                    connection_guard,
+                    total_bytes,
+                    staging_dir.as_deref(),
+                    &accepted_entries,
+                ),
+                Intent::Clipboard(_) => None,
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Staging directory of this transfer. `None` if the transfer can't be resumed.
+    fn partial_transfer_dir(&self) -> Option<PathBuf> {
+        let file_transfer_intent = self.get_file_transfer_intent()?;
+
+        if file_transfer_intent.manifest.is_empty()
+            || !has_unique_paths(&file_transfer_intent.manifest)
+        {
+            return None;
+        }
+
+        return partial_transfer_dir(
+            Path::new(&self.file_storage),
+            file_transfer_intent.transfer_id.as_deref()?,
+        );
+    }
+
+    /// Offsets of the files partially received by a previous attempt of this transfer.
+    fn resume_offsets(&self, selected_entries: &[u32]) -> Vec<ResumeOffset> {
+        let Some(staging_dir) = self.partial_transfer_dir() else {
+            return vec![];
+        };
+
+        return self
+            .get_file_manifest()
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| {
+                selected_entries.is_empty() || selected_entries.contains(&(*index as u32))
+            })
+            .map(|(index, entry)| ResumeOffset {
+                entry: index as u32,
+                offset: partial_file_offset(&staging_dir, entry),
+            })
+            .filter(|resume_offset| resume_offset.offset > 0)
+            .collect();
+    }
+
     pub fn get_intent(&self) -> Intent {
         self.transfer_request
             .intent
@@ -108,6 +447,25 @@ impl ConnectionRequest {
             .expect("Device information missing")
     }
 
+    /// Code to compare with the one shown on the sender's device.
+    /// `None` if the sender uses an older version which does not support verification.
+    pub fn get_verification_code(&self) -> Option<String> {
+        self.verification_code.clone()
+    }
+
+    /// Whether the sender proved ownership of the identity key pinned for its device id.
+    pub fn get_sender_trust(&self) -> DeviceTrust {
+        self.identity
+            .get_trust(&self.get_sender().id, self.peer_identity_key.as_ref())
+    }
+
+    /// Pins the sender's current identity key, e.g. after the user confirmed a changed key.
+    pub fn trust_sender(&self) {
+        if let Some(peer_identity_key) = &self.peer_identity_key {
+            self.identity.pin(&self.get_sender().id, peer_identity_key);
+        }
+    }
+
     pub fn get_intent_type(&self) -> ConnectionIntentType {
         match self
             .transfer_request
@@ -141,7 +499,21 @@ impl ConnectionRequest {
         }
     }
 
+    /// Every file of the transfer, with directories expanded.
+    /// Empty if the sender uses an older version which does not send a manifest.
+    pub fn get_file_manifest(&self) -> Vec<FileManifestEntry> {
+        self.get_file_transfer_intent()
+            .map(|file_transfer_intent| file_transfer_intent.manifest)
+            .unwrap_or_default()
+    }
+
+    /// The shared text. Newer senders send it only once the request was accepted,
+    /// until then its content is empty.
     pub fn get_clipboard_intent(&self) -> Option<ClipboardTransferIntent> {
+        if let Some(received_text) = self.received_text.lock().unwrap().clone() {
+            return Some(received_text);
+        }
+
         match self
             .transfer_request
             .intent
@@ -154,22 +526,53 @@ impl ConnectionRequest {
     }
 
     pub fn decline(&self) {
-        if self.get_intent_type() == ConnectionIntentType::Clipboard {
-            if let Ok(connection_guard) = self.connection.lock() {
+        self.decline_with_reason(DeclineReason::UserDeclined);
+    }
+
+    /// Declines the request and tells the sender why.
+    pub fn decline_with_reason(&self, reason: DeclineReason) {
+        info!("Declining request: {:?}", reason);
+
+        if let Some(upload) = self.take_upload() {
+            upload.decline(reason);
+            return;
+        }
+
+        // Older senders don't wait for an answer to text shares.
+        if self.get_intent_type() == ConnectionIntentType::Clipboard
+            && self.capabilities & CAPABILITY_TEXT_RESPONSE == 0
+        {
+            if let Some(connection_guard) = self.lock_connection() {
                 connection_guard.close();
             }
 
             return;
         }
 
-        if let Ok(mut connection_guard) = self.connection.lock() {
+        if let Some(staging_dir) = self.partial_transfer_dir() {
+            remove_partial_transfer(&staging_dir);
+        }
+
+        if let Some(mut connection_guard) = self.lock_connection() {
             let mut stream = Stream::new(&mut *connection_guard);
 
-            let _ = stream.send(&TransferRequestResponse { accepted: false });
+            let _ = stream.send(&TransferRequestResponse {
+                accepted: false,
+                selected_entries: vec![],
+                resume_offsets: vec![],
+                decline_reason: reason as i32,
+            });
+            let _ = connection_guard.finish();
             connection_guard.close();
         }
     }
 
+    /// Declines the request because the verification codes shown on both devices don't match.
+    pub fn reject_verification_code(&self) {
+        info!("Verification code was rejected by the user");
+        self.decline_with_reason(DeclineReason::VerificationRejected);
+    }
+
     fn update_progress(&self, new_state: ReceiveProgressState) {
         if let Some(receive_progress_delegate) =
             &self.variables.blocking_read().receive_progress_delegate
@@ -178,34 +581,49 @@ impl ConnectionRequest {
         }
     }
 
+    /// Stops receiving files and lets the sender know the transfer was cancelled.
     pub fn cancel(&self) {
         self.should_cancel.store(true, Ordering::Relaxed);
     }
 
-    pub fn accept(&self) -> Option<Vec<String>> {
-        if self.get_intent_type() == ConnectionIntentType::Clipboard {
-            if let Ok(connection_guard) = self.connection.lock() {
-                connection_guard.close();
-            }
+    /// Bytes already received by a previous, interrupted attempt of this transfer.
+    /// Accepting the request continues from there.
+    ///
+    /// The sender retries using `ShareStore::resume()`, the receiver of a download by
+    /// requesting the same link again with `InternalNearbyServer::request_download()`.
+    pub fn get_resumable_bytes(&self) -> u64 {
+        self.resume_offsets(&[])
+            .iter()
+            .map(|resume_offset| resume_offset.offset)
+            .sum()
+    }
 
-            return Some(vec![]);
-        }
+    pub fn accept(&self) -> Option<Vec<String>> {
+        self.accept_entries(vec![])
+    }
 
-        self.update_progress(ReceiveProgressState::Handshake);
+    /// Accepts only the files at the given indices of `get_file_manifest()`.
+    /// Accepts everything if the sender uses an older version which does not send a manifest.
+    pub fn accept_selected(&self, indices: Vec<u32>) -> Option<Vec<String>> {
+        let manifest_length = self.get_file_manifest().len();
 
-        if let Ok(mut connection_guard) = self.connection.lock() {
-            let mut stream = Stream::new(&mut *connection_guard);
+        if manifest_length == 0 {
+            return self.accept();
+        }
 
-            let _ = stream.send(&TransferRequestResponse { accepted: true });
+        let mut selected_entries: Vec<u32> = indices
+            .into_iter()
+            .filter(|index| (*index as usize) < manifest_length)
+            .collect();
+        selected_entries.sort_unstable();
+        selected_entries.dedup();
 
-            match self.get_intent() {
-                Intent::FileTransfer(file_transfer) => {
-                    self.handle_file(connection_guard, file_transfer)
-                }
-                Intent::Clipboard(_) => None,
-            }
-        } else {
-            None
+        if selected_entries.is_empty() {
+            info!("No files selected, declining request");
+            self.decline();
+            return Some(vec![]);
         }
+
+        self.accept_entries(selected_entries)
     }
 }
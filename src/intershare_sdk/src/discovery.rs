@@ -1,6 +1,7 @@
 use crate::encryption::generate_secure_base64_token;
 use crate::errors::DiscoverySetupError;
 use crate::init_logger;
+use crate::stream::NativeStreamDelegate;
 use log::{info, warn};
 use protocol::discovery;
 use protocol::discovery::device_discovery_message::Content;
@@ -10,7 +11,13 @@ use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
 #[cfg(target_os = "windows")]
 use std::sync::atomic::AtomicBool;
-use std::sync::{Arc, OnceLock, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot::Sender;
+
+/// How often devices are checked for expiry while scanning.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
 
 #[uniffi::export(callback_interface)]
 pub trait BleDiscoveryImplementationDelegate: Send + Sync + Debug {
@@ -24,20 +31,143 @@ pub trait DeviceListUpdateDelegate: Send + Sync + Debug {
     fn device_removed(&self, device_id: String);
 }
 
-static DISCOVERED_DEVICES: OnceLock<RwLock<HashMap<String, DeviceConnectionInfo>>> =
-    OnceLock::new();
+static DEFAULT_DISCOVERY_CONTEXT: OnceLock<Arc<DiscoveryContext>> = OnceLock::new();
+
+/// The devices discovered by the `InternalDiscovery` instances sharing this context, and the
+/// delegates told about them. Shares and connections look devices up in their context.
+#[derive(uniffi::Object, Default)]
+pub struct DiscoveryContext {
+    devices: RwLock<HashMap<String, DeviceConnectionInfo>>,
+    delegates: RwLock<HashMap<String, Arc<Box<dyn DeviceListUpdateDelegate>>>>,
+    /// L2CAP connections requested by connections of this context, by connection id.
+    l2cap_connections: RwLock<HashMap<String, Sender<Box<dyn NativeStreamDelegate>>>>,
+}
 
-static DELEGATES: OnceLock<RwLock<HashMap<String, Arc<Box<dyn DeviceListUpdateDelegate>>>>> =
-    OnceLock::new();
+#[uniffi::export]
+impl DiscoveryContext {
+    /// A context separate from the default one, e.g. for a second profile in the same process.
+    #[uniffi::constructor]
+    pub fn new() -> Arc<Self> {
+        return Arc::new(Self::default());
+    }
 
+    /// Passes an L2CAP connection opened for a connection of this context to it.
+    pub fn handle_incoming_l2cap_connection(
+        &self,
+        connection_id: String,
+        native_stream: Box<dyn NativeStreamDelegate>,
+    ) {
+        let sender = self
+            .l2cap_connections
+            .write()
+            .unwrap()
+            .remove(&connection_id);
+
+        if let Some(sender) = sender {
+            info!("Passing incomming L2CAP connection...");
+            let _ = sender.send(native_stream);
+        }
+    }
+}
+
+impl DiscoveryContext {
+    /// The context used unless another one is passed in.
+    pub fn default_context() -> Arc<Self> {
+        return DEFAULT_DISCOVERY_CONTEXT
+            .get_or_init(|| Arc::new(Self::default()))
+            .clone();
+    }
+
+    pub fn get_connection_details(&self, device_id: &str) -> Option<DeviceConnectionInfo> {
+        return self.devices.read().unwrap().get(device_id).cloned();
+    }
+
+    /// Waits for the L2CAP connection with `connection_id` using `sender`.
+    pub(crate) fn expect_l2cap_connection(
+        &self,
+        connection_id: String,
+        sender: Sender<Box<dyn NativeStreamDelegate>>,
+    ) {
+        self.l2cap_connections
+            .write()
+            .unwrap()
+            .insert(connection_id, sender);
+    }
+
+    fn add_delegate(&self, delegate_id: String, delegate: Box<dyn DeviceListUpdateDelegate>) {
+        info!("Adding delegate: {:?}", delegate_id);
+        self.delegates
+            .write()
+            .unwrap()
+            .insert(delegate_id, Arc::new(delegate));
+    }
+
+    fn remove_delegate(&self, delegate_id: &str) {
+        info!("Removing delegate: {:?}", delegate_id);
+        self.delegates
+            .write()
+            .expect("Failed to read delegates")
+            .remove(delegate_id);
+    }
+
+    fn notify_device_added(&self, device: &Device) {
+        let delegates = self.delegates.read().expect("Failed to read delegates");
+
+        for delegate in delegates.values() {
+            delegate.device_added(device.clone());
+        }
+    }
+
+    fn notify_device_removed(&self, device_id: &str) {
+        let delegates = self.delegates.read().expect("Failed to read delegates");
+
+        for delegate in delegates.values() {
+            delegate.device_removed(device_id.to_string());
+        }
+    }
+}
+
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct DiscoveredDevice {
+    pub device: Device,
+    /// Milliseconds since the Unix epoch.
+    pub last_seen: u64,
+    /// Signal strength of the last advertisement in dBm, if the scanner reported it.
+    pub rssi: Option<i32>,
+}
+
+struct DiscoveredDeviceEntry {
+    connection_info: DeviceConnectionInfo,
+    ble_uuid: Option<String>,
+    last_seen: SystemTime,
+    rssi: Option<i32>,
+}
+
+impl DiscoveredDeviceEntry {
+    fn is_expired(&self, ttl: Duration) -> bool {
+        return self.last_seen.elapsed().is_ok_and(|elapsed| elapsed > ttl);
+    }
+
+    fn to_discovered_device(&self) -> DiscoveredDevice {
+        return DiscoveredDevice {
+            device: self
+                .connection_info
+                .device
+                .clone()
+                .expect("No device in DeviceConnectionInfo"),
+            last_seen: self
+                .last_seen
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            rssi: self.rssi,
+        };
+    }
+}
+
+/// Looks `device` up in the default `DiscoveryContext`.
 pub fn get_connection_details(device: Device) -> Option<DeviceConnectionInfo> {
-    DISCOVERED_DEVICES
-        .get()
-        .unwrap()
-        .read()
-        .unwrap()
-        .get(&device.id)
-        .cloned()
+    return DiscoveryContext::default_context().get_connection_details(&device.id);
 }
 
 #[derive(uniffi::Object)]
@@ -45,7 +175,12 @@ pub struct InternalDiscovery {
     pub ble_discovery_implementation:
         tokio::sync::RwLock<Option<Box<dyn BleDiscoveryImplementationDelegate>>>,
     current_delegate_id: String,
-    discovered_devices: RwLock<HashMap<String, DeviceConnectionInfo>>,
+    context: Arc<DiscoveryContext>,
+    discovered_devices: RwLock<HashMap<String, DiscoveredDeviceEntry>>,
+    /// Unset until `set_device_ttl()` is called, devices don't expire before.
+    device_ttl: RwLock<Option<Duration>>,
+    /// Changes whenever scanning starts or stops, which ends the previous expiry thread.
+    expiry_generation: AtomicU64,
 
     #[cfg(target_os = "windows")]
     pub(crate) scanning: Arc<AtomicBool>,
@@ -59,31 +194,34 @@ impl Debug for InternalDiscovery {
 
 #[uniffi::export]
 impl InternalDiscovery {
+    /// Discovers devices into the default `DiscoveryContext`.
     #[uniffi::constructor]
     pub fn new(
         delegate: Option<Box<dyn DeviceListUpdateDelegate>>,
     ) -> Result<Arc<Self>, DiscoverySetupError> {
-        init_logger();
+        return Self::with_context(delegate, DiscoveryContext::default_context());
+    }
 
-        DISCOVERED_DEVICES.get_or_init(|| RwLock::new(HashMap::new()));
-        DELEGATES.get_or_init(|| RwLock::new(HashMap::new()));
+    #[uniffi::constructor]
+    pub fn with_context(
+        delegate: Option<Box<dyn DeviceListUpdateDelegate>>,
+        context: Arc<DiscoveryContext>,
+    ) -> Result<Arc<Self>, DiscoverySetupError> {
+        init_logger();
 
         let delegate_id = generate_secure_base64_token(4);
 
         if let Some(delegate) = delegate {
-            info!("Adding delegate: {:?}", delegate_id);
-            DELEGATES
-                .get()
-                .unwrap()
-                .write()
-                .unwrap()
-                .insert(delegate_id.clone(), Arc::new(delegate));
+            context.add_delegate(delegate_id.clone(), delegate);
         };
 
         return Ok(Arc::new(Self {
             ble_discovery_implementation: tokio::sync::RwLock::new(None),
             current_delegate_id: delegate_id,
+            context,
             discovered_devices: RwLock::new(HashMap::new()),
+            device_ttl: RwLock::new(None),
+            expiry_generation: AtomicU64::new(0),
 
             #[cfg(target_os = "windows")]
             scanning: Arc::new(AtomicBool::new(false)),
@@ -94,9 +232,10 @@ impl InternalDiscovery {
         let discovered_devices = self.discovered_devices.read().unwrap();
 
         discovered_devices
-            .iter()
-            .map(|(_, device_info)| {
+            .values()
+            .map(|device_info| {
                 device_info
+                    .connection_info
                     .device
                     .clone()
                     .expect("No device in DeviceConnectionInfo")
@@ -104,6 +243,24 @@ impl InternalDiscovery {
             .collect()
     }
 
+    /// The discovered devices, with when they were last seen and their signal strength.
+    pub fn get_discovered_devices(self: Arc<Self>) -> Vec<DiscoveredDevice> {
+        return self
+            .discovered_devices
+            .read()
+            .unwrap()
+            .values()
+            .map(|entry| entry.to_discovered_device())
+            .collect();
+    }
+
+    /// Devices not seen for longer than this are removed, and `device_removed` is called.
+    /// Off by default. The TTL should be well above the interval in which scanners read every
+    /// device again, see `OFFLINE_ANNOUNCEMENT_DURATION` in `nearby_server`, e.g. 90 seconds.
+    pub fn set_device_ttl(self: Arc<Self>, ttl_seconds: u64) {
+        *self.device_ttl.write().unwrap() = Some(Duration::from_secs(ttl_seconds));
+    }
+
     pub fn add_ble_implementation(
         self: Arc<Self>,
         implementation: Box<dyn BleDiscoveryImplementationDelegate>,
@@ -112,9 +269,11 @@ impl InternalDiscovery {
     }
 
     pub fn start(self: Arc<Self>) {
-        DISCOVERED_DEVICES.get().unwrap().write().unwrap().clear();
+        self.context.devices.write().unwrap().clear();
         self.discovered_devices.write().unwrap().clear();
 
+        self.clone().start_expiring_devices();
+
         #[cfg(target_os = "windows")]
         self.windows_start_scanning();
 
@@ -127,16 +286,12 @@ impl InternalDiscovery {
     }
 
     pub fn stop(self: Arc<Self>) {
+        self.expiry_generation.fetch_add(1, Ordering::Relaxed);
+
         #[cfg(target_os = "windows")]
         self.windows_stop_scanning();
 
-        info!("Removing delegate: {:?}", self.current_delegate_id);
-        DELEGATES
-            .get()
-            .unwrap()
-            .write()
-            .expect("Failed to read delegates")
-            .remove(&self.current_delegate_id);
+        self.context.remove_delegate(&self.current_delegate_id);
 
         #[cfg(not(target_os = "windows"))]
         if let Some(ble_discovery_implementation) =
@@ -147,6 +302,16 @@ impl InternalDiscovery {
     }
 
     pub fn parse_discovery_message(self: Arc<Self>, data: Vec<u8>, ble_uuid: Option<String>) {
+        self.parse_discovery_message_with_rssi(data, ble_uuid, None);
+    }
+
+    /// Like `parse_discovery_message()`, with the signal strength of the advertisement in dBm.
+    pub fn parse_discovery_message_with_rssi(
+        self: Arc<Self>,
+        data: Vec<u8>,
+        ble_uuid: Option<String>,
+        rssi: Option<i32>,
+    ) {
         let Ok(discovery_message) =
             DeviceDiscoveryMessage::decode_length_delimited(data.as_slice())
         else {
@@ -168,9 +333,9 @@ impl InternalDiscovery {
 
                 let mut device_connection_info = device_connection_info.clone();
 
-                if let Some(ble_uuid) = ble_uuid {
+                if let Some(ble_uuid) = &ble_uuid {
                     if let Some(mut ble_info) = device_connection_info.ble {
-                        ble_info.uuid = ble_uuid;
+                        ble_info.uuid = ble_uuid.clone();
                         device_connection_info.ble = Some(ble_info);
                     }
                 }
@@ -178,41 +343,71 @@ impl InternalDiscovery {
                 let mut discovered_devices = self.discovered_devices.write().unwrap();
 
                 if discovered_devices.contains_key(&device.id) {
-                    if discovered_devices[&device.id] != device_connection_info {
-                        info!("Device {:} already exist, updating...", &device.name);
+                    if discovered_devices[&device.id].connection_info != device_connection_info {
+                        info!("Device {:} already exist, updating...", device.name);
                         self.clone().add_discovered_device(device.clone());
                     }
                 } else {
-                    info!("Device {:} discovered", &device.name);
+                    info!("Device {:} discovered", device.name);
                     Arc::clone(&self).add_discovered_device(device.clone());
                 }
 
-                discovered_devices.insert(device.id.clone(), device_connection_info.clone());
-
-                DISCOVERED_DEVICES
-                    .get()
-                    .unwrap()
+                let previous_rssi = discovered_devices
+                    .get(&device.id)
+                    .and_then(|entry| entry.rssi);
+
+                discovered_devices.insert(
+                    device.id.clone(),
+                    DiscoveredDeviceEntry {
+                        connection_info: device_connection_info.clone(),
+                        ble_uuid,
+                        last_seen: SystemTime::now(),
+                        rssi: rssi.or(previous_rssi),
+                    },
+                );
+
+                self.context
+                    .devices
                     .write()
                     .unwrap()
                     .insert(device.id.clone(), device_connection_info.clone());
             }
             Some(Content::OfflineDeviceId(device_id)) => {
-                self.discovered_devices.write().unwrap().remove(&device_id);
-                self.remove_discovered_device(device_id);
+                // Devices keep announcing that they went offline for a while, only report it once.
+                if self
+                    .discovered_devices
+                    .write()
+                    .unwrap()
+                    .remove(&device_id)
+                    .is_none()
+                {
+                    return;
+                }
+
+                info!("Device {:} went offline", device_id);
+                self.forget_device(device_id);
             }
         };
     }
 
-    fn add_discovered_device(self: Arc<Self>, device: Device) {
-        let delegates = DELEGATES
-            .get()
-            .unwrap()
-            .read()
-            .expect("Failed to read delegates");
+    /// Marks the device advertising with `ble_uuid` as seen, without reading its discovery
+    /// message again.
+    pub fn device_seen(self: Arc<Self>, ble_uuid: String, rssi: Option<i32>) {
+        let mut discovered_devices = self.discovered_devices.write().unwrap();
 
-        for values in delegates.values() {
-            values.device_added(device.clone());
-        }
+        let Some(entry) = discovered_devices
+            .values_mut()
+            .find(|entry| entry.ble_uuid.as_deref() == Some(ble_uuid.as_str()))
+        else {
+            return;
+        };
+
+        entry.last_seen = SystemTime::now();
+        entry.rssi = rssi.or(entry.rssi);
+    }
+
+    fn add_discovered_device(self: Arc<Self>, device: Device) {
+        self.context.notify_device_added(&device);
 
         // if let Some(discovery_delegate) = &self.discovery_delegate {
         //     discovery_delegate.read().expect("Failed to lock discovery_delegate").device_added(device);
@@ -223,14 +418,60 @@ impl InternalDiscovery {
         // if let Some(discovery_delegate) = &self.discovery_delegate {
         //     discovery_delegate.read().expect("Failed to lock discovery_delegate").device_removed(device_id);
         // }
-        let delegates = DELEGATES
-            .get()
-            .unwrap()
-            .read()
-            .expect("Failed to read delegates");
+        self.context.notify_device_removed(&device_id);
+    }
+}
+
+impl InternalDiscovery {
+    /// Removes devices which weren't seen for longer than the TTL, until scanning stops.
+    fn start_expiring_devices(self: Arc<Self>) {
+        let generation = self.expiry_generation.fetch_add(1, Ordering::Relaxed) + 1;
+        let discovery: Weak<Self> = Arc::downgrade(&self);
+
+        std::thread::spawn(move || loop {
+            std::thread::sleep(EXPIRY_INTERVAL);
+
+            let Some(discovery) = discovery.upgrade() else {
+                return;
+            };
+
+            if discovery.expiry_generation.load(Ordering::Relaxed) != generation {
+                return;
+            }
 
-        for values in delegates.values() {
-            values.device_removed(device_id.clone());
+            discovery.expire_devices();
+        });
+    }
+
+    /// Removes the devices which weren't seen for longer than the TTL, if one was set.
+    pub fn expire_devices(self: Arc<Self>) {
+        let Some(ttl) = *self.device_ttl.read().unwrap() else {
+            return;
+        };
+
+        let mut discovered_devices = self.discovered_devices.write().unwrap();
+
+        let expired_device_ids: Vec<String> = discovered_devices
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(ttl))
+            .map(|(device_id, _)| device_id.clone())
+            .collect();
+
+        for device_id in &expired_device_ids {
+            discovered_devices.remove(device_id);
+        }
+
+        drop(discovered_devices);
+
+        for device_id in expired_device_ids {
+            info!("Device {:} was not seen for {:?}, removing", device_id, ttl);
+            self.clone().forget_device(device_id);
         }
     }
+
+    fn forget_device(self: Arc<Self>, device_id: String) {
+        self.context.devices.write().unwrap().remove(&device_id);
+
+        self.remove_discovered_device(device_id);
+    }
 }
@@ -1,14 +1,41 @@
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use chacha20::cipher::{KeyIvInit, StreamCipher};
 use chacha20::XChaCha20;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::Payload;
+use chacha20poly1305::XChaCha20Poly1305;
+use hkdf::Hkdf;
+use protocol::communication::{Cancel, CancelReason};
+use protocol::prost::Message;
 use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
 use std::io;
 use std::io::ErrorKind::Other;
 use std::io::{Error, Read, Write};
-use std::iter::repeat;
+use std::time::Duration;
 
+use crate::errors::{CancelError, StreamIntegrityError};
 use crate::stream::Close;
 
+/// Length of the nonce prefix used by the STREAM construction (24 byte XChaCha nonce minus
+/// the 5 bytes reserved for the frame counter and the last-frame flag).
+pub const STREAM_NONCE_LENGTH: usize = 19;
+
+/// Maximum amount of plaintext sealed into a single frame.
+pub const MAX_FRAME_PLAINTEXT_LENGTH: usize = 64 * 1024;
+
+const TRANSCRIPT_LABEL: &[u8] = b"InterShare handshake v1";
+const VERIFICATION_COMMITMENT_LABEL: &[u8] = b"InterShare verification commitment";
+const VERIFICATION_CODE_LABEL: &[u8] = b"InterShare verification code";
+const INITIATOR_TO_RESPONDER_LABEL: &[u8] = b"InterShare initiator to responder";
+const RESPONDER_TO_INITIATOR_LABEL: &[u8] = b"InterShare responder to initiator";
+
+const TAG_LENGTH: usize = 16;
+const FRAME_HEADER_LENGTH: usize = 4;
+const FRAME_FLAG_LAST: u8 = 0b0000_0001;
+const FRAME_FLAG_CANCEL: u8 = 0b0000_0010;
+
 pub fn generate_key() -> [u8; 32] {
     let key = XChaCha20::generate_key(&mut OsRng);
 
@@ -21,12 +48,126 @@ pub fn generate_secure_base64_token(byte_length: usize) -> String {
     return URL_SAFE_NO_PAD.encode(&bytes);
 }
 
+pub fn generate_verification_nonce() -> [u8; 32] {
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+
+    return nonce;
+}
+
 pub fn generate_iv() -> [u8; 24] {
     let nonce = XChaCha20::generate_iv(&mut OsRng);
 
     return nonce.into();
 }
 
+/// Which side of the handshake the local peer is on.
+/// The initiator is the peer which opened the connection and sent the `EncryptionRequest`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandshakeRole {
+    Initiator,
+    Responder,
+}
+
+/// Keys and nonces for both directions of an authenticated session.
+pub struct SessionKeys {
+    pub send_key: [u8; 32],
+    pub send_nonce: [u8; STREAM_NONCE_LENGTH],
+    pub receive_key: [u8; 32],
+    pub receive_nonce: [u8; STREAM_NONCE_LENGTH],
+}
+
+/// Hashes everything both peers agreed on during the handshake.
+/// Each value is length prefixed, so different inputs can never produce the same transcript.
+pub fn handshake_transcript(
+    initiator_public_key: &[u8],
+    responder_public_key: &[u8],
+    iv: &[u8],
+    mode: i32,
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(TRANSCRIPT_LABEL);
+
+    for value in [
+        initiator_public_key,
+        responder_public_key,
+        iv,
+        &mode.to_be_bytes(),
+    ] {
+        hasher.update((value.len() as u32).to_be_bytes());
+        hasher.update(value);
+    }
+
+    return hasher.finalize().into();
+}
+
+/// Commitment the responder sends before it learns the nonce of the initiator.
+/// This prevents a man-in-the-middle from choosing its nonce so that both verification codes match.
+pub fn verification_commitment(transcript: &[u8; 32], responder_nonce: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(VERIFICATION_COMMITMENT_LABEL);
+    hasher.update(transcript);
+    hasher.update(responder_nonce);
+
+    return hasher.finalize().into();
+}
+
+/// Six digit code both users compare to make sure they are talking to each other.
+pub fn verification_code(
+    transcript: &[u8; 32],
+    initiator_nonce: &[u8; 32],
+    responder_nonce: &[u8; 32],
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(VERIFICATION_CODE_LABEL);
+    hasher.update(transcript);
+    hasher.update(initiator_nonce);
+    hasher.update(responder_nonce);
+    let hash: [u8; 32] = hasher.finalize().into();
+
+    let value = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) % 1_000_000;
+
+    return format!("{:06}", value);
+}
+
+fn expand_direction(hkdf: &Hkdf<Sha256>, label: &[u8]) -> ([u8; 32], [u8; STREAM_NONCE_LENGTH]) {
+    let mut output = [0u8; 32 + STREAM_NONCE_LENGTH];
+    hkdf.expand(label, &mut output)
+        .expect("HKDF output length is valid");
+
+    let mut key = [0u8; 32];
+    let mut nonce = [0u8; STREAM_NONCE_LENGTH];
+    key.copy_from_slice(&output[..32]);
+    nonce.copy_from_slice(&output[32..]);
+
+    return (key, nonce);
+}
+
+/// Derives independent keys and nonces for each direction from the Diffie-Hellman output,
+/// using the handshake transcript as HKDF salt.
+pub fn derive_session_keys(
+    shared_secret: &[u8; 32],
+    transcript: &[u8; 32],
+    role: HandshakeRole,
+) -> SessionKeys {
+    let hkdf = Hkdf::<Sha256>::new(Some(transcript), shared_secret);
+
+    let initiator_to_responder = expand_direction(&hkdf, INITIATOR_TO_RESPONDER_LABEL);
+    let responder_to_initiator = expand_direction(&hkdf, RESPONDER_TO_INITIATOR_LABEL);
+
+    let ((send_key, send_nonce), (receive_key, receive_nonce)) = match role {
+        HandshakeRole::Initiator => (initiator_to_responder, responder_to_initiator),
+        HandshakeRole::Responder => (responder_to_initiator, initiator_to_responder),
+    };
+
+    return SessionKeys {
+        send_key,
+        send_nonce,
+        receive_key,
+        receive_nonce,
+    };
+}
+
 pub struct EncryptedStream<TStream>
 where
     TStream: Read + Write,
@@ -54,13 +195,13 @@ where
     TStream: Read + Write,
 {
     fn read(&mut self, read_buffer: &mut [u8]) -> io::Result<usize> {
-        let mut buffer: Vec<u8> = repeat(0).take(read_buffer.len()).collect();
+        let mut buffer = vec![0u8; read_buffer.len()];
         let read_bytes = self
             .raw_stream
             .read(&mut buffer)
             .expect("Failed to read from encrypted buffer");
 
-        if read_bytes <= 0 {
+        if read_bytes == 0 {
             return Ok(0);
         }
 
@@ -77,7 +218,7 @@ where
     TStream: Read + Write,
 {
     fn write(&mut self, write_buffer: &[u8]) -> io::Result<usize> {
-        let mut buffer: Vec<u8> = repeat(0).take(write_buffer.len()).collect();
+        let mut buffer = vec![0u8; write_buffer.len()];
 
         let Ok(()) = self.cipher.apply_keystream_b2b(write_buffer, &mut buffer) else {
             return Ok(0);
@@ -98,10 +239,271 @@ where
     fn close(&self) {
         self.raw_stream.close();
     }
+
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        return self.raw_stream.wait_readable(timeout);
+    }
+}
+
+/// Authenticated counterpart of [`EncryptedStream`].
+///
+/// Every `write` is sealed into its own frame using XChaCha20-Poly1305 in the STREAM
+/// construction. A frame consists of a 4 byte header (1 byte flags, 3 byte big endian ciphertext
+/// length) followed by the ciphertext, the header itself is authenticated as associated data.
+/// Reading fails with a [`StreamIntegrityError`] if a frame was modified, reordered or if the
+/// stream ends before the final frame written by [`EncryptedReadWrite::finish`].
+/// A final frame flagged as cancel carries a `Cancel` message instead of data, reading it
+/// fails with a [`CancelError`].
+pub struct AuthenticatedStream<TStream>
+where
+    TStream: Read + Write,
+{
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    read_buffer: Vec<u8>,
+    read_position: usize,
+    peer_cancel_reason: Option<CancelReason>,
+    pub raw_stream: TStream,
+}
+
+impl<TStream> AuthenticatedStream<TStream>
+where
+    TStream: Read + Write,
+{
+    pub fn new(session_keys: &SessionKeys, stream: TStream) -> Self {
+        Self {
+            encryptor: Some(EncryptorBE32::new(
+                GenericArray::from_slice(&session_keys.send_key),
+                GenericArray::from_slice(&session_keys.send_nonce),
+            )),
+            decryptor: Some(DecryptorBE32::new(
+                GenericArray::from_slice(&session_keys.receive_key),
+                GenericArray::from_slice(&session_keys.receive_nonce),
+            )),
+            read_buffer: Vec::new(),
+            read_position: 0,
+            peer_cancel_reason: None,
+            raw_stream: stream,
+        }
+    }
+
+    fn frame_header(flags: u8, ciphertext_length: usize) -> [u8; FRAME_HEADER_LENGTH] {
+        let length = (ciphertext_length as u32).to_be_bytes();
+
+        return [flags, length[1], length[2], length[3]];
+    }
+
+    fn write_frame(&mut self, flags: u8, plaintext: &[u8]) -> io::Result<()> {
+        let header = Self::frame_header(flags, plaintext.len() + TAG_LENGTH);
+        let payload = Payload {
+            msg: plaintext,
+            aad: &header,
+        };
+
+        let ciphertext = if flags & FRAME_FLAG_LAST != 0 {
+            let encryptor = self
+                .encryptor
+                .take()
+                .ok_or(StreamIntegrityError::AlreadyFinished)?;
+
+            encryptor.encrypt_last(payload)
+        } else {
+            let encryptor = self
+                .encryptor
+                .as_mut()
+                .ok_or(StreamIntegrityError::AlreadyFinished)?;
+
+            encryptor.encrypt_next(payload)
+        }
+        .map_err(|error| Error::new(Other, error.to_string()))?;
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LENGTH + ciphertext.len());
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&ciphertext);
+
+        return self.raw_stream.write_all(&frame);
+    }
+
+    /// Reads and decrypts the next frame into the read buffer.
+    /// Returns `false` once the final frame has been consumed.
+    fn read_frame(&mut self) -> io::Result<bool> {
+        if let Some(reason) = self.peer_cancel_reason {
+            return Err(CancelError::CancelledByPeer { reason }.into());
+        }
+
+        if self.decryptor.is_none() {
+            return Ok(false);
+        }
+
+        let mut header = [0u8; FRAME_HEADER_LENGTH];
+        self.raw_stream
+            .read_exact(&mut header)
+            .map_err(Self::map_read_error)?;
+
+        let flags = header[0];
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+        if !(TAG_LENGTH..=MAX_FRAME_PLAINTEXT_LENGTH + TAG_LENGTH).contains(&length) {
+            return Err(StreamIntegrityError::InvalidFrameLength { length }.into());
+        }
+
+        let mut ciphertext = vec![0u8; length];
+        self.raw_stream
+            .read_exact(&mut ciphertext)
+            .map_err(Self::map_read_error)?;
+
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: &header,
+        };
+
+        let plaintext = if flags & FRAME_FLAG_LAST != 0 {
+            self.decryptor
+                .take()
+                .expect("Decryptor missing")
+                .decrypt_last(payload)
+        } else {
+            self.decryptor
+                .as_mut()
+                .expect("Decryptor missing")
+                .decrypt_next(payload)
+        }
+        .map_err(|_| StreamIntegrityError::AuthenticationFailed)?;
+
+        if flags & FRAME_FLAG_CANCEL != 0 {
+            let reason = Cancel::decode(plaintext.as_slice())
+                .map(|cancel| cancel.reason())
+                .unwrap_or(CancelReason::Error);
+
+            self.peer_cancel_reason = Some(reason);
+
+            return Err(CancelError::CancelledByPeer { reason }.into());
+        }
+
+        self.read_buffer = plaintext;
+        self.read_position = 0;
+
+        return Ok(true);
+    }
+
+    fn map_read_error(error: Error) -> Error {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            return StreamIntegrityError::Truncated.into();
+        }
+
+        return error;
+    }
+}
+
+impl<TStream> Read for AuthenticatedStream<TStream>
+where
+    TStream: Read + Write,
+{
+    fn read(&mut self, read_buffer: &mut [u8]) -> io::Result<usize> {
+        if read_buffer.is_empty() {
+            return Ok(0);
+        }
+
+        while self.read_position >= self.read_buffer.len() {
+            if !self.read_frame()? {
+                return Ok(0);
+            }
+        }
+
+        let available = &self.read_buffer[self.read_position..];
+        let length = std::cmp::min(available.len(), read_buffer.len());
+        read_buffer[..length].copy_from_slice(&available[..length]);
+        self.read_position += length;
+
+        return Ok(length);
+    }
+}
+
+impl<TStream> Write for AuthenticatedStream<TStream>
+where
+    TStream: Read + Write,
+{
+    fn write(&mut self, write_buffer: &[u8]) -> io::Result<usize> {
+        if write_buffer.is_empty() {
+            return Ok(0);
+        }
+
+        let length = std::cmp::min(write_buffer.len(), MAX_FRAME_PLAINTEXT_LENGTH);
+        self.write_frame(0, &write_buffer[..length])?;
+
+        return Ok(length);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.raw_stream.flush();
+    }
+}
+
+impl<TStream> Close for AuthenticatedStream<TStream>
+where
+    TStream: Close + Read + Write,
+{
+    fn close(&self) {
+        self.raw_stream.close();
+    }
+
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        // Decrypted data, the end of the stream or a cancel frame can be read right away.
+        if self.read_position < self.read_buffer.len()
+            || self.decryptor.is_none()
+            || self.peer_cancel_reason.is_some()
+        {
+            return Ok(true);
+        }
+
+        return self.raw_stream.wait_readable(timeout);
+    }
+}
+
+pub trait EncryptedReadWrite: Read + Write + Send + Close {
+    /// Signals the remote side that no more data will be written.
+    /// For authenticated streams this seals the final frame, which allows the receiver to
+    /// tell a complete transfer from a truncated one.
+    fn finish(&mut self) -> io::Result<()> {
+        return self.flush();
+    }
+
+    /// Aborts the transfer and tells the remote side why, if the stream supports it.
+    /// Nothing can be written afterwards.
+    fn cancel(&mut self, _reason: CancelReason) -> io::Result<()> {
+        return self.flush();
+    }
 }
 
-pub trait EncryptedReadWrite: Read + Write + Send + Close {}
 impl<TStream> EncryptedReadWrite for EncryptedStream<TStream> where
     TStream: Read + Write + Send + Close
 {
 }
+
+impl<TStream> EncryptedReadWrite for AuthenticatedStream<TStream>
+where
+    TStream: Read + Write + Send + Close,
+{
+    fn finish(&mut self) -> io::Result<()> {
+        if self.encryptor.is_none() {
+            return Ok(());
+        }
+
+        self.write_frame(FRAME_FLAG_LAST, &[])?;
+
+        return self.flush();
+    }
+
+    fn cancel(&mut self, reason: CancelReason) -> io::Result<()> {
+        if self.encryptor.is_none() {
+            return Ok(());
+        }
+
+        let cancel = Cancel {
+            reason: reason as i32,
+        };
+        self.write_frame(FRAME_FLAG_LAST | FRAME_FLAG_CANCEL, &cancel.encode_to_vec())?;
+
+        return self.flush();
+    }
+}
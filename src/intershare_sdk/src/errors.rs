@@ -1,3 +1,5 @@
+use protocol::communication::{CancelReason, DeclineReason};
+use std::error::Error as StdError;
 use std::io;
 use std::string::FromUtf8Error;
 use thiserror::Error;
@@ -19,8 +21,8 @@ pub enum ConnectErrors {
     #[error("Failed to get connection details")]
     FailedToGetConnectionDetails,
 
-    #[error("Peripheral declined the connection")]
-    Declined,
+    #[error("Peripheral declined the connection: {reason:?}")]
+    Declined { reason: DeclineReason },
 
     #[error("Failed to get TCP connection details")]
     FailedToGetTcpDetails,
@@ -48,6 +50,30 @@ pub enum ConnectErrors {
 
     #[error("Failed to get transfer request response: {error}")]
     FailedToGetTransferRequestResponse { error: String },
+
+    #[error("The verification code was rejected")]
+    VerificationRejected,
+
+    #[error("The receiver used to verify connections, but this one can't be verified")]
+    DowngradeDetected,
+
+    #[error("The identity key of the receiver changed")]
+    KeyChanged,
+
+    #[error("There is no interrupted transfer to resume")]
+    NoTransferToResume,
+
+    #[error("Transfer was interrupted: {error}")]
+    TransferInterrupted { error: String },
+
+    #[error("The transfer was cancelled")]
+    Cancelled,
+
+    #[error("The receiver cancelled the transfer: {reason:?}")]
+    CancelledByPeer { reason: CancelReason },
+
+    #[error("The receiver failed to store the transfer: {error}")]
+    TransferFailed { error: String },
 }
 
 #[derive(Error, Debug, uniffi::Error)]
@@ -60,6 +86,30 @@ pub enum RequestConvenienceShareErrors {
 
     #[error("Failed to connect")]
     FailedToConnect { error: String },
+
+    #[error("The link does not belong to a share of the other device")]
+    UnknownShare,
+
+    #[error("The share is no longer available")]
+    ShareExpired,
+
+    #[error("The share was downloaded as often as allowed")]
+    DownloadLimitReached,
+
+    #[error("The other device is not the one which created the link")]
+    ServerKeyMismatch,
+}
+
+#[derive(Error, Debug)]
+pub enum ConvenienceDownloadError {
+    #[error("No share with this id is available for download")]
+    UnknownShare,
+
+    #[error("The share expired or was revoked")]
+    Expired,
+
+    #[error("The share was downloaded as often as allowed")]
+    DownloadLimitReached,
 }
 
 #[derive(Error, Debug)]
@@ -101,6 +151,103 @@ pub enum IncomingErrors {
     Rejected,
 }
 
+#[derive(Error, Debug)]
+pub enum HandshakeError {
+    #[error("Invalid verification nonce")]
+    InvalidVerificationNonce,
+
+    #[error("Verification nonce does not match the commitment of the remote peer")]
+    VerificationCommitmentMismatch,
+
+    #[error("Remote peer failed to prove ownership of its identity key")]
+    InvalidIdentityProof,
+
+    #[error("No protocol version is supported by both peers")]
+    IncompatibleProtocolVersion,
+}
+
+#[derive(Error, Debug)]
+pub enum StreamIntegrityError {
+    #[error("Encrypted frame failed authentication")]
+    AuthenticationFailed,
+
+    #[error("Encrypted stream ended before its final frame")]
+    Truncated,
+
+    #[error("Encrypted frame has an invalid length: {length}")]
+    InvalidFrameLength { length: usize },
+
+    #[error("Encrypted stream has already been finished")]
+    AlreadyFinished,
+}
+
+impl From<StreamIntegrityError> for io::Error {
+    fn from(error: StreamIntegrityError) -> Self {
+        return io::Error::new(io::ErrorKind::InvalidData, error);
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FileIntegrityError {
+    #[error("Received files don't match the files that were sent: {files:?}")]
+    HashMismatch { files: Vec<String> },
+}
+
+impl From<FileIntegrityError> for io::Error {
+    fn from(error: FileIntegrityError) -> Self {
+        return io::Error::new(io::ErrorKind::InvalidData, error);
+    }
+}
+
+/// The sender sent an archive entry the receiver didn't accept.
+#[derive(Error, Debug)]
+pub enum ArchiveEntryError {
+    #[error("The archive contains a file which wasn't accepted: {path}")]
+    NotAccepted { path: String },
+
+    #[error("The size of {path} differs from the file manifest")]
+    SizeMismatch { path: String },
+}
+
+impl From<ArchiveEntryError> for io::Error {
+    fn from(error: ArchiveEntryError) -> Self {
+        return io::Error::new(io::ErrorKind::InvalidData, error);
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CancelError {
+    #[error("The remote peer cancelled the transfer: {reason:?}")]
+    CancelledByPeer { reason: CancelReason },
+}
+
+impl From<CancelError> for io::Error {
+    fn from(error: CancelError) -> Self {
+        return io::Error::new(io::ErrorKind::ConnectionAborted, error);
+    }
+}
+
+/// The reason the remote peer gave, if `error` was caused by its cancel frame.
+/// Also finds cancel errors which were wrapped by the tar or zstd readers.
+pub fn peer_cancel_reason(error: &io::Error) -> Option<CancelReason> {
+    let mut current: Option<&(dyn StdError + 'static)> = Some(error);
+
+    while let Some(error) = current {
+        if let Some(CancelError::CancelledByPeer { reason }) = error.downcast_ref::<CancelError>() {
+            return Some(*reason);
+        }
+
+        current = match error.downcast_ref::<io::Error>() {
+            Some(io_error) => io_error
+                .get_ref()
+                .map(|inner_error| inner_error as &(dyn StdError + 'static)),
+            None => error.source(),
+        };
+    }
+
+    return None;
+}
+
 #[derive(Error, Debug, uniffi::Error)]
 pub enum DiscoverySetupError {
     #[error("Unable to setup UDP Discovery")]
@@ -0,0 +1,157 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use log::{info, warn};
+use rand_core::OsRng;
+use std::fmt::Debug;
+
+use crate::encryption::HandshakeRole;
+
+const IDENTITY_PROOF_LABEL: &[u8] = b"InterShare identity proof";
+
+/// Persistent storage for key material, implemented by the host app (e.g. using the Keychain).
+#[uniffi::export(callback_interface)]
+pub trait IdentityStoreDelegate: Send + Sync + Debug {
+    fn load_identity_key(&self) -> Option<Vec<u8>>;
+    fn store_identity_key(&self, private_key: Vec<u8>);
+    fn load_trusted_device_key(&self, device_id: String) -> Option<Vec<u8>>;
+    fn store_trusted_device_key(&self, device_id: String, public_key: Vec<u8>);
+}
+
+#[derive(uniffi::Enum, Clone, Debug, PartialEq)]
+pub enum DeviceTrust {
+    /// The remote device did not prove ownership of an identity key (older version).
+    Unverified,
+
+    /// First connection with this device. Its key will be pinned once the request is accepted.
+    New,
+
+    /// The device proved ownership of the key pinned for its id.
+    Known,
+
+    /// The device uses a different key than the one pinned for its id, or none at all.
+    KeyChanged,
+}
+
+/// Long-term identity of this installation and the keys pinned for other devices.
+pub struct IdentityStore {
+    signing_key: SigningKey,
+    delegate: Option<Box<dyn IdentityStoreDelegate>>,
+}
+
+impl IdentityStore {
+    /// Loads the identity key from the `delegate` or creates and stores a new one.
+    /// Without a delegate, a new identity is generated that only lives as long as this instance.
+    pub fn new(delegate: Option<Box<dyn IdentityStoreDelegate>>) -> Self {
+        let stored_key = delegate
+            .as_ref()
+            .and_then(|delegate| delegate.load_identity_key())
+            .and_then(|key| <[u8; 32]>::try_from(key).ok());
+
+        let signing_key = match stored_key {
+            Some(key) => SigningKey::from_bytes(&key),
+            None => {
+                info!("Generating new identity key");
+                let signing_key = SigningKey::generate(&mut OsRng);
+
+                if let Some(delegate) = &delegate {
+                    delegate.store_identity_key(signing_key.to_bytes().to_vec());
+                }
+
+                signing_key
+            }
+        };
+
+        return Self {
+            signing_key,
+            delegate,
+        };
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        return self.signing_key.verifying_key().to_bytes();
+    }
+
+    /// Signs the handshake transcript, proving ownership of the identity key for this session.
+    pub fn sign_transcript(&self, transcript: &[u8; 32], role: HandshakeRole) -> [u8; 64] {
+        return self
+            .signing_key
+            .sign(&identity_proof_message(transcript, role))
+            .to_bytes();
+    }
+
+    /// A device with a pinned key which doesn't prove ownership of any key counts as
+    /// `KeyChanged`, since only a man-in-the-middle would fall back to the legacy handshake.
+    pub fn get_trust(&self, device_id: &str, public_key: Option<&[u8; 32]>) -> DeviceTrust {
+        let pinned_key = self
+            .delegate
+            .as_ref()
+            .and_then(|delegate| delegate.load_trusted_device_key(device_id.to_string()));
+
+        let Some(public_key) = public_key else {
+            if pinned_key.is_some() {
+                warn!("Device {} no longer proves its identity key", device_id);
+                return DeviceTrust::KeyChanged;
+            }
+
+            return DeviceTrust::Unverified;
+        };
+
+        return match pinned_key {
+            None => DeviceTrust::New,
+            Some(pinned_key) if pinned_key.as_slice() == public_key.as_slice() => {
+                DeviceTrust::Known
+            }
+            Some(_) => {
+                warn!("Identity key of device {} changed", device_id);
+                DeviceTrust::KeyChanged
+            }
+        };
+    }
+
+    /// Whether a key is pinned for the device, i.e. it completed the authenticated
+    /// handshake with this installation before.
+    pub fn has_pinned_key(&self, device_id: &str) -> bool {
+        return self
+            .delegate
+            .as_ref()
+            .and_then(|delegate| delegate.load_trusted_device_key(device_id.to_string()))
+            .is_some();
+    }
+
+    pub fn pin(&self, device_id: &str, public_key: &[u8; 32]) {
+        if let Some(delegate) = &self.delegate {
+            info!("Pinning identity key of device {}", device_id);
+            delegate.store_trusted_device_key(device_id.to_string(), public_key.to_vec());
+        }
+    }
+}
+
+fn identity_proof_message(transcript: &[u8; 32], role: HandshakeRole) -> Vec<u8> {
+    let mut message = Vec::with_capacity(IDENTITY_PROOF_LABEL.len() + 33);
+    message.extend_from_slice(IDENTITY_PROOF_LABEL);
+    message.extend_from_slice(transcript);
+    message.push(match role {
+        HandshakeRole::Initiator => 0,
+        HandshakeRole::Responder => 1,
+    });
+
+    return message;
+}
+
+/// Checks that the remote peer signed this session's transcript with the given identity key.
+pub fn verify_identity_proof(
+    public_key: &[u8; 32],
+    transcript: &[u8; 32],
+    role: HandshakeRole,
+    signature: &[u8; 64],
+) -> bool {
+    let Ok(verifying_key) = VerifyingKey::from_bytes(public_key) else {
+        return false;
+    };
+
+    return verifying_key
+        .verify_strict(
+            &identity_proof_message(transcript, role),
+            &Signature::from_bytes(signature),
+        )
+        .is_ok();
+}
@@ -28,10 +28,15 @@ pub use crate::connection_request::{
     ConnectionRequest, ReceiveProgressDelegate, ReceiveProgressState,
 };
 pub use crate::errors::ConnectErrors;
+pub use crate::identity::{DeviceTrust, IdentityStoreDelegate};
 pub use crate::nearby_server::ConnectionIntentType;
 pub use crate::nearby_server::{InternalNearbyServer, NearbyConnectionDelegate};
-pub use crate::protocol::communication::FileTransferIntent;
+pub use crate::protocol::communication::{
+    CancelReason, DeclineReason, FileManifestEntry, FileTransferIntent,
+};
 pub use crate::protocol::discovery::{BluetoothLeConnectionInfo, TcpConnectionInfo};
+pub use crate::qr_code::{QrCodeColor, QrCodeErrorCorrection, QrCodeModuleShape, QrCodeOptions};
+pub use crate::share_registry::{ShareDelegate, ShareInvalidationReason, ShareLimits};
 pub use crate::share_store::{
     ConnectionMedium, SendProgressDelegate, SendProgressState, ShareStore,
 };
@@ -46,16 +51,23 @@ pub mod connection_request;
 pub mod discovery;
 pub mod encryption;
 pub mod errors;
+pub mod identity;
 pub mod nearby_server;
 mod progress;
+pub mod qr_code;
+pub mod share_link;
+pub mod share_registry;
 pub mod share_store;
 pub mod stream;
-mod tar;
+pub mod tar;
 pub mod transmission;
 #[cfg(target_os = "windows")]
 mod windows;
 
+/// Newest protocol version this build speaks. During a migration, raise this while keeping
+/// `MIN_PROTOCOL_VERSION` at the previous version, so both versions are accepted.
 pub const PROTOCOL_VERSION: u32 = 0;
+pub const MIN_PROTOCOL_VERSION: u32 = 0;
 pub const BLE_SERVICE_UUID: &str = "68D60EB2-8AAA-4D72-8851-BD6D64E169B7";
 pub const BLE_DISCOVERY_CHARACTERISTIC_UUID: &str = "0BEBF3FE-9A5E-4ED1-8157-76281B3F0DA5";
 pub const BLE_BUFFER_SIZE: usize = 10240;
@@ -81,12 +93,13 @@ pub enum VersionCompatibility {
 }
 
 #[uniffi::export]
+#[allow(clippy::absurd_extreme_comparisons)]
 pub fn is_compatible(device: Device) -> VersionCompatibility {
     let Some(remote_device_version) = device.protocol_version else {
         return VersionCompatibility::OutdatedVersion;
     };
 
-    if remote_device_version < PROTOCOL_VERSION {
+    if remote_device_version < MIN_PROTOCOL_VERSION {
         return VersionCompatibility::OutdatedVersion;
     }
 
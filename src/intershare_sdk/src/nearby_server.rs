@@ -1,17 +1,25 @@
-use crate::communication::initiate_receiver_communication;
+use crate::communication::{initiate_receiver_communication, is_downgrade, EncryptedConnection};
 use crate::connection::Connection;
 use crate::connection_request::ConnectionRequest;
-use crate::errors::RequestConvenienceShareErrors;
+use crate::discovery::DiscoveryContext;
+use crate::errors::{ConnectErrors, ConvenienceDownloadError, RequestConvenienceShareErrors};
+use crate::identity::{IdentityStore, IdentityStoreDelegate};
+use crate::qr_code::{generate_qr_code, generate_qr_code_svg, QrCodeOptions};
+use crate::share_link::{ShareLink, ShareLinkError};
+use crate::share_registry::{ShareDelegate, ShareLimits, ShareRegistry};
 use crate::share_store::ShareStore;
 use crate::stream::Close;
 use crate::stream::NativeStreamDelegate;
+use crate::transmission::http::HttpServer;
 use crate::transmission::tcp::TcpServer;
-use crate::{init_logger, PROTOCOL_VERSION};
+use crate::transmission::TransmissionSetupError;
+use crate::{init_logger, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 use local_ip_address::local_ip;
-use log::{error, info};
+use log::{error, info, warn};
 use prost_stream::Stream;
+use protocol::communication::convenience_download_response::Status;
 use protocol::communication::request::RequestTypes;
-use protocol::communication::Request;
+use protocol::communication::{ConvenienceDownloadResponse, Request};
 use protocol::discovery::device_discovery_message::Content;
 use protocol::discovery::{
     BluetoothLeConnectionInfo, Device, DeviceConnectionInfo, DeviceDiscoveryMessage,
@@ -20,14 +28,20 @@ use protocol::discovery::{
 use protocol::prost::Message;
 use std::fmt::Debug;
 use std::io::{Read, Write};
+use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::runtime::Handle;
 use tokio::sync::RwLock;
-use url::Url;
+use tokio::task::JoinHandle;
 
 #[cfg(target_os = "windows")]
 use windows::Devices::Bluetooth::GenericAttributeProfile::*;
 
+/// How long discovery reads are answered with an offline message after `stop()`, before the
+/// BLE server stops. Scanners on Apple platforms read every device again each 30 seconds.
+const OFFLINE_ANNOUNCEMENT_DURATION: Duration = Duration::from_secs(30);
+
 #[uniffi::export(callback_interface)]
 pub trait BleServerImplementationDelegate: Send + Sync + Debug {
     fn start_server(&self);
@@ -64,16 +78,21 @@ pub struct CurrentShareStore {
 #[derive(uniffi::Object)]
 pub struct InternalNearbyServer {
     pub(crate) tcp_server: RwLock<Option<TcpServer>>,
-    ble_server_implementation: RwLock<Option<Box<dyn BleServerImplementationDelegate>>>,
+    http_server: RwLock<Option<HttpServer>>,
+    ble_server_implementation: Arc<RwLock<Option<Box<dyn BleServerImplementationDelegate>>>>,
     ble_l2_cap_client: Arc<RwLock<Option<Box<dyn L2CapDelegate>>>>,
-    pub advertise: RwLock<bool>,
-    file_storage: String,
+    pub advertise: Arc<RwLock<bool>>,
+    /// Stops the BLE server once scanners had time to read that this device went offline.
+    offline_announcement: RwLock<Option<JoinHandle<()>>>,
+    pub(crate) file_storage: String,
     pub device_connection_info: RwLock<DeviceConnectionInfo>,
-    nearby_connection_delegate: Option<Arc<RwLock<Box<dyn NearbyConnectionDelegate>>>>,
-    pub(crate) current_share_store: Arc<RwLock<Option<Arc<ShareStore>>>>,
+    pub(crate) nearby_connection_delegate: Option<Arc<RwLock<Box<dyn NearbyConnectionDelegate>>>>,
+    pub(crate) shares: Arc<ShareRegistry>,
+    pub(crate) identity: Arc<RwLock<Arc<IdentityStore>>>,
+    discovery_context: RwLock<Arc<DiscoveryContext>>,
 
     #[cfg(target_os = "windows")]
-    pub(crate) gatt_service_provider: std::sync::RwLock<Option<GattServiceProvider>>,
+    pub(crate) gatt_service_provider: Arc<std::sync::RwLock<Option<GattServiceProvider>>>,
 
     requested_download_id: Arc<RwLock<Option<String>>>,
 }
@@ -97,23 +116,24 @@ impl InternalNearbyServer {
             tcp: None,
         };
 
-        let nearby_connection_delegate = match delegate {
-            Some(d) => Some(Arc::new(RwLock::new(d))),
-            None => None,
-        };
+        let nearby_connection_delegate = delegate.map(|d| Arc::new(RwLock::new(d)));
 
         return Self {
             tcp_server: RwLock::new(None),
-            ble_server_implementation: RwLock::new(None),
+            http_server: RwLock::new(None),
+            ble_server_implementation: Arc::new(RwLock::new(None)),
             ble_l2_cap_client: Arc::new(RwLock::new(None)),
-            advertise: RwLock::new(false),
+            advertise: Arc::new(RwLock::new(false)),
+            offline_announcement: RwLock::new(None),
             file_storage,
             device_connection_info: RwLock::new(device_connection_info),
             nearby_connection_delegate,
-            current_share_store: Arc::new(RwLock::new(None)),
+            shares: Arc::new(ShareRegistry::default()),
+            identity: Arc::new(RwLock::new(Arc::new(IdentityStore::new(None)))),
+            discovery_context: RwLock::new(DiscoveryContext::default_context()),
 
             #[cfg(target_os = "windows")]
-            gatt_service_provider: std::sync::RwLock::new(None),
+            gatt_service_provider: Arc::new(std::sync::RwLock::new(None)),
 
             requested_download_id: Arc::new(RwLock::new(None)),
         };
@@ -123,6 +143,17 @@ impl InternalNearbyServer {
         *self.ble_l2_cap_client.blocking_write() = Some(delegate);
     }
 
+    /// Loads (or creates) the identity key of this installation and the pinned keys of other
+    /// devices from the host app. Without it, a new identity is used on every launch.
+    pub fn set_identity_store(&self, delegate: Box<dyn IdentityStoreDelegate>) {
+        *self.identity.blocking_write() = Arc::new(IdentityStore::new(Some(delegate)));
+    }
+
+    /// The context shares look up receivers in. Defaults to the default `DiscoveryContext`.
+    pub fn set_discovery_context(&self, context: Arc<DiscoveryContext>) {
+        *self.discovery_context.blocking_write() = context;
+    }
+
     pub fn add_bluetooth_implementation(
         &self,
         implementation: Box<dyn BleServerImplementationDelegate>,
@@ -130,30 +161,12 @@ impl InternalNearbyServer {
         *self.ble_server_implementation.blocking_write() = Some(implementation)
     }
 
+    /// Answers discovery reads. After `stop()`, scanners are told that this device went offline.
     pub async fn get_advertisement_data(&self) -> Vec<u8> {
-        if *self.advertise.read().await {
-            return DeviceDiscoveryMessage {
-                content: Some(Content::DeviceConnectionInfo(
-                    self.device_connection_info.read().await.clone(),
-                )),
-            }
-            .encode_length_delimited_to_vec();
-
-            // self.mut_variables.write().await.discovery_message = message;
-        } else {
-            // return DeviceDiscoveryMessage {
-            //     content: Some(
-            //         Content::OfflineDeviceId(
-            //             self.handler.variables
-            //                 .read()
-            //                 .await
-            //                 .device_connection_info.device?.id.clone()
-            //         )
-            //     ),
-            // }.encode_length_delimited_to_vec();
-        }
-
-        return vec![];
+        return discovery_message(
+            *self.advertise.read().await,
+            &*self.device_connection_info.read().await,
+        );
     }
 
     pub fn change_device(&self, new_device: Device) {
@@ -181,78 +194,83 @@ impl InternalNearbyServer {
         return None;
     }
 
-    /// https://share.intershare.app?id=hgf8o47fdsb394mv385&ip=192.168.12.13&port=5200&device_id=9A403351-A926-4D1C-855F-432A6ED51E0E&protocol_version=1
+    /// Downloads the share of a link created by `ShareStore::generate_link`. Legacy links
+    /// (`?i=&ip=&p=&d=`) are still accepted.
+    ///
+    /// Returns the downloaded share as a `ConnectionRequest`. Accept it to receive the files.
+    /// To retry an interrupted download, request the same link again. The new request
+    /// continues where the previous one stopped, see `ConnectionRequest::get_resumable_bytes()`.
+    #[allow(clippy::absurd_extreme_comparisons)]
     pub async fn request_download(
         &self,
         link: String,
-    ) -> Result<(), RequestConvenienceShareErrors> {
-        let parsed_url =
-            Url::parse(&link).map_err(|_| RequestConvenienceShareErrors::NotAValidLink)?;
+    ) -> Result<Arc<ConnectionRequest>, RequestConvenienceShareErrors> {
+        let share_link = ShareLink::parse(&link).map_err(|error| match error {
+            ShareLinkError::Invalid => RequestConvenienceShareErrors::NotAValidLink,
+            ShareLinkError::UnsupportedFormat => {
+                RequestConvenienceShareErrors::IncompatibleProtocolVersion
+            }
+        })?;
 
-        if parsed_url.host_str() != Some("s.intershare.app") {
-            error!("Invalid host: {:?}", parsed_url.host_str());
-            return Err(RequestConvenienceShareErrors::NotAValidLink);
+        if share_link.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(RequestConvenienceShareErrors::IncompatibleProtocolVersion);
         }
 
-        let id = parsed_url
-            .query_pairs()
-            .find(|(key, _)| key == "i")
-            .map(|(_, value)| value)
-            .filter(|val| !val.is_empty())
-            .ok_or(RequestConvenienceShareErrors::NotAValidLink)?
-            .to_string();
-
-        let ip = parsed_url
-            .query_pairs()
-            .find(|(key, _)| key == "ip")
-            .map(|(_, value)| value)
-            .filter(|val| !val.is_empty())
-            .ok_or(RequestConvenienceShareErrors::NotAValidLink)?
-            .to_string();
-
-        let port = parsed_url
-            .query_pairs()
-            .find(|(key, _)| key == "p")
-            .map(|(_, value)| value)
-            .filter(|val| !val.is_empty())
-            .ok_or(RequestConvenienceShareErrors::NotAValidLink)?
-            .parse::<u32>()
-            .map_err(|_| RequestConvenienceShareErrors::NotAValidLink)?;
-
-        // let device_id = parsed_url
-        //     .query_pairs()
-        //     .find(|(key, _)| key == "d")
-        //     .map(|(_, value)| value)
-        //     .filter(|val| !val.is_empty())
-        //     .ok_or(RequestConvenienceShareErrors::NotAValidLink)
-        //     ?.to_string();
-
-        // let protocol_version = parsed_url
-        //     .query_pairs()
-        //     .find(|(key, _)| key == "v")
-        //     .map(|(_, value)| value)
-        //     .filter(|val| !val.is_empty())
-        //     .ok_or(RequestConvenienceShareErrors::NotAValidLink)
-        //     ?.to_string();
-
-        let connection = Connection::new(self.ble_l2_cap_client.clone());
-
-        let connection_details = DeviceConnectionInfo {
-            device: None,
-            tcp: Some(TcpConnectionInfo { hostname: ip, port }),
-            ble: None,
-        };
+        let connection = Connection::new(
+            self.ble_l2_cap_client.clone(),
+            self.identity.read().await.clone(),
+            self.discovery_context.read().await.clone(),
+        );
+
+        let mut encrypted_connection = None;
+        let mut last_error = None;
+
+        for address in &share_link.addresses {
+            let hostname = match address.ip() {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("[{}]", ip),
+            };
+
+            let connection_details = DeviceConnectionInfo {
+                device: None,
+                tcp: Some(TcpConnectionInfo {
+                    hostname,
+                    port: address.port() as u32,
+                }),
+                ble: None,
+            };
 
-        let mut encrypted_stream = match connection.connect_tcp(&connection_details).await {
-            Ok(connection) => connection,
-            Err(err) => {
-                error!("Error while trying to connect: {:?}", err);
-                return Err(RequestConvenienceShareErrors::FailedToConnect {
-                    error: err.to_string(),
-                });
+            match connection.connect_tcp(&connection_details).await {
+                Ok(connection) => {
+                    encrypted_connection = Some(connection);
+                    break;
+                }
+                Err(ConnectErrors::InvalidProtocolVersion) => {
+                    return Err(RequestConvenienceShareErrors::IncompatibleProtocolVersion);
+                }
+                Err(error) => {
+                    warn!("Unable to connect to {}: {:?}", address, error);
+                    last_error = Some(error);
+                }
             }
+        }
+
+        let Some(mut encrypted_connection) = encrypted_connection else {
+            return Err(RequestConvenienceShareErrors::FailedToConnect {
+                error: last_error
+                    .map(|error| error.to_string())
+                    .unwrap_or_default(),
+            });
         };
 
+        if !share_link.matches_key(encrypted_connection.peer_identity_key.as_ref()) {
+            error!("The identity key of the other device does not match the link");
+            encrypted_connection.stream.close();
+            return Err(RequestConvenienceShareErrors::ServerKeyMismatch);
+        }
+
+        let id = share_link.share_id;
+
         let request = Request {
             r#type: RequestTypes::ConvenienceDownloadRequest as i32,
             device: self.device_connection_info.read().await.device.clone(),
@@ -262,10 +280,38 @@ impl InternalNearbyServer {
 
         *self.requested_download_id.write().await = Some(id);
 
-        let mut proto_stream = Stream::new(&mut encrypted_stream);
+        let mut proto_stream = Stream::new(&mut encrypted_connection.stream);
         let _ = proto_stream.send(&request);
 
-        return Ok(());
+        let response = proto_stream
+            .recv::<ConvenienceDownloadResponse>()
+            .map_err(|error| RequestConvenienceShareErrors::FailedToConnect {
+                error: error.to_string(),
+            })?;
+
+        match response.status() {
+            Status::Ok => {}
+            Status::UnknownShare => return Err(RequestConvenienceShareErrors::UnknownShare),
+            Status::Expired => return Err(RequestConvenienceShareErrors::ShareExpired),
+            Status::DownloadLimitReached => {
+                return Err(RequestConvenienceShareErrors::DownloadLimitReached)
+            }
+        }
+
+        let share_request = proto_stream.recv::<Request>().map_err(|error| {
+            RequestConvenienceShareErrors::FailedToConnect {
+                error: error.to_string(),
+            }
+        })?;
+
+        let connection_request = ConnectionRequest::new(
+            share_request,
+            encrypted_connection,
+            self.file_storage.clone(),
+            self.identity.read().await.clone(),
+        );
+
+        return Ok(Arc::new(connection_request));
     }
 
     pub async fn start(&self) {
@@ -286,7 +332,7 @@ impl InternalNearbyServer {
                     info!("IP: {}", my_local_ip);
                     info!("Port: {}", tcp_server.port);
 
-                    let port = tcp_server.port.clone();
+                    let port = tcp_server.port;
                     *self.tcp_server.write().await = Some(tcp_server);
 
                     self.start_loop().await;
@@ -301,6 +347,7 @@ impl InternalNearbyServer {
             }
         }
 
+        self.finish_offline_announcement().await;
         *self.advertise.write().await = true;
 
         #[cfg(target_os = "windows")]
@@ -324,17 +371,23 @@ impl InternalNearbyServer {
     }
 
     pub async fn share_text(&self, text: String, allow_convenience_share: bool) -> Arc<ShareStore> {
-        let share_store = Arc::new(ShareStore::new(
-            None,
-            Some(text),
-            allow_convenience_share,
-            self.ble_l2_cap_client.clone(),
-            self.device_connection_info.read().await.clone(),
-        ));
-
-        *self.current_share_store.write().await = Some(share_store.clone());
+        return self
+            .create_share(
+                None,
+                Some(text),
+                allow_convenience_share,
+                ShareLimits::default(),
+            )
+            .await;
+    }
 
-        return share_store;
+    /// Shares text with a link which stops working after the given limits.
+    pub async fn share_text_with_limits(
+        &self,
+        text: String,
+        limits: ShareLimits,
+    ) -> Arc<ShareStore> {
+        return self.create_share(None, Some(text), true, limits).await;
     }
 
     pub fn handle_incoming_connection(&self, native_stream_handle: Box<dyn NativeStreamDelegate>) {
@@ -346,35 +399,31 @@ impl InternalNearbyServer {
         file_paths: Vec<String>,
         allow_convenience_share: bool,
     ) -> Arc<ShareStore> {
-        let share_store = Arc::new(ShareStore::new(
-            Some(file_paths),
-            None,
-            allow_convenience_share,
-            self.ble_l2_cap_client.clone(),
-            self.device_connection_info.read().await.clone(),
-        ));
-
-        *self.current_share_store.write().await = Some(share_store.clone());
+        return self
+            .create_share(
+                Some(file_paths),
+                None,
+                allow_convenience_share,
+                ShareLimits::default(),
+            )
+            .await;
+    }
 
-        return share_store;
+    /// Shares files with a link which stops working after the given limits.
+    pub async fn share_files_with_limits(
+        &self,
+        file_paths: Vec<String>,
+        limits: ShareLimits,
+    ) -> Arc<ShareStore> {
+        return self
+            .create_share(Some(file_paths), None, true, limits)
+            .await;
     }
 
-    // pub(crate) async fn received_convenience_download_request(request: Request, current_share_store: Arc<RwLock<Option<Arc<ShareStore>>>>) {
-    //     let Some(current_share_store) = &*current_share_store.read().await else {
-    //         return;
-    //     };
-    //
-    //     if request.share_id != Some(current_share_store.request_id.clone()) {
-    //         warn!("Received convenience download request, but wrong with a wrong ID. Expected: {}, but received {:?}", current_share_store.request_id.clone(), request.share_id());
-    //         return;
-    //     }
-    //
-    //     let Some(device) = request.device else {
-    //         return;
-    //     };
-    //
-    //     let _ = current_share_store.send_to(device, None).await;
-    // }
+    /// Notified when a share expires or reaches its download limit.
+    pub fn set_share_delegate(&self, delegate: Box<dyn ShareDelegate>) {
+        self.shares.set_delegate(delegate);
+    }
 
     pub async fn stop(&self) {
         *self.advertise.write().await = false;
@@ -382,15 +431,110 @@ impl InternalNearbyServer {
 
         *self.tcp_server.write().await = None;
 
+        // The BLE server keeps running for a while to announce that this device went offline.
+        let ble_server_implementation = self.ble_server_implementation.clone();
         #[cfg(target_os = "windows")]
-        self.stop_windows_server();
-
-        #[cfg(not(target_os = "windows"))]
-        if let Some(ble_advertisement_implementation) =
-            &*self.ble_server_implementation.blocking_read()
+        let gatt_service_provider = self.gatt_service_provider.clone();
+
+        let offline_announcement = tokio::spawn(async move {
+            tokio::time::sleep(OFFLINE_ANNOUNCEMENT_DURATION).await;
+
+            stop_ble_server(
+                &ble_server_implementation,
+                #[cfg(target_os = "windows")]
+                &gatt_service_provider,
+            )
+            .await;
+        });
+
+        if let Some(previous_announcement) = self
+            .offline_announcement
+            .write()
+            .await
+            .replace(offline_announcement)
         {
-            ble_advertisement_implementation.stop_server();
+            previous_announcement.abort();
+        }
+    }
+
+    /// Serves the current convenience share to browsers, see `generate_http_link()`.
+    pub async fn start_http_server(&self) -> Result<(), TransmissionSetupError> {
+        let mut http_server = self.http_server.write().await;
+
+        if http_server.is_some() {
+            return Ok(());
         }
+
+        let new_http_server = self.new_http_server().await.map_err(|error| {
+            TransmissionSetupError::UnableToStartHttpServer {
+                error: error.to_string(),
+            }
+        })?;
+
+        *http_server = Some(new_http_server);
+
+        return Ok(());
+    }
+
+    pub async fn stop_http_server(&self) {
+        *self.http_server.write().await = None;
+    }
+
+    /// Link to the landing page of a share, which can be opened in any browser.
+    pub async fn generate_http_link(&self, share_id: String) -> Option<String> {
+        let port = self.http_server.read().await.as_ref()?.port;
+        let share_store = self.shares.get(&share_id)?;
+        let ip = self.get_current_ip()?;
+
+        return Some(format!("http://{}:{}/{}", ip, port, share_store.request_id));
+    }
+
+    /// Shares which can be downloaded using their link, in no particular order.
+    pub async fn list_shares(&self) -> Vec<Arc<ShareStore>> {
+        return self.shares.list();
+    }
+
+    pub async fn get_share(&self, share_id: String) -> Option<Arc<ShareStore>> {
+        return self.shares.get(&share_id);
+    }
+
+    /// Stops serving a share. Its link and QR code don't work anymore.
+    /// Returns `false` if there is no such share.
+    pub async fn revoke_share(&self, share_id: String) -> bool {
+        info!("Revoking share {}", share_id);
+        return self.shares.revoke(&share_id);
+    }
+
+    /// Link to a page where browsers can upload files to this device.
+    /// Uploads are passed to the `NearbyConnectionDelegate` like requests of other devices.
+    pub async fn generate_upload_link(&self) -> Option<String> {
+        let http_server = self.http_server.read().await;
+        let http_server = http_server.as_ref()?;
+        let ip = self.get_current_ip()?;
+
+        return Some(format!(
+            "http://{}:{}/upload/{}",
+            ip, http_server.port, http_server.upload_token
+        ));
+    }
+
+    pub async fn generate_upload_qr_code(&self, dark_mode: bool) -> Option<Vec<u8>> {
+        return self
+            .generate_upload_qr_code_with_options(QrCodeOptions::for_dark_mode(dark_mode))
+            .await;
+    }
+
+    pub async fn generate_upload_qr_code_with_options(
+        &self,
+        options: QrCodeOptions,
+    ) -> Option<Vec<u8>> {
+        let link = self.generate_upload_link().await?;
+        return generate_qr_code(link, &options);
+    }
+
+    pub async fn generate_upload_qr_code_svg(&self, options: QrCodeOptions) -> Option<String> {
+        let link = self.generate_upload_link().await?;
+        return generate_qr_code_svg(link, &options);
     }
 
     pub fn get_device_name(&self) -> Option<String> {
@@ -400,6 +544,102 @@ impl InternalNearbyServer {
 }
 
 impl InternalNearbyServer {
+    /// Stops the BLE server right away if it is still announcing that this device went offline.
+    async fn finish_offline_announcement(&self) {
+        let Some(offline_announcement) = self.offline_announcement.write().await.take() else {
+            return;
+        };
+
+        offline_announcement.abort();
+
+        // The announcement already stopped the server.
+        if offline_announcement.await.is_ok() {
+            return;
+        }
+
+        stop_ble_server(
+            &self.ble_server_implementation,
+            #[cfg(target_os = "windows")]
+            &self.gatt_service_provider,
+        )
+        .await;
+    }
+
+    async fn create_share(
+        &self,
+        file_paths: Option<Vec<String>>,
+        clipboard: Option<String>,
+        allow_convenience_share: bool,
+        limits: ShareLimits,
+    ) -> Arc<ShareStore> {
+        let share_store = Arc::new(ShareStore::new(
+            file_paths,
+            clipboard,
+            allow_convenience_share,
+            self.ble_l2_cap_client.clone(),
+            self.device_connection_info.read().await.clone(),
+            self.identity.read().await.clone(),
+            self.discovery_context.read().await.clone(),
+        ));
+
+        self.shares.insert(share_store.clone(), &limits);
+
+        // Lets the delegate know when the share expired, even if nobody requests it anymore.
+        if let Some(expires_at) = limits.expires_at() {
+            let shares = self.shares.clone();
+
+            tokio::spawn(async move {
+                tokio::time::sleep_until(expires_at.into()).await;
+                shares.expire_shares();
+            });
+        }
+
+        return share_store;
+    }
+
+    /// Answers a request of a device which opened the link of a share,
+    /// and sends the share over the same connection if the id is valid.
+    pub(crate) async fn received_convenience_download_request(
+        request: Request,
+        mut encrypted_connection: EncryptedConnection,
+        shares: Arc<ShareRegistry>,
+    ) {
+        let share_store = shares.start_download(request.share_id.as_deref());
+
+        let status = match &share_store {
+            Ok(_) => Status::Ok,
+            Err(ConvenienceDownloadError::UnknownShare) => Status::UnknownShare,
+            Err(ConvenienceDownloadError::Expired) => Status::Expired,
+            Err(ConvenienceDownloadError::DownloadLimitReached) => Status::DownloadLimitReached,
+        };
+
+        let _ = Stream::new(&mut encrypted_connection.stream).send(&ConvenienceDownloadResponse {
+            status: status as i32,
+        });
+
+        let share_store = match share_store {
+            Ok(share_store) => share_store,
+            Err(error) => {
+                warn!(
+                    "Rejected convenience download request for {:?}: {}",
+                    request.share_id, error
+                );
+                let _ = encrypted_connection.stream.finish();
+                encrypted_connection.stream.close();
+                return;
+            }
+        };
+
+        // Only downloads the receiver accepted and stored count towards the limit.
+        let result = share_store.serve_download(encrypted_connection).await;
+
+        if let Err(error) = &result {
+            error!("Failed to serve convenience download: {}", error);
+        }
+
+        shares.finish_download(&share_store.request_id, result.is_ok());
+    }
+
     fn handle_incoming_connection_generic<T>(&self, native_stream_handle: T)
     where
         T: Read + Write + Send + Close + 'static,
@@ -411,20 +651,33 @@ impl InternalNearbyServer {
         };
 
         let file_storage = self.file_storage.clone();
-        // let current_share_store = self.current_share_store.clone();
+        let identity = self.identity.blocking_read().clone();
+        let shares = self.shares.clone();
 
         if Handle::try_current().is_err() {
             // Create a new runtime if one doesn't exist
             let rt = tokio::runtime::Runtime::new().expect("Failed to create Tokio runtime");
             rt.spawn(async move {
-                Self::process_incoming_connection(native_stream_handle, delegate, file_storage)
-                    .await;
+                Self::process_incoming_connection(
+                    native_stream_handle,
+                    delegate,
+                    file_storage,
+                    identity,
+                    shares,
+                )
+                .await;
             });
         } else {
             // Already in a Tokio runtime
             tokio::spawn(async move {
-                Self::process_incoming_connection(native_stream_handle, delegate, file_storage)
-                    .await;
+                Self::process_incoming_connection(
+                    native_stream_handle,
+                    delegate,
+                    file_storage,
+                    identity,
+                    shares,
+                )
+                .await;
             });
         }
     }
@@ -433,20 +686,23 @@ impl InternalNearbyServer {
         native_stream_handle: T,
         delegate: Arc<RwLock<Box<dyn NearbyConnectionDelegate>>>,
         file_storage: String,
+        identity: Arc<IdentityStore>,
+        shares: Arc<ShareRegistry>,
     ) where
         T: Read + Write + Send + Close + 'static,
     {
-        let mut encrypted_stream = match initiate_receiver_communication(native_stream_handle) {
-            Ok(request) => request,
-            Err(error) => {
-                error!("Encryption error {:}", error);
-                return;
-            }
-        };
+        let mut encrypted_connection =
+            match initiate_receiver_communication(native_stream_handle, &identity) {
+                Ok(request) => request,
+                Err(error) => {
+                    error!("Encryption error {:}", error);
+                    return;
+                }
+            };
 
         info!("Received encrypted connection request.");
 
-        let mut prost_stream = Stream::new(&mut encrypted_stream);
+        let mut prost_stream = Stream::new(&mut encrypted_connection.stream);
         let request = match prost_stream.recv::<Request>() {
             Ok(message) => message,
             Err(error) => {
@@ -455,17 +711,71 @@ impl InternalNearbyServer {
             }
         };
 
+        let sender_id = request.device.as_ref().map(|device| device.id.as_str());
+
+        if is_downgrade(
+            &encrypted_connection,
+            &identity,
+            sender_id.unwrap_or_default(),
+        ) {
+            encrypted_connection.stream.close();
+            return;
+        }
+
         if request.r#type == RequestTypes::ShareRequest as i32 {
-            let connection_request =
-                ConnectionRequest::new(request, Box::new(encrypted_stream), file_storage.clone());
+            let connection_request = ConnectionRequest::new(
+                request,
+                encrypted_connection,
+                file_storage.clone(),
+                identity,
+            );
 
             info!("Sending received_connection_request delegate.");
             delegate
                 .read()
                 .await
                 .received_connection_request(Arc::new(connection_request));
-        } else {
-            // NearbyServer::received_convenience_download_request(request, current_share_store).await;
+        } else if request.r#type == RequestTypes::ConvenienceDownloadRequest as i32 {
+            Self::received_convenience_download_request(request, encrypted_connection, shares)
+                .await;
         }
     }
 }
+
+/// What discovery reads are answered with.
+pub(crate) fn discovery_message(
+    advertise: bool,
+    device_connection_info: &DeviceConnectionInfo,
+) -> Vec<u8> {
+    let content = if advertise {
+        Content::DeviceConnectionInfo(device_connection_info.clone())
+    } else {
+        let Some(device) = &device_connection_info.device else {
+            return vec![];
+        };
+
+        Content::OfflineDeviceId(device.id.clone())
+    };
+
+    return DeviceDiscoveryMessage {
+        content: Some(content),
+    }
+    .encode_length_delimited_to_vec();
+}
+
+async fn stop_ble_server(
+    #[cfg_attr(target_os = "windows", allow(unused_variables))] ble_server_implementation: &RwLock<
+        Option<Box<dyn BleServerImplementationDelegate>>,
+    >,
+    #[cfg(target_os = "windows")] gatt_service_provider: &std::sync::RwLock<
+        Option<GattServiceProvider>,
+    >,
+) {
+    #[cfg(target_os = "windows")]
+    crate::windows::ble_server::stop_windows_server(gatt_service_provider);
+
+    #[cfg(not(target_os = "windows"))]
+    if let Some(ble_advertisement_implementation) = &*ble_server_implementation.read().await {
+        ble_advertisement_implementation.stop_server();
+    }
+}
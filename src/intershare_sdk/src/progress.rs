@@ -1,17 +1,19 @@
 use std::io::{self, Read, Write};
 
-pub struct ProgressWriter<W: Write, F: FnMut(u64)> {
+pub struct ProgressWriter<W: Write, F: FnMut(u64), C: Fn() -> bool> {
     inner: W,
     sent: u64,
     progress_callback: F,
+    should_cancel: C,
 }
 
-impl<W: Write, F: FnMut(u64)> ProgressWriter<W, F> {
-    pub fn new(inner: W, progress_callback: F) -> Self {
+impl<W: Write, F: FnMut(u64), C: Fn() -> bool> ProgressWriter<W, F, C> {
+    pub fn new(inner: W, progress_callback: F, should_cancel: C) -> Self {
         Self {
             inner,
             sent: 0,
             progress_callback,
+            should_cancel,
         }
     }
 
@@ -20,8 +22,12 @@ impl<W: Write, F: FnMut(u64)> ProgressWriter<W, F> {
     }
 }
 
-impl<W: Write, F: FnMut(u64)> Write for ProgressWriter<W, F> {
+impl<W: Write, F: FnMut(u64), C: Fn() -> bool> Write for ProgressWriter<W, F, C> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if (self.should_cancel)() {
+            return Err(io::Error::other("transfer cancelled"));
+        }
+
         let written_bytes = self.inner.write(buf)?;
         self.sent += written_bytes as u64;
         (self.progress_callback)(self.sent);
@@ -50,12 +56,16 @@ impl<R: Read, F: FnMut(u64), C: Fn() -> bool> ProgressReader<R, F, C> {
             should_cancel,
         }
     }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
 }
 
 impl<R: Read, F: FnMut(u64), C: Fn() -> bool> Read for ProgressReader<R, F, C> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if (self.should_cancel)() {
-            return Err(io::Error::new(io::ErrorKind::Other, "transfer cancelled"));
+            return Err(io::Error::other("transfer cancelled"));
         }
 
         let read_bytes = self.inner.read(buf)?;
@@ -0,0 +1,184 @@
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use fast_qr::convert::{
+    image::ImageBuilder, svg::SvgBuilder, Builder, ImageBackgroundShape, Shape,
+};
+use fast_qr::qr::{QRBuilder, QRCode};
+use fast_qr::ECL;
+use log::error;
+
+#[derive(uniffi::Record, Clone, Copy, Debug, PartialEq)]
+pub struct QrCodeColor {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub alpha: u8,
+}
+
+impl QrCodeColor {
+    pub const BLACK: Self = Self::rgba(0, 0, 0, 255);
+    pub const WHITE: Self = Self::rgba(255, 255, 255, 255);
+    pub const TRANSPARENT: Self = Self::rgba(0, 0, 0, 0);
+
+    const fn rgba(red: u8, green: u8, blue: u8, alpha: u8) -> Self {
+        return Self {
+            red,
+            green,
+            blue,
+            alpha,
+        };
+    }
+
+    fn to_array(self) -> [u8; 4] {
+        return [self.red, self.green, self.blue, self.alpha];
+    }
+}
+
+/// How much of a QR code can be damaged or covered while it still scans.
+#[derive(uniffi::Enum, Clone, Copy, Debug, PartialEq)]
+pub enum QrCodeErrorCorrection {
+    /// About 7%.
+    Low,
+    /// About 15%.
+    Medium,
+    /// About 25%.
+    Quartile,
+    /// About 30%. Use this together with a logo.
+    High,
+}
+
+#[derive(uniffi::Enum, Clone, Copy, Debug, PartialEq)]
+pub enum QrCodeModuleShape {
+    Square,
+    Circle,
+    RoundedSquare,
+    Vertical,
+    Horizontal,
+    Diamond,
+}
+
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct QrCodeOptions {
+    /// Width of PNG images in pixels. SVG images scale to any size.
+    pub size: u32,
+    pub module_color: QrCodeColor,
+    pub background_color: QrCodeColor,
+    /// Empty modules around the code.
+    pub quiet_zone: u32,
+    pub error_correction: QrCodeErrorCorrection,
+    pub shape: QrCodeModuleShape,
+    /// PNG, JPEG or SVG image shown in the centre of the code.
+    pub logo: Option<Vec<u8>>,
+}
+
+impl Default for QrCodeOptions {
+    fn default() -> Self {
+        return Self {
+            size: 300,
+            module_color: QrCodeColor::BLACK,
+            background_color: QrCodeColor::TRANSPARENT,
+            quiet_zone: 4,
+            error_correction: QrCodeErrorCorrection::Quartile,
+            shape: QrCodeModuleShape::Circle,
+            logo: None,
+        };
+    }
+}
+
+impl QrCodeOptions {
+    /// The default look, with white modules for dark backgrounds.
+    pub fn for_dark_mode(dark_mode: bool) -> Self {
+        let mut options = Self::default();
+
+        if dark_mode {
+            options.module_color = QrCodeColor::WHITE;
+        }
+
+        return options;
+    }
+
+    fn apply<T: Builder>(&self, builder: &mut T) {
+        builder
+            .shape(match self.shape {
+                QrCodeModuleShape::Square => Shape::Square,
+                QrCodeModuleShape::Circle => Shape::Circle,
+                QrCodeModuleShape::RoundedSquare => Shape::RoundedSquare,
+                QrCodeModuleShape::Vertical => Shape::Vertical,
+                QrCodeModuleShape::Horizontal => Shape::Horizontal,
+                QrCodeModuleShape::Diamond => Shape::Diamond,
+            })
+            .module_color(self.module_color.to_array())
+            .background_color(self.background_color.to_array())
+            .margin(self.quiet_zone as usize);
+
+        if let Some(logo) = &self.logo {
+            builder
+                .image(logo_data_url(logo))
+                .image_background_color(self.background_color.to_array())
+                .image_background_shape(ImageBackgroundShape::RoundedSquare);
+        }
+    }
+}
+
+/// The logo as a `data:` URL, which is how fast_qr embeds images.
+fn logo_data_url(logo: &[u8]) -> String {
+    let mime_type = if logo.starts_with(b"\x89PNG") {
+        "image/png"
+    } else if logo.starts_with(&[0xFF, 0xD8]) {
+        "image/jpeg"
+    } else {
+        "image/svg+xml"
+    };
+
+    return format!("data:{};base64,{}", mime_type, STANDARD.encode(logo));
+}
+
+fn build_qr_code(link: String, options: &QrCodeOptions) -> Option<QRCode> {
+    let ecl = match options.error_correction {
+        QrCodeErrorCorrection::Low => ECL::L,
+        QrCodeErrorCorrection::Medium => ECL::M,
+        QrCodeErrorCorrection::Quartile => ECL::Q,
+        QrCodeErrorCorrection::High => ECL::H,
+    };
+
+    return QRBuilder::new(link)
+        .ecl(ecl)
+        .build()
+        .inspect_err(|error_message| {
+            error!("Error while trying to build QR code: {:?}", error_message)
+        })
+        .ok();
+}
+
+/// PNG image of a QR code of `link`. `None` if `options.size` is 0.
+pub fn generate_qr_code(link: String, options: &QrCodeOptions) -> Option<Vec<u8>> {
+    if options.size == 0 {
+        error!("QR code images need a size larger than 0");
+        return None;
+    }
+
+    let qrcode = build_qr_code(link, options)?;
+
+    let mut builder = ImageBuilder::default();
+    options.apply(&mut builder);
+
+    return builder
+        .fit_width(options.size)
+        .to_bytes(&qrcode)
+        .inspect_err(|error_message| {
+            error!(
+                "Error while trying to generate QR code: {:?}",
+                error_message
+            )
+        })
+        .ok();
+}
+
+/// SVG document of a QR code of `link`.
+pub fn generate_qr_code_svg(link: String, options: &QrCodeOptions) -> Option<String> {
+    let qrcode = build_qr_code(link, options)?;
+
+    let mut builder = SvgBuilder::default();
+    options.apply(&mut builder);
+
+    return Some(builder.to_str(&qrcode));
+}
@@ -0,0 +1,250 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use local_ip_address::list_afinet_netifas;
+use log::info;
+use protocol::discovery::TcpConnectionInfo;
+use sha2::{Digest, Sha256};
+#[cfg(unix)]
+use std::ffi::CString;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use url::Url;
+
+use crate::PROTOCOL_VERSION;
+
+const SHARE_LINK_HOST: &str = "s.intershare.app";
+
+/// Version of the link format, the first path segment of a link.
+/// Links without one are the legacy `?i=&ip=&p=&d=` format.
+const SHARE_LINK_FORMAT_VERSION: &str = "2";
+
+/// Protocol version assumed for legacy links, which don't carry one.
+const LEGACY_PROTOCOL_VERSION: u32 = 0;
+
+#[derive(Debug, PartialEq)]
+pub enum ShareLinkError {
+    /// Not a share link, or a malformed one.
+    Invalid,
+    /// A link format newer than this version of the SDK knows.
+    UnsupportedFormat,
+}
+
+/// The contents of a convenience share link.
+///
+/// https://s.intershare.app/2?i=hgf8o47fdsb394mv385&a=192.168.12.13:5200&a=[fd00::12]:5200&d=9A403351-A926-4D1C-855F-432A6ED51E0E&v=0&k=7Xq2bVd0n4Vn3kJ…
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShareLink {
+    pub share_id: String,
+    /// Addresses of the sharing device, in the order they should be tried.
+    pub addresses: Vec<SocketAddr>,
+    pub device_id: Option<String>,
+    pub protocol_version: u32,
+    /// Fingerprint of the sharing device's identity key. `None` for legacy links.
+    pub key_fingerprint: Option<String>,
+}
+
+/// base64url encoded SHA-256 hash of an identity public key.
+pub fn key_fingerprint(public_key: &[u8; 32]) -> String {
+    return URL_SAFE_NO_PAD.encode(Sha256::digest(public_key));
+}
+
+impl ShareLink {
+    pub fn new(
+        share_id: String,
+        addresses: Vec<SocketAddr>,
+        device_id: String,
+        public_key: &[u8; 32],
+    ) -> Self {
+        return Self {
+            share_id,
+            addresses,
+            device_id: Some(device_id),
+            protocol_version: PROTOCOL_VERSION,
+            key_fingerprint: Some(key_fingerprint(public_key)),
+        };
+    }
+
+    pub fn parse(link: &str) -> Result<Self, ShareLinkError> {
+        let url = Url::parse(link).map_err(|_| ShareLinkError::Invalid)?;
+
+        if url.host_str() != Some(SHARE_LINK_HOST) {
+            return Err(ShareLinkError::Invalid);
+        }
+
+        return match url.path().trim_matches('/') {
+            "" => Self::parse_legacy(&url),
+            SHARE_LINK_FORMAT_VERSION => Self::parse_versioned(&url),
+            version if version.parse::<u32>().is_ok() => Err(ShareLinkError::UnsupportedFormat),
+            _ => Err(ShareLinkError::Invalid),
+        };
+    }
+
+    fn parse_legacy(url: &Url) -> Result<Self, ShareLinkError> {
+        let ip = query_value(url, "ip")
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .ok_or(ShareLinkError::Invalid)?;
+
+        let port = query_value(url, "p")
+            .and_then(|port| port.parse::<u16>().ok())
+            .ok_or(ShareLinkError::Invalid)?;
+
+        return Ok(Self {
+            share_id: query_value(url, "i").ok_or(ShareLinkError::Invalid)?,
+            addresses: vec![SocketAddr::new(ip, port)],
+            device_id: query_value(url, "d"),
+            protocol_version: LEGACY_PROTOCOL_VERSION,
+            key_fingerprint: None,
+        });
+    }
+
+    fn parse_versioned(url: &Url) -> Result<Self, ShareLinkError> {
+        let addresses = url
+            .query_pairs()
+            .filter(|(key, _)| key == "a")
+            .map(|(_, address)| address.parse::<SocketAddr>())
+            .collect::<Result<Vec<SocketAddr>, _>>()
+            .map_err(|_| ShareLinkError::Invalid)?;
+
+        if addresses.is_empty() {
+            return Err(ShareLinkError::Invalid);
+        }
+
+        let protocol_version = query_value(url, "v")
+            .and_then(|version| version.parse::<u32>().ok())
+            .ok_or(ShareLinkError::Invalid)?;
+
+        return Ok(Self {
+            share_id: query_value(url, "i").ok_or(ShareLinkError::Invalid)?,
+            addresses,
+            device_id: query_value(url, "d"),
+            protocol_version,
+            key_fingerprint: Some(query_value(url, "k").ok_or(ShareLinkError::Invalid)?),
+        });
+    }
+
+    pub fn to_url(&self) -> String {
+        let mut url = Url::parse(&format!(
+            "https://{}/{}",
+            SHARE_LINK_HOST, SHARE_LINK_FORMAT_VERSION
+        ))
+        .unwrap();
+
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("i", &self.share_id);
+
+            for address in &self.addresses {
+                query.append_pair("a", &address.to_string());
+            }
+
+            if let Some(device_id) = &self.device_id {
+                query.append_pair("d", device_id);
+            }
+
+            query.append_pair("v", &self.protocol_version.to_string());
+
+            if let Some(key_fingerprint) = &self.key_fingerprint {
+                query.append_pair("k", key_fingerprint);
+            }
+        }
+
+        return url.to_string();
+    }
+
+    /// Whether `public_key` is the identity key the link was created with.
+    /// Legacy links don't carry a fingerprint, so any key matches.
+    pub fn matches_key(&self, public_key: Option<&[u8; 32]>) -> bool {
+        let Some(key_fingerprint) = &self.key_fingerprint else {
+            return true;
+        };
+
+        return public_key
+            .is_some_and(|public_key| *key_fingerprint == self::key_fingerprint(public_key));
+    }
+}
+
+/// Addresses the TCP server can be reached at. The advertised one comes first,
+/// followed by the IPv4, global IPv6 and link-local IPv6 addresses of the other interfaces.
+pub fn candidate_addresses(tcp_connection_info: &TcpConnectionInfo) -> Vec<SocketAddr> {
+    let interfaces = match list_afinet_netifas() {
+        Ok(interfaces) => interfaces,
+        Err(error) => {
+            info!("Unable to list network interfaces: {:?}", error);
+            Vec::new()
+        }
+    };
+
+    return interface_addresses(tcp_connection_info, &interfaces);
+}
+
+/// Like `candidate_addresses`, for the given pairs of interface name and address.
+/// Link-local addresses get the index of their interface as scope, they are skipped
+/// if the index is unknown.
+pub fn interface_addresses(
+    tcp_connection_info: &TcpConnectionInfo,
+    interfaces: &[(String, IpAddr)],
+) -> Vec<SocketAddr> {
+    let port = tcp_connection_info.port as u16;
+    let mut addresses = Vec::new();
+
+    if let Ok(ip) = tcp_connection_info.hostname.parse::<IpAddr>() {
+        addresses.push(SocketAddr::new(ip, port));
+    }
+
+    let mut ipv4_addresses = Vec::new();
+    let mut global_addresses = Vec::new();
+    let mut link_local_addresses = Vec::new();
+
+    for (interface_name, ip) in interfaces {
+        if ip.is_loopback() || ip.is_unspecified() {
+            continue;
+        }
+
+        match ip {
+            IpAddr::V4(_) => ipv4_addresses.push(SocketAddr::new(*ip, port)),
+            IpAddr::V6(ipv6) if is_link_local(ipv6) => {
+                if let Some(scope_id) = interface_index(interface_name) {
+                    link_local_addresses
+                        .push(SocketAddr::V6(SocketAddrV6::new(*ipv6, port, 0, scope_id)));
+                }
+            }
+            IpAddr::V6(_) => global_addresses.push(SocketAddr::new(*ip, port)),
+        }
+    }
+
+    for address in ipv4_addresses
+        .into_iter()
+        .chain(global_addresses)
+        .chain(link_local_addresses)
+    {
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+
+    return addresses;
+}
+
+/// Whether `ip` is in `fe80::/10`, which is only reachable on the same link.
+fn is_link_local(ip: &Ipv6Addr) -> bool {
+    return ip.segments()[0] & 0xffc0 == 0xfe80;
+}
+
+#[cfg(unix)]
+fn interface_index(interface_name: &str) -> Option<u32> {
+    let interface_name = CString::new(interface_name).ok()?;
+    let index = unsafe { libc::if_nametoindex(interface_name.as_ptr()) };
+
+    return (index != 0).then_some(index);
+}
+
+#[cfg(not(unix))]
+fn interface_index(_interface_name: &str) -> Option<u32> {
+    return None;
+}
+
+fn query_value(url: &Url, key: &str) -> Option<String> {
+    return url
+        .query_pairs()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.to_string())
+        .filter(|value| !value.is_empty());
+}
@@ -0,0 +1,275 @@
+use crate::errors::ConvenienceDownloadError;
+use crate::share_store::ShareStore;
+use log::info;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long requests for an invalidated share are answered with the reason,
+/// afterwards the share is unknown.
+const INVALIDATED_SHARE_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Limits of a share, after which its link stops working.
+#[derive(uniffi::Record, Clone, Debug, Default)]
+pub struct ShareLimits {
+    pub expires_in_seconds: Option<u64>,
+    pub max_downloads: Option<u32>,
+}
+
+impl ShareLimits {
+    /// When a share created now expires. `None` if it doesn't, or the expiry time is too far
+    /// in the future to represent.
+    pub fn expires_at(&self) -> Option<Instant> {
+        return self
+            .expires_in_seconds
+            .and_then(|seconds| Instant::now().checked_add(Duration::from_secs(seconds)));
+    }
+}
+
+#[derive(uniffi::Enum, Clone, Copy, Debug, PartialEq)]
+pub enum ShareInvalidationReason {
+    Revoked,
+    Expired,
+    DownloadLimitReached,
+}
+
+#[uniffi::export(callback_interface)]
+pub trait ShareDelegate: Send + Sync + Debug {
+    /// A share was revoked or reached its expiry time or download limit,
+    /// and its link doesn't work anymore.
+    fn share_invalidated(&self, share_id: String, reason: ShareInvalidationReason);
+}
+
+struct RegisteredShare {
+    share_store: Arc<ShareStore>,
+    expires_at: Option<Instant>,
+    remaining_downloads: Option<u32>,
+    /// Downloads which started but didn't complete yet. They hold one of the remaining ones.
+    running_downloads: u32,
+}
+
+#[derive(Default)]
+struct Shares {
+    active: HashMap<String, RegisteredShare>,
+    /// Why and when shares which are gone were invalidated, to answer requests for them.
+    invalidated: HashMap<String, (ShareInvalidationReason, Instant)>,
+}
+
+impl Shares {
+    fn invalidate(&mut self, share_id: &str, reason: ShareInvalidationReason) -> bool {
+        if self.active.remove(share_id).is_none() {
+            return false;
+        }
+
+        info!("Share {} was invalidated: {:?}", share_id, reason);
+        self.invalidated
+            .insert(share_id.to_string(), (reason, Instant::now()));
+
+        return true;
+    }
+
+    /// Invalidates every share whose expiry time passed. Returns their ids.
+    /// Forgets shares which were invalidated long ago.
+    fn invalidate_expired(&mut self) -> Vec<String> {
+        let now = Instant::now();
+
+        self.invalidated.retain(|_, (_, invalidated_at)| {
+            now.duration_since(*invalidated_at) < INVALIDATED_SHARE_RETENTION
+        });
+
+        let expired_share_ids: Vec<String> = self
+            .active
+            .iter()
+            .filter(|(_, share)| share.expires_at.is_some_and(|expires_at| expires_at <= now))
+            .map(|(share_id, _)| share_id.clone())
+            .collect();
+
+        for share_id in &expired_share_ids {
+            self.invalidate(share_id, ShareInvalidationReason::Expired);
+        }
+
+        return expired_share_ids;
+    }
+}
+
+/// Shares which can be downloaded using their link or QR code, keyed by their `request_id`.
+#[derive(Default)]
+pub struct ShareRegistry {
+    shares: Mutex<Shares>,
+    delegate: Mutex<Option<Box<dyn ShareDelegate>>>,
+}
+
+impl ShareRegistry {
+    pub fn set_delegate(&self, delegate: Box<dyn ShareDelegate>) {
+        *self.delegate.lock().unwrap() = Some(delegate);
+    }
+
+    /// Adds a share, if it allows convenience downloads.
+    pub fn insert(&self, share_store: Arc<ShareStore>, limits: &ShareLimits) {
+        if !share_store.allows_convenience_download() {
+            return;
+        }
+
+        let registered_share = RegisteredShare {
+            expires_at: limits.expires_at(),
+            remaining_downloads: limits.max_downloads,
+            running_downloads: 0,
+            share_store: share_store.clone(),
+        };
+
+        self.shares
+            .lock()
+            .unwrap()
+            .active
+            .insert(share_store.request_id.clone(), registered_share);
+    }
+
+    pub fn get(&self, share_id: &str) -> Option<Arc<ShareStore>> {
+        self.expire_shares();
+
+        return self
+            .shares
+            .lock()
+            .unwrap()
+            .active
+            .get(share_id)
+            .map(|share| share.share_store.clone());
+    }
+
+    pub fn list(&self) -> Vec<Arc<ShareStore>> {
+        self.expire_shares();
+
+        return self
+            .shares
+            .lock()
+            .unwrap()
+            .active
+            .values()
+            .map(|share| share.share_store.clone())
+            .collect();
+    }
+
+    /// Removes a share, its link stops working. Returns whether the share existed.
+    pub fn revoke(&self, share_id: &str) -> bool {
+        let revoked = self
+            .shares
+            .lock()
+            .unwrap()
+            .invalidate(share_id, ShareInvalidationReason::Revoked);
+
+        if revoked {
+            self.notify(share_id.to_string(), ShareInvalidationReason::Revoked);
+        }
+
+        return revoked;
+    }
+
+    /// Invalidates the shares whose expiry time passed, and tells the delegate about them.
+    pub fn expire_shares(&self) {
+        let expired_share_ids = self.shares.lock().unwrap().invalidate_expired();
+
+        for share_id in expired_share_ids {
+            self.notify(share_id, ShareInvalidationReason::Expired);
+        }
+    }
+
+    fn notify(&self, share_id: String, reason: ShareInvalidationReason) {
+        if let Some(delegate) = &*self.delegate.lock().unwrap() {
+            delegate.share_invalidated(share_id, reason);
+        }
+    }
+
+    /// The share a request asks for, without counting it as a download.
+    pub fn find(
+        &self,
+        share_id: Option<&str>,
+    ) -> Result<Arc<ShareStore>, ConvenienceDownloadError> {
+        self.expire_shares();
+
+        let Some(share_id) = share_id else {
+            return Err(ConvenienceDownloadError::UnknownShare);
+        };
+
+        let shares = self.shares.lock().unwrap();
+
+        if let Some(share) = shares.active.get(share_id) {
+            return Ok(share.share_store.clone());
+        }
+
+        return match shares.invalidated.get(share_id).map(|(reason, _)| reason) {
+            Some(ShareInvalidationReason::DownloadLimitReached) => {
+                Err(ConvenienceDownloadError::DownloadLimitReached)
+            }
+            Some(_) => Err(ConvenienceDownloadError::Expired),
+            None => Err(ConvenienceDownloadError::UnknownShare),
+        };
+    }
+
+    /// The share a download request asks for. The download holds one of the remaining
+    /// downloads until `finish_download()`, which counts it if it completed.
+    pub fn start_download(
+        &self,
+        share_id: Option<&str>,
+    ) -> Result<Arc<ShareStore>, ConvenienceDownloadError> {
+        let share_store = self.find(share_id)?;
+        let share_id = share_store.request_id.clone();
+
+        let mut shares = self.shares.lock().unwrap();
+
+        let Some(share) = shares.active.get_mut(&share_id) else {
+            return Err(ConvenienceDownloadError::Expired);
+        };
+
+        let Some(remaining_downloads) = share.remaining_downloads else {
+            return Ok(share_store);
+        };
+
+        // A share created with no allowed downloads can't be downloaded at all.
+        if remaining_downloads == 0 {
+            shares.invalidate(&share_id, ShareInvalidationReason::DownloadLimitReached);
+            drop(shares);
+            self.notify(share_id, ShareInvalidationReason::DownloadLimitReached);
+            return Err(ConvenienceDownloadError::DownloadLimitReached);
+        }
+
+        if share.running_downloads >= remaining_downloads {
+            return Err(ConvenienceDownloadError::DownloadLimitReached);
+        }
+
+        share.running_downloads += 1;
+
+        return Ok(share_store);
+    }
+
+    /// Ends a download of `start_download()`. A completed download counts towards the limit,
+    /// and invalidates the share if it was the last one allowed.
+    pub fn finish_download(&self, share_id: &str, completed: bool) {
+        let mut shares = self.shares.lock().unwrap();
+
+        let Some(share) = shares.active.get_mut(share_id) else {
+            return;
+        };
+
+        let Some(remaining_downloads) = &mut share.remaining_downloads else {
+            return;
+        };
+
+        share.running_downloads = share.running_downloads.saturating_sub(1);
+
+        if !completed {
+            return;
+        }
+
+        *remaining_downloads = remaining_downloads.saturating_sub(1);
+
+        if *remaining_downloads == 0 {
+            shares.invalidate(share_id, ShareInvalidationReason::DownloadLimitReached);
+            drop(shares);
+            self.notify(
+                share_id.to_string(),
+                ShareInvalidationReason::DownloadLimitReached,
+            );
+        }
+    }
+}
@@ -1,23 +1,41 @@
+use crate::communication::{
+    EncryptedConnection, CAPABILITY_CANCEL, CAPABILITY_FILE_HASHES, CAPABILITY_TEXT_RESPONSE,
+    CAPABILITY_TRANSFER_RESULT, CAPABILITY_ZSTD,
+};
+use crate::discovery::DiscoveryContext;
+use crate::encryption::EncryptedReadWrite;
+use crate::errors::peer_cancel_reason;
+use crate::identity::{DeviceTrust, IdentityStore};
 use crate::nearby_server::L2CapDelegate;
-use crate::tar::stream_tar;
+use crate::qr_code::{generate_qr_code, generate_qr_code_svg, QrCodeOptions};
+use crate::share_link::{candidate_addresses, ShareLink};
+use crate::tar::{build_file_manifest, stream_tar, ArchiveEntry, ArchiveFile, ArchiveOptions};
 use crate::{
     connection::Connection, convert_os_str, encryption::generate_secure_base64_token,
     errors::ConnectErrors,
 };
-use fast_qr::convert::{image::ImageBuilder, Builder, Shape};
-use fast_qr::qr::QRBuilder;
 use log::{error, info};
 use prost_stream::Stream;
 use protocol::{
     communication::{
         request::{Intent, RequestTypes},
-        ClipboardTransferIntent, FileTransferIntent, Request, TransferRequestResponse,
+        transfer_result::Outcome,
+        CancelReason, ClipboardTransferIntent, DeclineReason, FileTransferIntent, Request,
+        ResumeOffset, TransferRequestResponse, TransferResult,
     },
     discovery::{Device, DeviceConnectionInfo},
 };
-use std::{fmt::Debug, fs::File, path::Path, sync::Arc};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use std::{fmt::Debug, path::Path, sync::Arc};
 use tokio::sync::RwLock;
 
+/// How often the user may cancel while the sender waits for the receiver to answer.
+const RESPONSE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
 pub enum ConnectionMedium {
     BLE,
     WiFi,
@@ -28,8 +46,11 @@ pub enum SendProgressState {
     Connecting,
     Requesting,
     ConnectionMediumUpdate { medium: ConnectionMedium },
+    VerificationCode { code: String },
+    Unverified,
     Transferring { progress: f64 },
     Cancelled,
+    CancelledByPeer { reason: CancelReason },
     Finished,
     Declined,
 }
@@ -38,6 +59,13 @@ pub trait SendProgressDelegate: Send + Sync + Debug {
     fn progress_changed(&self, progress: SendProgressState);
 }
 
+/// Cancel flag of a `send_to` or a convenience download which is running.
+struct RunningTransfer {
+    /// `None` for convenience downloads.
+    receiver_id: Option<String>,
+    cancelled: Arc<AtomicBool>,
+}
+
 pub struct ShareStore {
     pub request_id: String,
     pub file_paths: Option<Vec<String>>,
@@ -45,6 +73,13 @@ pub struct ShareStore {
     allow_convenience_share: bool,
     ble_l2_cap_client: Arc<RwLock<Option<Box<dyn L2CapDelegate>>>>,
     device_connection_info: DeviceConnectionInfo,
+    identity: Arc<IdentityStore>,
+    discovery_context: Arc<DiscoveryContext>,
+    /// Verification codes of the open connections and whether the user rejected them.
+    verifications: Mutex<HashMap<String, bool>>,
+    running_transfers: Mutex<Vec<RunningTransfer>>,
+    transfer_id: String,
+    interrupted_receiver: RwLock<Option<Device>>,
 }
 
 pub(crate) fn update_progress(
@@ -56,6 +91,107 @@ pub(crate) fn update_progress(
     }
 }
 
+/// Whether `error` means the connection to the receiver is gone, rather than a local failure.
+fn is_connection_error(error: &io::Error) -> bool {
+    return matches!(
+        error.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::WriteZero
+    );
+}
+
+/// Tells the receiver why the transfer stops, if it understands cancel frames.
+fn cancel_transfer(
+    stream: &mut Box<dyn EncryptedReadWrite>,
+    capabilities: u64,
+    reason: CancelReason,
+) {
+    if capabilities & CAPABILITY_CANCEL != 0 {
+        let _ = stream.cancel(reason);
+    }
+
+    stream.close();
+}
+
+/// Reads the cancel frame the receiver sent before closing the connection, if there is one.
+pub(crate) fn read_peer_cancel(stream: &mut Box<dyn EncryptedReadWrite>) -> Option<CancelReason> {
+    let mut buffer = [0u8; 1];
+
+    return stream
+        .read(&mut buffer)
+        .err()
+        .and_then(|error| peer_cancel_reason(&error));
+}
+
+/// Waits until the receiver confirms that it stored everything it received.
+/// Receivers without `CAPABILITY_TRANSFER_RESULT` don't confirm anything.
+fn receive_transfer_result(
+    stream: &mut Box<dyn EncryptedReadWrite>,
+    capabilities: u64,
+) -> Result<(), ConnectErrors> {
+    if capabilities & CAPABILITY_TRANSFER_RESULT == 0 {
+        return Ok(());
+    }
+
+    let transfer_result = match Stream::new(&mut *stream).recv::<TransferResult>() {
+        Ok(transfer_result) => transfer_result,
+        Err(error) => {
+            if let Some(reason) = read_peer_cancel(stream) {
+                return Err(ConnectErrors::CancelledByPeer { reason });
+            }
+
+            return Err(ConnectErrors::TransferInterrupted {
+                error: error.to_string(),
+            });
+        }
+    };
+
+    return match transfer_result.outcome {
+        Some(Outcome::Complete(_)) => Ok(()),
+        Some(Outcome::Failed(transfer_failed)) => Err(ConnectErrors::TransferFailed {
+            error: transfer_failed.error,
+        }),
+        None => Err(ConnectErrors::TransferFailed {
+            error: "Missing transfer result".to_string(),
+        }),
+    };
+}
+
+/// The error for a declined request. The receiver declines with `VerificationRejected`
+/// if the verification codes shown on both devices don't match.
+fn declined_error(response: &TransferRequestResponse) -> ConnectErrors {
+    return match response.decline_reason() {
+        DeclineReason::VerificationRejected => ConnectErrors::VerificationRejected,
+        reason => ConnectErrors::Declined { reason },
+    };
+}
+
+/// Files the receiver selected, starting at the offsets it already received.
+/// An empty selection means all files.
+fn select_archive_files<'a>(
+    archive_files: &'a [ArchiveFile],
+    selected_entries: &[u32],
+    resume_offsets: &[ResumeOffset],
+) -> Vec<ArchiveEntry<'a>> {
+    return archive_files
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| {
+            selected_entries.is_empty() || selected_entries.contains(&(*index as u32))
+        })
+        .map(|(index, archive_file)| ArchiveEntry {
+            file: archive_file,
+            offset: resume_offsets
+                .iter()
+                .find(|resume_offset| resume_offset.entry as usize == index)
+                .map(|resume_offset| resume_offset.offset.min(archive_file.manifest_entry.size))
+                .unwrap_or(0),
+        })
+        .collect();
+}
+
 impl ShareStore {
     #[uniffi::constructor]
     pub fn new(
@@ -64,6 +200,8 @@ impl ShareStore {
         allow_convenience_share: bool,
         ble_l2_cap_client: Arc<RwLock<Option<Box<dyn L2CapDelegate>>>>,
         device_connection_info: DeviceConnectionInfo,
+        identity: Arc<IdentityStore>,
+        discovery_context: Arc<DiscoveryContext>,
     ) -> Self {
         Self {
             request_id: generate_secure_base64_token(23),
@@ -72,6 +210,12 @@ impl ShareStore {
             allow_convenience_share,
             ble_l2_cap_client,
             device_connection_info,
+            identity,
+            discovery_context,
+            verifications: Mutex::new(HashMap::new()),
+            running_transfers: Mutex::new(Vec::new()),
+            transfer_id: generate_secure_base64_token(16),
+            interrupted_receiver: RwLock::new(None),
         }
     }
 
@@ -80,78 +224,354 @@ impl ShareStore {
         receiver: Device,
         progress_delegate: Option<Box<dyn SendProgressDelegate>>,
     ) -> Result<(), ConnectErrors> {
-        return if self.file_paths.is_none() {
-            self.send_text(receiver, progress_delegate).await
+        let cancelled = self.start_transfer(Some(receiver.id.clone()));
+        let result = self
+            .connect_and_send(&receiver, &cancelled, &progress_delegate)
+            .await;
+        self.finish_transfer(&cancelled);
+
+        // Only file transfers can be resumed.
+        *self.interrupted_receiver.write().await = match &result {
+            Err(
+                ConnectErrors::TransferInterrupted { .. } | ConnectErrors::TransferFailed { .. },
+            ) if self.file_paths.is_some() => Some(receiver),
+            _ => None,
+        };
+
+        return result;
+    }
+
+    /// Registers the cancel flag of a new transfer, see `cancel()`.
+    fn start_transfer(&self, receiver_id: Option<String>) -> Arc<AtomicBool> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        self.running_transfers
+            .lock()
+            .unwrap()
+            .push(RunningTransfer {
+                receiver_id,
+                cancelled: cancelled.clone(),
+            });
+
+        return cancelled;
+    }
+
+    fn finish_transfer(&self, cancelled: &Arc<AtomicBool>) {
+        self.running_transfers
+            .lock()
+            .unwrap()
+            .retain(|transfer| !Arc::ptr_eq(&transfer.cancelled, cancelled));
+    }
+
+    async fn connect_and_send(
+        &self,
+        receiver: &Device,
+        cancelled: &AtomicBool,
+        progress_delegate: &Option<Box<dyn SendProgressDelegate>>,
+    ) -> Result<(), ConnectErrors> {
+        if self.file_paths.is_none() && self.clipboard.is_none() {
+            return Err(ConnectErrors::NoTextProvided);
+        }
+
+        if cancelled.load(Ordering::Relaxed) {
+            update_progress(progress_delegate, SendProgressState::Cancelled);
+            return Err(ConnectErrors::Cancelled);
+        }
+
+        update_progress(progress_delegate, SendProgressState::Connecting);
+
+        let connection = Connection::new(
+            self.ble_l2_cap_client.clone(),
+            self.identity.clone(),
+            self.discovery_context.clone(),
+        );
+
+        let encrypted_connection = connection
+            .connect(receiver.clone(), progress_delegate)
+            .await
+            .inspect_err(|_| update_progress(progress_delegate, SendProgressState::Unknown))?;
+
+        let peer_identity_key = encrypted_connection.peer_identity_key;
+
+        let result = self
+            .send_over(encrypted_connection, None, cancelled, progress_delegate)
+            .await;
+
+        // Trust on first use, the receiver pins the key of the sender once it accepts.
+        if let (Ok(_), Some(peer_identity_key)) = (&result, &peer_identity_key) {
+            if self
+                .identity
+                .get_trust(&receiver.id, Some(peer_identity_key))
+                == DeviceTrust::New
+            {
+                self.identity.pin(&receiver.id, peer_identity_key);
+            }
+        }
+
+        return result;
+    }
+
+    /// Sends the share to a device which requested it using the convenience link.
+    pub(crate) async fn serve_download(
+        &self,
+        encrypted_connection: EncryptedConnection,
+    ) -> Result<(), ConnectErrors> {
+        info!("Serving convenience download of share {}", self.request_id);
+
+        let cancelled = self.start_transfer(None);
+        let result = self
+            .send_over(
+                encrypted_connection,
+                Some(self.request_id.clone()),
+                &cancelled,
+                &None,
+            )
+            .await;
+        self.finish_transfer(&cancelled);
+
+        return result;
+    }
+
+    /// Whether the share can be downloaded using its link.
+    pub(crate) fn allows_convenience_download(&self) -> bool {
+        return self.allow_convenience_share;
+    }
+
+    async fn send_over(
+        &self,
+        encrypted_connection: EncryptedConnection,
+        share_id: Option<String>,
+        cancelled: &AtomicBool,
+        progress_delegate: &Option<Box<dyn SendProgressDelegate>>,
+    ) -> Result<(), ConnectErrors> {
+        let verification_code = encrypted_connection.verification_code.clone();
+
+        if let Some(verification_code) = &verification_code {
+            self.verifications
+                .lock()
+                .unwrap()
+                .insert(verification_code.clone(), false);
+        }
+
+        let result = if self.file_paths.is_none() {
+            self.send_text(encrypted_connection, share_id, cancelled, progress_delegate)
+                .await
         } else {
-            self.send_files(receiver, progress_delegate).await
+            self.send_files(encrypted_connection, share_id, cancelled, progress_delegate)
+                .await
         };
+
+        if let Some(verification_code) = &verification_code {
+            self.verifications.lock().unwrap().remove(verification_code);
+        }
+
+        return result;
     }
 
-    async fn send_text(
+    /// Cancels the transfer if the user rejected the verification code of its connection.
+    fn check_verification(
+        &self,
+        stream: &mut Box<dyn EncryptedReadWrite>,
+        capabilities: u64,
+        verification_code: &Option<String>,
+        progress_delegate: &Option<Box<dyn SendProgressDelegate>>,
+    ) -> Result<(), ConnectErrors> {
+        let rejected = verification_code.as_ref().is_some_and(|verification_code| {
+            self.verifications.lock().unwrap().get(verification_code) == Some(&true)
+        });
+
+        if !rejected {
+            return Ok(());
+        }
+
+        info!("Verification code was rejected, aborting transfer.");
+        cancel_transfer(stream, capabilities, CancelReason::VerificationCodeRejected);
+        update_progress(progress_delegate, SendProgressState::Cancelled);
+        return Err(ConnectErrors::VerificationRejected);
+    }
+
+    /// Closes the connection if the user cancelled the transfer before the receiver accepted it.
+    fn check_cancelled(
+        &self,
+        stream: &mut Box<dyn EncryptedReadWrite>,
+        capabilities: u64,
+        cancelled: &AtomicBool,
+        progress_delegate: &Option<Box<dyn SendProgressDelegate>>,
+    ) -> Result<(), ConnectErrors> {
+        if !cancelled.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        info!("Transfer was cancelled before the receiver accepted it.");
+        cancel_transfer(stream, capabilities, CancelReason::UserCancelled);
+        update_progress(progress_delegate, SendProgressState::Cancelled);
+        return Err(ConnectErrors::Cancelled);
+    }
+
+    /// Waits for the receiver to answer the request, which can take until its user decides.
+    /// Stops waiting if the user cancels or rejects the verification code in the meantime.
+    fn receive_response(
+        &self,
+        stream: &mut Box<dyn EncryptedReadWrite>,
+        capabilities: u64,
+        verification_code: &Option<String>,
+        cancelled: &AtomicBool,
+        progress_delegate: &Option<Box<dyn SendProgressDelegate>>,
+    ) -> Result<TransferRequestResponse, ConnectErrors> {
+        while !stream.wait_readable(RESPONSE_POLL_INTERVAL).unwrap_or(true) {
+            self.check_verification(stream, capabilities, verification_code, progress_delegate)?;
+            self.check_cancelled(stream, capabilities, cancelled, progress_delegate)?;
+        }
+
+        return Stream::new(&mut *stream)
+            .recv::<TransferRequestResponse>()
+            .map_err(|error| ConnectErrors::FailedToGetTransferRequestResponse {
+                error: error.to_string(),
+            });
+    }
+
+    /// Sends the remaining data of an interrupted file transfer to the same receiver again.
+    pub async fn resume(
         &self,
-        receiver: Device,
         progress_delegate: Option<Box<dyn SendProgressDelegate>>,
     ) -> Result<(), ConnectErrors> {
-        let Some(text) = &self.clipboard else {
-            return Err(ConnectErrors::NoTextProvided);
+        let Some(receiver) = self.interrupted_receiver.read().await.clone() else {
+            return Err(ConnectErrors::NoTransferToResume);
         };
 
-        update_progress(&progress_delegate, SendProgressState::Connecting);
+        info!("Resuming transfer {}", self.transfer_id);
 
-        let connection = Connection::new(self.ble_l2_cap_client.clone());
+        return self.send_to(receiver, progress_delegate).await;
+    }
 
-        let mut encrypted_stream = connection
-            .connect(receiver, &progress_delegate)
-            .await
-            .inspect_err(|_| update_progress(&progress_delegate, SendProgressState::Unknown))?;
+    async fn send_text(
+        &self,
+        encrypted_connection: EncryptedConnection,
+        share_id: Option<String>,
+        cancelled: &AtomicBool,
+        progress_delegate: &Option<Box<dyn SendProgressDelegate>>,
+    ) -> Result<(), ConnectErrors> {
+        let Some(text) = &self.clipboard else {
+            return Err(ConnectErrors::NoTextProvided);
+        };
 
-        let mut proto_stream = Stream::new(&mut encrypted_stream);
+        let capabilities = encrypted_connection.capabilities;
+        let verification_code = encrypted_connection.verification_code;
+        let mut encrypted_stream = encrypted_connection.stream;
+        let text_response = capabilities & CAPABILITY_TEXT_RESPONSE != 0;
+
+        // Older receivers expect the text inside the request.
+        if !text_response {
+            self.check_verification(
+                &mut encrypted_stream,
+                capabilities,
+                &verification_code,
+                progress_delegate,
+            )?;
+            self.check_cancelled(
+                &mut encrypted_stream,
+                capabilities,
+                cancelled,
+                progress_delegate,
+            )?;
+        }
 
-        update_progress(
-            &progress_delegate,
-            SendProgressState::Transferring { progress: 0.0 },
-        );
+        update_progress(progress_delegate, SendProgressState::Requesting);
+
+        let clipboard_content = if text_response {
+            String::new()
+        } else {
+            text.to_string()
+        };
 
         let transfer_request = Request {
             r#type: RequestTypes::ShareRequest as i32,
             device: self.device_connection_info.device.clone(),
-            share_id: None,
+            share_id,
             intent: Some(Intent::Clipboard(ClipboardTransferIntent {
-                clipboard_content: text.to_string(),
+                clipboard_content,
             })),
         };
 
-        update_progress(
-            &progress_delegate,
-            SendProgressState::Transferring { progress: 0.8 },
-        );
-        let _ = proto_stream.send(&transfer_request);
-        update_progress(&progress_delegate, SendProgressState::Finished);
+        let _ = Stream::new(&mut encrypted_stream).send(&transfer_request);
+
+        if text_response {
+            let response = self.receive_response(
+                &mut encrypted_stream,
+                capabilities,
+                &verification_code,
+                cancelled,
+                progress_delegate,
+            )?;
+
+            if !response.accepted {
+                update_progress(progress_delegate, SendProgressState::Declined);
+                return Err(declined_error(&response));
+            }
+
+            // The text is sent only once the codes were not rejected on either device.
+            self.check_verification(
+                &mut encrypted_stream,
+                capabilities,
+                &verification_code,
+                progress_delegate,
+            )?;
+            self.check_cancelled(
+                &mut encrypted_stream,
+                capabilities,
+                cancelled,
+                progress_delegate,
+            )?;
+
+            let _ = Stream::new(&mut encrypted_stream).send(&ClipboardTransferIntent {
+                clipboard_content: text.to_string(),
+            });
+        }
+
+        let _ = encrypted_stream.finish();
+
+        if let Err(error) = receive_transfer_result(&mut encrypted_stream, capabilities) {
+            error!("Receiver did not confirm the text: {}", error);
+
+            let state = match error {
+                ConnectErrors::CancelledByPeer { reason } => {
+                    SendProgressState::CancelledByPeer { reason }
+                }
+                _ => SendProgressState::Unknown,
+            };
+            update_progress(progress_delegate, state);
+
+            return Err(error);
+        }
+
+        update_progress(progress_delegate, SendProgressState::Finished);
 
         return Ok(());
     }
 
     async fn send_files(
         &self,
-        receiver: Device,
-        progress_delegate: Option<Box<dyn SendProgressDelegate>>,
+        encrypted_connection: EncryptedConnection,
+        share_id: Option<String>,
+        cancelled: &AtomicBool,
+        progress_delegate: &Option<Box<dyn SendProgressDelegate>>,
     ) -> Result<(), ConnectErrors> {
         let Some(file_paths) = &self.file_paths else {
             return Err(ConnectErrors::NoFilesProvided);
         };
 
-        update_progress(&progress_delegate, SendProgressState::Connecting);
-
-        let connection = Connection::new(self.ble_l2_cap_client.clone());
-
-        let mut encrypted_stream = connection
-            .connect(receiver, &progress_delegate)
-            .await
-            .inspect_err(|_| update_progress(&progress_delegate, SendProgressState::Unknown))?;
+        let capabilities = encrypted_connection.capabilities;
+        let verification_code = encrypted_connection.verification_code;
+        let mut encrypted_stream = encrypted_connection.stream;
 
-        let mut proto_stream = Stream::new(&mut encrypted_stream);
+        self.check_verification(
+            &mut encrypted_stream,
+            capabilities,
+            &verification_code,
+            progress_delegate,
+        )?;
 
-        update_progress(&progress_delegate, SendProgressState::Requesting);
+        update_progress(progress_delegate, SendProgressState::Requesting);
 
         let file_name = file_paths.first().map(|file_path| {
             convert_os_str(
@@ -161,112 +581,213 @@ impl ShareStore {
             )
         });
 
-        let mut file_size: u64 = 0;
-
-        for file_path in file_paths {
-            let metadata = File::open(file_path)
-                .expect("Failed to read file")
-                .metadata()
-                .expect("Failed to get file metadata");
+        let archive_files = build_file_manifest(file_paths).map_err(|error| {
+            update_progress(progress_delegate, SendProgressState::Unknown);
+            ConnectErrors::FailedToDetermineFileSize {
+                error: error.to_string(),
+            }
+        })?;
 
-            file_size = file_size + metadata.len();
-        }
+        let file_size: u64 = archive_files
+            .iter()
+            .map(|archive_file| archive_file.manifest_entry.size)
+            .sum();
 
         info!("Total size of files: {}", file_size);
 
         let transfer_request = Request {
             r#type: RequestTypes::ShareRequest as i32,
             device: self.device_connection_info.device.clone(),
-            share_id: None,
+            share_id,
             intent: Some(Intent::FileTransfer(FileTransferIntent {
                 file_name,
                 file_size,
                 file_count: file_paths.len() as u64,
+                manifest: archive_files
+                    .iter()
+                    .map(|archive_file| archive_file.manifest_entry.clone())
+                    .collect(),
+                transfer_id: Some(self.transfer_id.clone()),
             })),
         };
 
-        let _ = proto_stream.send(&transfer_request);
+        // Listing the files can take a while, the user may have cancelled in the meantime.
+        self.check_cancelled(
+            &mut encrypted_stream,
+            capabilities,
+            cancelled,
+            progress_delegate,
+        )?;
 
-        let response = proto_stream
-            .recv::<TransferRequestResponse>()
-            .map_err(|error| ConnectErrors::FailedToGetTransferRequestResponse {
-                error: error.to_string(),
-            })?;
+        let _ = Stream::new(&mut encrypted_stream).send(&transfer_request);
+
+        let response = self.receive_response(
+            &mut encrypted_stream,
+            capabilities,
+            &verification_code,
+            cancelled,
+            progress_delegate,
+        )?;
 
         if !response.accepted {
-            update_progress(&progress_delegate, SendProgressState::Declined);
-            return Err(ConnectErrors::Declined);
+            info!("Receiver declined: {:?}", response.decline_reason());
+            update_progress(progress_delegate, SendProgressState::Declined);
+            return Err(declined_error(&response));
         }
 
+        self.check_verification(
+            &mut encrypted_stream,
+            capabilities,
+            &verification_code,
+            progress_delegate,
+        )?;
+
+        let selected_files = select_archive_files(
+            &archive_files,
+            &response.selected_entries,
+            &response.resume_offsets,
+        );
+        let selected_size: u64 = selected_files
+            .iter()
+            .map(|entry| entry.file.manifest_entry.size - entry.offset)
+            .sum();
+
+        info!(
+            "Receiver selected {} of {} files",
+            selected_files.len(),
+            archive_files.len()
+        );
+
+        let archive_options = ArchiveOptions {
+            file_hashes: capabilities & CAPABILITY_FILE_HASHES != 0,
+            compression: capabilities & CAPABILITY_ZSTD != 0,
+        };
+
         update_progress(
-            &progress_delegate,
+            progress_delegate,
             SendProgressState::Transferring { progress: 0.0 },
         );
 
         let tar_result = stream_tar(
             &mut encrypted_stream,
-            file_paths,
-            file_size,
-            &progress_delegate,
+            &selected_files,
+            selected_size,
+            progress_delegate,
+            cancelled,
+            &archive_options,
         );
 
-        if let Err(error) = tar_result {
+        if let Err(error) = tar_result.and_then(|_| encrypted_stream.finish()) {
             error!("Error while tarring: {}", error);
-            update_progress(&progress_delegate, SendProgressState::Cancelled);
+
+            if cancelled.load(Ordering::Relaxed) {
+                cancel_transfer(
+                    &mut encrypted_stream,
+                    capabilities,
+                    CancelReason::UserCancelled,
+                );
+                update_progress(progress_delegate, SendProgressState::Cancelled);
+                return Err(ConnectErrors::Cancelled);
+            }
+
+            if !is_connection_error(&error) {
+                cancel_transfer(&mut encrypted_stream, capabilities, CancelReason::Error);
+            } else if let Some(reason) = read_peer_cancel(&mut encrypted_stream) {
+                info!("Receiver cancelled the transfer: {:?}", reason);
+                update_progress(
+                    progress_delegate,
+                    SendProgressState::CancelledByPeer { reason },
+                );
+                return Err(ConnectErrors::CancelledByPeer { reason });
+            }
+
+            update_progress(progress_delegate, SendProgressState::Cancelled);
+            return Err(ConnectErrors::TransferInterrupted {
+                error: error.to_string(),
+            });
         }
 
-        update_progress(&progress_delegate, SendProgressState::Finished);
+        if let Err(error) = receive_transfer_result(&mut encrypted_stream, capabilities) {
+            error!("Receiver did not confirm the transfer: {}", error);
+
+            let state = match error {
+                ConnectErrors::CancelledByPeer { reason } => {
+                    SendProgressState::CancelledByPeer { reason }
+                }
+                _ => SendProgressState::Unknown,
+            };
+            update_progress(progress_delegate, state);
+
+            return Err(error);
+        }
+
+        update_progress(progress_delegate, SendProgressState::Finished);
 
         return Ok(());
     }
 
-    /// https://share.intershare.app?id=hgf8o47fdsb394mv385&ip=192.168.12.13&port=5200&device_id=9A403351-A926-4D1C-855F-432A6ED51E0E&protocol_version=1
-    pub fn generate_link(&self) -> Option<String> {
-        if !self.allow_convenience_share {
-            return None;
+    /// Aborts the transfer over the connection which shows `verification_code` before any
+    /// more data is sent, because the codes shown on both devices don't match.
+    pub fn reject_verification_code(&self, verification_code: String) {
+        let mut verifications = self.verifications.lock().unwrap();
+
+        if let Some(rejected) = verifications.get_mut(&verification_code) {
+            *rejected = true;
         }
+    }
 
-        let Some(device) = self.device_connection_info.device.clone() else {
-            return None;
-        };
+    /// Stops every running transfer of this share at its next step and lets the receivers
+    /// know it was cancelled. Transfers started afterwards aren't affected.
+    pub fn cancel(&self) {
+        for transfer in self.running_transfers.lock().unwrap().iter() {
+            transfer.cancelled.store(true, Ordering::Relaxed);
+        }
+    }
 
-        let Some(tcp_connection_info) = self.device_connection_info.tcp.clone() else {
+    /// Like `cancel()`, but only stops the transfer to the device with id `receiver_id`.
+    pub fn cancel_send_to(&self, receiver_id: String) {
+        for transfer in self.running_transfers.lock().unwrap().iter() {
+            if transfer.receiver_id.as_ref() == Some(&receiver_id) {
+                transfer.cancelled.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// A versioned link, see `ShareLink`.
+    pub fn generate_link(&self) -> Option<String> {
+        if !self.allow_convenience_share {
             return None;
-        };
+        }
 
-        let ip = tcp_connection_info.hostname;
-        let port = tcp_connection_info.port;
+        let device = self.device_connection_info.device.clone()?;
+        let tcp_connection_info = self.device_connection_info.tcp.clone()?;
 
-        let link = format!(
-            "https://s.intershare.app?i={0}&ip={1}&p={2}&d={3}",
-            self.request_id, ip, port, device.id
+        let link = ShareLink::new(
+            self.request_id.clone(),
+            candidate_addresses(&tcp_connection_info),
+            device.id,
+            &self.identity.public_key(),
         );
 
-        return Some(link);
+        return Some(link.to_url());
     }
 
     pub fn generate_qr_code(&self, dark_mode: bool) -> Option<Vec<u8>> {
+        return self.generate_qr_code_with_options(QrCodeOptions::for_dark_mode(dark_mode));
+    }
+}
+
+#[uniffi::export]
+impl ShareStore {
+    /// PNG image of a QR code of the link.
+    pub fn generate_qr_code_with_options(&self, options: QrCodeOptions) -> Option<Vec<u8>> {
         let link = self.generate_link()?;
+        return generate_qr_code(link, &options);
+    }
 
-        let qrcode = QRBuilder::new(link).build().unwrap();
-
-        let img = ImageBuilder::default()
-            .shape(Shape::Circle)
-            .module_color(if dark_mode {
-                [255, 255, 255, 255]
-            } else {
-                [0, 0, 0, 255]
-            })
-            .background_color([0, 0, 0, 0])
-            .fit_width(300)
-            .to_bytes(&qrcode);
-
-        img.inspect_err(|error_message| {
-            error!(
-                "Error while trying to generate QR code: {:?}",
-                error_message
-            )
-        })
-        .ok()
+    /// SVG document of a QR code of the link.
+    pub fn generate_qr_code_svg(&self, options: QrCodeOptions) -> Option<String> {
+        let link = self.generate_link()?;
+        return generate_qr_code_svg(link, &options);
     }
 }
@@ -1,9 +1,16 @@
 use std::fmt::Debug;
 use std::io;
 use std::io::{Read, Write};
+use std::time::Duration;
 
 pub trait Close {
     fn close(&self);
+
+    /// Waits up to `timeout` until there is something to read, without reading it.
+    /// Streams which can't wait return `true` right away, reading then blocks as usual.
+    fn wait_readable(&self, _timeout: Duration) -> io::Result<bool> {
+        return Ok(true);
+    }
 }
 
 #[uniffi::export(callback_interface)]
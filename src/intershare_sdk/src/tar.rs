@@ -1,17 +1,197 @@
 use crate::encryption::EncryptedReadWrite;
+use crate::errors::{ArchiveEntryError, FileIntegrityError};
 use crate::progress::{ProgressReader, ProgressWriter};
 use crate::share_store::update_progress;
 use crate::BLE_BUFFER_SIZE;
 use crate::{SendProgressDelegate, SendProgressState};
-use log::info;
-use std::collections::HashMap;
+use log::{info, warn};
+use protocol::communication::{FileHash, FileManifestEntry, TransferTrailer};
+use protocol::prost::{self, Message};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsString;
-use std::fs::{self, File};
-use std::io::BufWriter;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicBool;
-use tar::{Archive, Builder, EntryType};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tar::{Archive, Builder, Entry, EntryType, Header};
+use walkdir::WalkDir;
+
+/// PAX extension carrying the offset at which the data of a resumed entry starts.
+const RESUME_OFFSET_PAX_KEY: &str = "INTERSHARE.resume_offset";
+
+/// Directory inside the file storage where partially received transfers are kept.
+const PARTIAL_TRANSFERS_DIRECTORY: &str = ".intershare-partial";
+
+/// Partial transfers which weren't resumed for this long are removed.
+const PARTIAL_TRANSFER_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Size of a tar block. An archive ends with two empty blocks.
+const TAR_BLOCK_SIZE: usize = 512;
+
+/// PAX extension flagging an entry whose data is compressed, with the algorithm as its value.
+const COMPRESSION_PAX_KEY: &str = "INTERSHARE.compression";
+
+const ZSTD_COMPRESSION: &str = "zstd";
+
+const ZSTD_COMPRESSION_LEVEL: i32 = 3;
+
+/// Entries smaller than this aren't compressed, the PAX header would cost more than is saved.
+const MIN_COMPRESSED_ENTRY_SIZE: u64 = 4 * 1024;
+
+/// Size of the sample compressed to decide whether a file of unknown type is worth compressing.
+const COMPRESSION_SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// How the archive is sent, as negotiated between both peers.
+pub struct ArchiveOptions {
+    /// A `TransferTrailer` with the hash of every file follows the archive.
+    pub file_hashes: bool,
+
+    /// Entries may be compressed with zstd. Each compressed entry is flagged in its PAX header.
+    pub compression: bool,
+}
+
+/// MIME types of formats which are compressed already, so compressing them again only costs time.
+pub(crate) fn is_compressed_format(mime_type: &str) -> bool {
+    let (kind, subtype) = mime_type.split_once('/').unwrap_or((mime_type, ""));
+
+    return match kind {
+        "image" => !matches!(subtype, "bmp" | "svg+xml" | "tiff" | "x-icon"),
+        "video" => true,
+        "audio" => !matches!(subtype, "wav" | "x-wav" | "aiff" | "x-aiff"),
+        "application" => {
+            matches!(
+                subtype,
+                "zip"
+                    | "gzip"
+                    | "x-gzip"
+                    | "x-bzip2"
+                    | "x-xz"
+                    | "x-7z-compressed"
+                    | "vnd.rar"
+                    | "x-rar-compressed"
+                    | "zstd"
+                    | "pdf"
+                    | "epub+zip"
+                    | "java-archive"
+                    | "vnd.android.package-archive"
+            ) || subtype.starts_with("vnd.openxmlformats-officedocument")
+        }
+        _ => false,
+    };
+}
+
+/// Whether compressing the data of `entry` is worth it. Files in compressed formats are
+/// sent as they are, files of unknown type only if a sample of them shrinks by a tenth or more.
+pub fn should_compress(entry: &ArchiveEntry) -> bool {
+    let manifest_entry = &entry.file.manifest_entry;
+
+    if manifest_entry.size.saturating_sub(entry.offset) < MIN_COMPRESSED_ENTRY_SIZE
+        || is_compressed_format(&manifest_entry.mime_type)
+    {
+        return false;
+    }
+
+    if manifest_entry.mime_type != mime_guess::mime::APPLICATION_OCTET_STREAM.as_ref() {
+        return true;
+    }
+
+    return sample_compression_ratio(&entry.file.source_path, entry.offset)
+        .is_ok_and(|ratio| ratio < 0.9);
+}
+
+/// Size of the compressed sample of a file, relative to the sample, starting at `offset`.
+fn sample_compression_ratio(path: &Path, offset: u64) -> io::Result<f64> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut sample = Vec::new();
+    file.take(COMPRESSION_SAMPLE_SIZE)
+        .read_to_end(&mut sample)?;
+
+    if sample.is_empty() {
+        return Ok(1.0);
+    }
+
+    let compressed_sample = zstd::bulk::compress(&sample, ZSTD_COMPRESSION_LEVEL)?;
+
+    return Ok(compressed_sample.len() as f64 / sample.len() as f64);
+}
+
+/// Compresses `data` into a temporary file, as the tar header needs the compressed size.
+/// Returns the file, positioned at its start, and its size.
+fn compress_to_temp_file(data: impl Read, cancel_flag: &AtomicBool) -> io::Result<(File, u64)> {
+    let mut compressed_file = tempfile::tempfile()?;
+    let mut encoder = zstd::Encoder::new(&mut compressed_file, ZSTD_COMPRESSION_LEVEL)?;
+
+    io::copy(
+        &mut ProgressReader::new(
+            data,
+            |_| {},
+            || cancel_flag.load(std::sync::atomic::Ordering::Relaxed),
+        ),
+        &mut encoder,
+    )?;
+    encoder.finish()?;
+
+    let compressed_size = compressed_file.stream_position()?;
+    compressed_file.rewind()?;
+
+    return Ok((compressed_file, compressed_size));
+}
+
+fn read_trailer(reader: &mut impl Read) -> io::Result<TransferTrailer> {
+    let mut length_delimiter = Vec::new();
+
+    loop {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        length_delimiter.push(byte[0]);
+
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let length = prost::decode_length_delimiter(length_delimiter.as_slice())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+
+    let mut message = vec![0u8; length];
+    reader.read_exact(&mut message)?;
+
+    return TransferTrailer::decode(message.as_slice())
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error));
+}
+
+/// Feeds everything read through it into a SHA-256 hash.
+struct HashingReader<'a, R: Read> {
+    inner: R,
+    hasher: &'a mut Sha256,
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_bytes = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read_bytes]);
+
+        return Ok(read_bytes);
+    }
+}
+
+fn hash_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(
+        &mut HashingReader {
+            inner: File::open(path)?,
+            hasher: &mut hasher,
+        },
+        &mut io::sink(),
+    )?;
+
+    return Ok(hasher.finalize().to_vec());
+}
 
 fn normalize_path(path: &Path) -> String {
     use std::path::Component;
@@ -31,26 +211,124 @@ fn normalize_path(path: &Path) -> String {
     ".".to_string()
 }
 
-fn get_unique_path(path: &Path) -> PathBuf {
-    if !path.exists() {
-        return path.to_path_buf();
-    }
+/// A file to send, together with the manifest entry announced to the receiver.
+pub struct ArchiveFile {
+    pub source_path: PathBuf,
+    pub manifest_entry: FileManifestEntry,
+}
 
-    let mut counter = 1;
-    let file_stem = path.file_stem().unwrap_or_default().to_string_lossy();
-    let extension = path
+fn archive_file(relative_path: String, path: &Path, metadata: &Metadata) -> ArchiveFile {
+    let modified_at = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs() as i64);
+
+    return ArchiveFile {
+        source_path: path.to_path_buf(),
+        manifest_entry: FileManifestEntry {
+            relative_path,
+            size: metadata.len(),
+            mime_type: mime_guess::from_path(path)
+                .first_or_octet_stream()
+                .to_string(),
+            modified_at,
+        },
+    };
+}
+
+/// `name` with a counter inserted before its extension, e.g. `photo (1).jpg`.
+fn numbered_file_name(name: &Path, counter: u32) -> String {
+    let file_stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = name
         .extension()
         .map(|ext| ext.to_string_lossy())
         .unwrap_or_default();
 
-    loop {
-        let new_file_name = if extension.is_empty() {
-            format!("{} ({})", file_stem, counter)
-        } else {
-            format!("{} ({}).{}", file_stem, counter, extension)
+    return if extension.is_empty() {
+        format!("{} ({})", file_stem, counter)
+    } else {
+        format!("{} ({}).{}", file_stem, counter, extension)
+    };
+}
+
+/// Numbers the file name of `relative_path` if the archive has an entry with this path already.
+pub(crate) fn unique_relative_path(
+    relative_path: String,
+    used_paths: &mut HashSet<String>,
+) -> String {
+    let mut unique_path = relative_path.clone();
+    let mut counter = 1;
+
+    while !used_paths.insert(unique_path.clone()) {
+        let file_name = numbered_file_name(Path::new(&relative_path), counter);
+
+        unique_path = match relative_path.rsplit_once('/') {
+            Some((parent, _)) => format!("{}/{}", parent, file_name),
+            None => file_name,
         };
+        counter += 1;
+    }
 
-        let new_path = path.with_file_name(new_file_name);
+    return unique_path;
+}
+
+/// Lists every file to send, using the relative paths they get in the archive.
+/// Directories are expanded into the files they contain. Files or directories with the
+/// same name are numbered, so every relative path is unique.
+pub fn build_file_manifest(file_paths: &[String]) -> std::io::Result<Vec<ArchiveFile>> {
+    let mut manifest = Vec::new();
+    let mut top_level_names = HashSet::new();
+
+    for file_path in file_paths {
+        let path = Path::new(file_path);
+        let normalized_path = unique_relative_path(normalize_path(path), &mut top_level_names);
+
+        if !path.is_dir() {
+            manifest.push(archive_file(normalized_path, path, &fs::metadata(path)?));
+            continue;
+        }
+
+        for entry in WalkDir::new(path).sort_by_file_name() {
+            let entry = entry?;
+
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let relative_path = entry
+                .path()
+                .strip_prefix(path)
+                .unwrap_or(entry.path())
+                .components()
+                .fold(normalized_path.clone(), |relative_path, component| {
+                    format!(
+                        "{}/{}",
+                        relative_path,
+                        component.as_os_str().to_string_lossy()
+                    )
+                });
+
+            manifest.push(archive_file(
+                relative_path,
+                entry.path(),
+                &entry.metadata()?,
+            ));
+        }
+    }
+
+    return Ok(manifest);
+}
+
+pub(crate) fn get_unique_path(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_path_buf();
+    }
+
+    let mut counter = 1;
+
+    loop {
+        let new_path = path.with_file_name(numbered_file_name(path, counter));
 
         if !new_path.exists() {
             return new_path;
@@ -60,46 +338,117 @@ fn get_unique_path(path: &Path) -> PathBuf {
     }
 }
 
+/// A file selected by the receiver and the offset from which it is sent.
+pub struct ArchiveEntry<'a> {
+    pub file: &'a ArchiveFile,
+    pub offset: u64,
+}
+
 pub fn stream_tar(
     output_stream: &mut Box<dyn EncryptedReadWrite>,
-    file_paths: &Vec<String>,
+    entries: &[ArchiveEntry],
     total_bytes: u64,
     progress_delegate: &Option<Box<dyn SendProgressDelegate>>,
+    cancel_flag: &AtomicBool,
+    options: &ArchiveOptions,
 ) -> std::io::Result<()> {
-    let progress_writer = ProgressWriter::new(output_stream, |sent_bytes| {
-        if sent_bytes > 0 {
-            let mut frac = (sent_bytes as f64) / (total_bytes as f64);
-            if frac > 0.999 {
-                frac = 0.999;
-            } // avoid hitting 1.0 early
-
-            update_progress(
-                progress_delegate,
-                SendProgressState::Transferring { progress: frac },
-            )
-        }
-    });
+    let progress_writer = ProgressWriter::new(
+        output_stream,
+        |sent_bytes| {
+            if sent_bytes > 0 {
+                let mut frac = (sent_bytes as f64) / (total_bytes as f64);
+                if frac > 0.999 {
+                    frac = 0.999;
+                } // avoid hitting 1.0 early
+
+                update_progress(
+                    progress_delegate,
+                    SendProgressState::Transferring { progress: frac },
+                )
+            }
+        },
+        || cancel_flag.load(std::sync::atomic::Ordering::Relaxed),
+    );
 
     let buf_out = BufWriter::with_capacity(BLE_BUFFER_SIZE, progress_writer);
     let mut tar = Builder::new(buf_out);
+    let mut file_hashes = Vec::new();
+
+    for entry in entries {
+        let relative_path = &entry.file.manifest_entry.relative_path;
+        info!("Archive path: {}", relative_path);
+
+        let mut source_file = File::open(&entry.file.source_path)?;
+        let metadata = source_file.metadata()?;
+        let offset = entry.offset.min(metadata.len());
+        let mut hasher = Sha256::new();
+        let mut pax_extensions = Vec::new();
+
+        if offset > 0 {
+            info!("Resuming {} at offset {}", relative_path, offset);
+            pax_extensions.push((RESUME_OFFSET_PAX_KEY, offset.to_string().into_bytes()));
+
+            // The hash covers the whole file, including the part the receiver already has.
+            io::copy(
+                &mut HashingReader {
+                    inner: (&mut source_file).take(offset),
+                    hasher: &mut hasher,
+                },
+                &mut io::sink(),
+            )?;
+        }
 
-    for file_path in file_paths {
-        let path = Path::new(file_path);
-        let normalized_path = normalize_path(path);
-        info!("Normalized path: {}", normalized_path);
+        let mut data = HashingReader {
+            inner: (&mut source_file).take(metadata.len() - offset),
+            hasher: &mut hasher,
+        };
 
-        if path.is_dir() {
-            tar.append_dir_all(&normalized_path, path)?;
+        let compressed_data = if options.compression && should_compress(entry) {
+            pax_extensions.push((COMPRESSION_PAX_KEY, ZSTD_COMPRESSION.as_bytes().to_vec()));
+            Some(compress_to_temp_file(&mut data, cancel_flag)?)
         } else {
-            let mut file = File::open(path)?;
-            tar.append_file(&normalized_path, &mut file)?;
+            None
+        };
+
+        // Only the last PAX header before an entry is read, so all extensions go into one.
+        if !pax_extensions.is_empty() {
+            tar.append_pax_extensions(
+                pax_extensions
+                    .iter()
+                    .map(|(key, value)| (*key, value.as_slice())),
+            )?;
         }
+
+        let mut header = Header::new_gnu();
+        header.set_metadata(&metadata);
+
+        match compressed_data {
+            Some((compressed_file, compressed_size)) => {
+                header.set_size(compressed_size);
+                tar.append_data(&mut header, relative_path, compressed_file)?;
+            }
+            None => {
+                header.set_size(metadata.len() - offset);
+                tar.append_data(&mut header, relative_path, data)?;
+            }
+        }
+
+        file_hashes.push(FileHash {
+            relative_path: relative_path.clone(),
+            sha256: hasher.finalize().to_vec(),
+        });
     }
 
     let buf_writer = tar.into_inner()?;
     let progress_writer = buf_writer.into_inner()?;
-    let stream = progress_writer.into_inner().0;
-    stream.flush()?;
+    let output_stream = progress_writer.into_inner().0;
+
+    if options.file_hashes {
+        output_stream
+            .write_all(&TransferTrailer { file_hashes }.encode_length_delimited_to_vec())?;
+    }
+
+    output_stream.flush()?;
 
     update_progress(
         progress_delegate,
@@ -114,22 +463,89 @@ fn sanitize_rel_path(p: &Path) -> PathBuf {
     use std::path::Component::*;
     let mut out = PathBuf::new();
     for comp in p.components() {
-        match comp {
-            Normal(seg) => out.push(seg),
-            _ => {} // skip RootDir, CurDir, ParentDir, Prefix
+        if let Normal(seg) = comp {
+            out.push(seg);
         }
     }
     out
 }
 
-pub fn untar_stream<T: FnMut(f64)>(
-    stream: &mut Box<dyn EncryptedReadWrite>,
-    dest_dir: &Path,
+/// Where the partial files of a transfer are kept until all of them are complete.
+/// `None` if the transfer id is not safe to use as a directory name.
+pub fn partial_transfer_dir(dest_dir: &Path, transfer_id: &str) -> Option<PathBuf> {
+    let is_valid = !transfer_id.is_empty()
+        && transfer_id
+            .chars()
+            .all(|character| character.is_ascii_alphanumeric() || "-_".contains(character));
+
+    if !is_valid {
+        return None;
+    }
+
+    return Some(dest_dir.join(PARTIAL_TRANSFERS_DIRECTORY).join(transfer_id));
+}
+
+/// Whether every file of the manifest is staged at a different path.
+/// Older senders may announce files with the same name, which can't be resumed.
+pub fn has_unique_paths(manifest: &[FileManifestEntry]) -> bool {
+    let mut relative_paths = HashSet::new();
+
+    return manifest.iter().all(|manifest_entry| {
+        relative_paths.insert(sanitize_rel_path(Path::new(&manifest_entry.relative_path)))
+    });
+}
+
+/// Removes the staging directory of a transfer which finished or won't be resumed.
+pub fn remove_partial_transfer(staging_dir: &Path) {
+    let _ = fs::remove_dir_all(staging_dir);
+
+    // Only succeeds if no other transfer is staged.
+    if let Some(partial_transfers_dir) = staging_dir.parent() {
+        let _ = fs::remove_dir(partial_transfers_dir);
+    }
+}
+
+/// Removes partial transfers of `dest_dir` which weren't resumed for a long time.
+pub fn remove_stale_partial_transfers(dest_dir: &Path) {
+    let Ok(staging_dirs) = fs::read_dir(dest_dir.join(PARTIAL_TRANSFERS_DIRECTORY)) else {
+        return;
+    };
+
+    for staging_dir in staging_dirs.flatten() {
+        let is_stale = staging_dir
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .is_some_and(|age| age > PARTIAL_TRANSFER_MAX_AGE);
+
+        if is_stale {
+            info!("Removing stale partial transfer {:?}", staging_dir.path());
+            remove_partial_transfer(&staging_dir.path());
+        }
+    }
+}
+
+/// Number of bytes of `manifest_entry` already received by a previous attempt.
+pub fn partial_file_offset(staging_dir: &Path, manifest_entry: &FileManifestEntry) -> u64 {
+    let partial_path =
+        staging_dir.join(sanitize_rel_path(Path::new(&manifest_entry.relative_path)));
+
+    return match fs::metadata(partial_path) {
+        Ok(metadata) if metadata.is_file() && metadata.len() <= manifest_entry.size => {
+            metadata.len()
+        }
+        _ => 0,
+    };
+}
+
+pub(crate) fn receive_progress_reader<'a, R: Read, T: FnMut(f64) + 'a>(
+    stream: R,
     total_bytes: u64,
     mut progress_cb: T,
-    cancel_flag: &AtomicBool,
-) -> std::io::Result<Vec<String>> {
-    let progress_reader = ProgressReader::new(
+    cancel_flag: &'a AtomicBool,
+) -> ProgressReader<R, impl FnMut(u64) + 'a, impl Fn() -> bool + 'a> {
+    return ProgressReader::new(
         stream,
         move |bytes_read| {
             if total_bytes > 0 {
@@ -143,7 +559,276 @@ pub fn untar_stream<T: FnMut(f64)>(
         },
         || cancel_flag.load(std::sync::atomic::Ordering::Relaxed),
     );
+}
+
+/// The resume offset of `entry` and whether its data is compressed.
+fn read_pax_extensions<R: Read>(
+    entry: &mut Entry<R>,
+    options: &ArchiveOptions,
+) -> io::Result<(u64, bool)> {
+    let mut offset = 0;
+    let mut compressed = false;
+
+    let Some(pax_extensions) = entry.pax_extensions()? else {
+        return Ok((offset, compressed));
+    };
+
+    for extension in pax_extensions {
+        let extension = extension?;
+
+        match extension.key() {
+            Ok(RESUME_OFFSET_PAX_KEY) => {
+                offset = extension
+                    .value()
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "Invalid resume offset")
+                    })?;
+            }
+            Ok(COMPRESSION_PAX_KEY) => {
+                if !options.compression || extension.value() != Ok(ZSTD_COMPRESSION) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unsupported entry compression",
+                    ));
+                }
+
+                compressed = true;
+            }
+            _ => {}
+        }
+    }
+
+    return Ok((offset, compressed));
+}
+
+/// Appends the data of `entry` to the partial file, starting at `offset`, and returns the
+/// number of bytes written. Compressed data is decompressed up to one byte more than
+/// `expected_length`. Everything written is authenticated by the encrypted stream,
+/// so it can be resumed from.
+fn unpack_partial_entry<R: Read>(
+    entry: &mut Entry<R>,
+    partial_path: &Path,
+    offset: u64,
+    expected_length: u64,
+    compressed: bool,
+) -> io::Result<u64> {
+    if let Some(parent) = partial_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(partial_path)?;
+
+    if file.metadata()?.len() < offset {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Partial file is shorter than the resume offset",
+        ));
+    }
+
+    file.set_len(offset)?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let written_bytes = if compressed {
+        let decoder = zstd::Decoder::new(entry)?.single_frame();
+        io::copy(&mut decoder.take(expected_length + 1), &mut file)?
+    } else {
+        io::copy(entry, &mut file)?
+    };
+    file.sync_data()?;
+
+    return Ok(written_bytes);
+}
+
+/// Moves the completed files out of the staging directory into `dest_dir`.
+fn move_partial_files(
+    staging_dir: &Path,
+    dest_dir: &Path,
+    relative_paths: &[PathBuf],
+) -> io::Result<Vec<String>> {
+    let mut restored_paths = Vec::new();
+    let mut top_level_map: HashMap<OsString, PathBuf> = HashMap::new();
+
+    for relative_path in relative_paths {
+        let mut components = relative_path.components();
+        let Some(std::path::Component::Normal(root_component)) = components.next() else {
+            continue;
+        };
+
+        if !top_level_map.contains_key(root_component) {
+            let root_target = get_unique_path(&dest_dir.join(root_component));
+            fs::rename(staging_dir.join(root_component), &root_target)?;
+            top_level_map.insert(root_component.to_os_string(), root_target);
+        }
+
+        let sub_path = components.as_path();
+        let root_target = &top_level_map[root_component];
+
+        let target_path = if sub_path.as_os_str().is_empty() {
+            root_target.clone()
+        } else {
+            root_target.join(sub_path)
+        };
+
+        restored_paths.push(target_path.to_string_lossy().to_string());
+    }
+
+    remove_partial_transfer(staging_dir);
+
+    return Ok(restored_paths);
+}
+
+/// Reads the `TransferTrailer` following the archive and compares its hashes with the
+/// received files. Files that don't match are removed, so they aren't resumed from.
+fn verify_partial_files(
+    reader: &mut impl Read,
+    staging_dir: &Path,
+    relative_paths: &[PathBuf],
+) -> io::Result<()> {
+    // The archive reader stops after the first of the two empty blocks at the end.
+    let mut end_of_archive = [0u8; TAR_BLOCK_SIZE];
+    reader.read_exact(&mut end_of_archive)?;
+
+    let trailer = read_trailer(reader)?;
+
+    let expected_hashes: HashMap<PathBuf, Vec<u8>> = trailer
+        .file_hashes
+        .into_iter()
+        .map(|file_hash| {
+            (
+                sanitize_rel_path(Path::new(&file_hash.relative_path)),
+                file_hash.sha256,
+            )
+        })
+        .collect();
+
+    let mut mismatched_files = Vec::new();
+
+    for relative_path in relative_paths {
+        let partial_path = staging_dir.join(relative_path);
+
+        if expected_hashes.get(relative_path) != Some(&hash_file(&partial_path)?) {
+            warn!("Hash mismatch for {}", relative_path.display());
+            let _ = fs::remove_file(partial_path);
+            mismatched_files.push(relative_path.to_string_lossy().to_string());
+        }
+    }
+
+    if !mismatched_files.is_empty() {
+        return Err(FileIntegrityError::HashMismatch {
+            files: mismatched_files,
+        }
+        .into());
+    }
+
+    return Ok(());
+}
+
+/// Like `untar_stream`, but keeps partial files in `staging_dir`, so an interrupted
+/// transfer can be resumed. Files are moved to `dest_dir` once all of them are complete.
+/// Aborts at the first entry which is not one of `accepted_entries` or differs in size.
+#[allow(clippy::too_many_arguments)]
+pub fn untar_stream_resumable<T: FnMut(f64)>(
+    stream: &mut Box<dyn EncryptedReadWrite>,
+    dest_dir: &Path,
+    staging_dir: &Path,
+    accepted_entries: &[FileManifestEntry],
+    total_bytes: u64,
+    progress_cb: T,
+    cancel_flag: &AtomicBool,
+    options: &ArchiveOptions,
+) -> std::io::Result<Vec<String>> {
+    let progress_reader = receive_progress_reader(stream, total_bytes, progress_cb, cancel_flag);
+    let mut archive = Archive::new(progress_reader);
+    let mut relative_paths = Vec::new();
+
+    // Every accepted file is expected once.
+    let mut expected_sizes: HashMap<PathBuf, u64> = accepted_entries
+        .iter()
+        .map(|manifest_entry| {
+            (
+                sanitize_rel_path(Path::new(&manifest_entry.relative_path)),
+                manifest_entry.size,
+            )
+        })
+        .collect();
+
+    for entry_result in archive.entries()? {
+        if cancel_flag.load(std::sync::atomic::Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "transfer cancelled",
+            ));
+        }
+
+        let mut entry = entry_result?;
 
+        if !matches!(
+            entry.header().entry_type(),
+            EntryType::Regular | EntryType::GNUSparse | EntryType::Continuous
+        ) {
+            continue;
+        }
+
+        let raw_rel_path = entry.path().map(|p| p.into_owned()).unwrap_or_default();
+        let clean_rel_path = sanitize_rel_path(&raw_rel_path);
+        if clean_rel_path.as_os_str().is_empty() {
+            continue;
+        }
+
+        let path = clean_rel_path.to_string_lossy().to_string();
+        let Some(expected_size) = expected_sizes.remove(&clean_rel_path) else {
+            return Err(ArchiveEntryError::NotAccepted { path }.into());
+        };
+
+        let (offset, compressed) = read_pax_extensions(&mut entry, options)?;
+        let Some(expected_length) = expected_size.checked_sub(offset) else {
+            return Err(ArchiveEntryError::SizeMismatch { path }.into());
+        };
+
+        // The size of compressed data is checked once it is decompressed.
+        if !compressed && entry.size() != expected_length {
+            return Err(ArchiveEntryError::SizeMismatch { path }.into());
+        }
+
+        let partial_path = staging_dir.join(&clean_rel_path);
+        let written_bytes = unpack_partial_entry(
+            &mut entry,
+            &partial_path,
+            offset,
+            expected_length,
+            compressed,
+        )?;
+
+        if written_bytes != expected_length {
+            return Err(ArchiveEntryError::SizeMismatch { path }.into());
+        }
+
+        relative_paths.push(clean_rel_path);
+    }
+
+    let mut stream = archive.into_inner().into_inner();
+
+    if options.file_hashes {
+        verify_partial_files(&mut stream, staging_dir, &relative_paths)?;
+    }
+
+    return move_partial_files(staging_dir, dest_dir, &relative_paths);
+}
+
+pub fn untar_stream<T: FnMut(f64)>(
+    stream: &mut Box<dyn EncryptedReadWrite>,
+    dest_dir: &Path,
+    total_bytes: u64,
+    progress_cb: T,
+    cancel_flag: &AtomicBool,
+) -> std::io::Result<Vec<String>> {
+    let progress_reader = receive_progress_reader(stream, total_bytes, progress_cb, cancel_flag);
     let mut archive = Archive::new(progress_reader);
     let mut restored_paths = Vec::new();
     let mut top_level_map: HashMap<OsString, PathBuf> = HashMap::new();
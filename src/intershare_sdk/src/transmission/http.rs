@@ -0,0 +1,879 @@
+use crate::connection_request::ConnectionRequest;
+use crate::encryption::generate_secure_base64_token;
+use crate::errors::ConvenienceDownloadError;
+use crate::identity::IdentityStore;
+use crate::nearby_server::{InternalNearbyServer, NearbyConnectionDelegate};
+use crate::share_registry::ShareRegistry;
+use crate::share_store::ShareStore;
+use crate::tar::{
+    build_file_manifest, get_unique_path, is_compressed_format, receive_progress_reader,
+    unique_relative_path, ArchiveFile,
+};
+use crate::transmission::bind_dual_stack;
+use crate::transmission::multipart::{multipart_boundary, MultipartReader, PartHeaders};
+use log::{error, info, warn};
+use protocol::communication::request::{Intent, RequestTypes};
+use protocol::communication::{DeclineReason, FileManifestEntry, FileTransferIntent, Request};
+use protocol::discovery::Device;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::RwLock;
+use url::form_urlencoded;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// Requests with a larger head are rejected, the server only needs the request line.
+const MAX_REQUEST_HEAD_SIZE: usize = 8 * 1024;
+
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connections beyond this are answered with `503 Service Unavailable` right away.
+const MAX_CONNECTIONS: usize = 32;
+
+/// Name of the archive multi-file shares are downloaded as.
+const ZIP_FILE_NAME: &str = "InterShare.zip";
+
+/// Form field the upload page fills with the name and size of every selected file,
+/// so they can be shown before the upload is accepted.
+const MANIFEST_FIELD: &str = "manifest";
+const MAX_MANIFEST_SIZE: usize = 1024 * 1024;
+
+/// Serves convenience shares to browsers, for devices without the app.
+///
+/// `GET /<request_id>` shows a landing page and `GET /<request_id>/download` downloads the share.
+/// Single files are sent as they are, multiple files as a zip archive.
+///
+/// `/upload/<upload_token>` lets browsers send files to this device. Every upload is passed to
+/// the `NearbyConnectionDelegate` as a `ConnectionRequest`.
+pub struct HttpServer {
+    pub port: u16,
+    pub upload_token: String,
+    running: Arc<AtomicBool>,
+}
+
+/// What the connections of the HTTP server need from the nearby server.
+#[derive(Clone)]
+struct HttpContext {
+    runtime: Handle,
+    shares: Arc<ShareRegistry>,
+    upload_token: String,
+    nearby_connection_delegate: Option<Arc<RwLock<Box<dyn NearbyConnectionDelegate>>>>,
+    file_storage: String,
+    identity: Arc<RwLock<Arc<IdentityStore>>>,
+}
+
+/// The head of an HTTP request.
+struct RequestHead {
+    method: String,
+    /// The request target, without the query.
+    path: String,
+    headers: Vec<(String, String)>,
+    /// Bytes of the body which were read together with the head.
+    body_start: Vec<u8>,
+}
+
+impl RequestHead {
+    fn header(&self, name: &str) -> Option<&str> {
+        return self
+            .headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str());
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+impl InternalNearbyServer {
+    pub(crate) async fn new_http_server(&self) -> Result<HttpServer, io::Error> {
+        let listener = bind_dual_stack(&[8080, 0])?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+
+        info!("Started http listener on port {}", port);
+
+        let running = Arc::new(AtomicBool::new(true));
+        let upload_token = generate_secure_base64_token(24);
+        let context = HttpContext {
+            runtime: Handle::current(),
+            shares: self.shares.clone(),
+            upload_token: upload_token.clone(),
+            nearby_connection_delegate: self.nearby_connection_delegate.clone(),
+            file_storage: self.file_storage.clone(),
+            identity: self.identity.clone(),
+        };
+
+        let loop_running = running.clone();
+        let active_connections = Arc::new(AtomicUsize::new(0));
+
+        thread::spawn(move || {
+            while loop_running.load(Ordering::SeqCst) {
+                let mut tcp_stream = match listener.accept() {
+                    Ok((tcp_stream, _socket_address)) => tcp_stream,
+                    Err(error) => {
+                        if error.kind() != io::ErrorKind::WouldBlock {
+                            warn!("Failed to accept http connection: {}", error);
+                        }
+
+                        thread::sleep(ACCEPT_POLL_INTERVAL);
+                        continue;
+                    }
+                };
+
+                if active_connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    active_connections.fetch_sub(1, Ordering::SeqCst);
+                    warn!("Too many http connections, refusing a new one");
+
+                    let _ = tcp_stream.set_nonblocking(false);
+                    let _ = write_error_page(
+                        &mut tcp_stream,
+                        "503 Service Unavailable",
+                        "The receiver is busy, try again later.",
+                    );
+                    continue;
+                }
+
+                let context = context.clone();
+                let active_connections = active_connections.clone();
+
+                thread::spawn(move || {
+                    if let Err(error) = handle_http_connection(tcp_stream, context) {
+                        warn!("Failed to answer http request: {}", error);
+                    }
+
+                    active_connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+
+            info!("Stopped http listener on port {}", port);
+        });
+
+        return Ok(HttpServer {
+            port,
+            upload_token,
+            running,
+        });
+    }
+}
+
+/// Reads the head of a request, up to the empty line in front of the body.
+fn read_request_head(tcp_stream: &mut TcpStream) -> io::Result<RequestHead> {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+
+    let head_end = loop {
+        if let Some(position) = head.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position;
+        }
+
+        if head.len() > MAX_REQUEST_HEAD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Request head is too large",
+            ));
+        }
+
+        let read = tcp_stream.read(&mut buffer)?;
+
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        head.extend_from_slice(&buffer[..read]);
+    };
+
+    let body_start = head.split_off(head_end + 4);
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+
+    let (Some(method), Some(target)) = (request_line.next(), request_line.next()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Malformed request line",
+        ));
+    };
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    return Ok(RequestHead {
+        method: method.to_string(),
+        path: target.split('?').next().unwrap_or_default().to_string(),
+        headers,
+        body_start,
+    });
+}
+
+fn write_response(
+    stream: &mut impl Write,
+    status: &str,
+    content_type: &str,
+    content_length: u64,
+    content_disposition: Option<String>,
+) -> io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n",
+        status, content_type, content_length
+    );
+
+    if let Some(content_disposition) = content_disposition {
+        head.push_str(&format!("Content-Disposition: {}\r\n", content_disposition));
+    }
+
+    head.push_str("\r\n");
+
+    return stream.write_all(head.as_bytes());
+}
+
+fn write_page(stream: &mut impl Write, status: &str, body: &str) -> io::Result<()> {
+    write_response(
+        stream,
+        status,
+        "text/html; charset=utf-8",
+        body.len() as u64,
+        None,
+    )?;
+
+    return stream.write_all(body.as_bytes());
+}
+
+fn write_error_page(stream: &mut impl Write, status: &str, message: &str) -> io::Result<()> {
+    return write_page(stream, status, &html_page(&format!("<p>{}</p>", message)));
+}
+
+fn handle_http_connection(mut tcp_stream: TcpStream, context: HttpContext) -> io::Result<()> {
+    tcp_stream.set_nonblocking(false)?;
+    tcp_stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+    let request_head = match read_request_head(&mut tcp_stream) {
+        Ok(request_head) => request_head,
+        Err(error) => {
+            let _ = write_error_page(&mut tcp_stream, "400 Bad Request", "Bad request");
+            return Err(error);
+        }
+    };
+
+    let mut segments = request_head.path.trim_start_matches('/').splitn(2, '/');
+    let token = segments.next().unwrap_or_default();
+    let action = segments.next().unwrap_or_default();
+
+    if token == "upload" {
+        return handle_upload(tcp_stream, &request_head, action, &context);
+    }
+
+    if request_head.method != "GET" {
+        return write_error_page(
+            &mut tcp_stream,
+            "405 Method Not Allowed",
+            "Method not allowed",
+        );
+    }
+
+    let share_id = Some(token).filter(|token| !token.is_empty());
+
+    // Only downloads count towards the download limit of a share, not views of its page.
+    let share_store = match action {
+        "download" => context.shares.start_download(share_id),
+        _ => context.shares.find(share_id),
+    };
+
+    let share_store = match share_store {
+        Ok(share_store) => share_store,
+        Err(ConvenienceDownloadError::UnknownShare) => {
+            return write_error_page(
+                &mut tcp_stream,
+                "404 Not Found",
+                "This share doesn't exist.",
+            );
+        }
+        Err(ConvenienceDownloadError::Expired) => {
+            return write_error_page(&mut tcp_stream, "410 Gone", "This share has expired.");
+        }
+        Err(ConvenienceDownloadError::DownloadLimitReached) => {
+            return write_error_page(
+                &mut tcp_stream,
+                "410 Gone",
+                "This share was downloaded as often as allowed.",
+            );
+        }
+    };
+
+    return match action {
+        "" => write_landing_page(&mut tcp_stream, &share_store),
+        "download" => {
+            info!("Serving http download of share {}", share_store.request_id);
+            let result = write_download(&mut tcp_stream, &share_store);
+            context
+                .shares
+                .finish_download(&share_store.request_id, result.is_ok());
+            result
+        }
+        _ => write_error_page(&mut tcp_stream, "404 Not Found", "Not found"),
+    };
+}
+
+fn share_manifest(share_store: &ShareStore) -> io::Result<Vec<ArchiveFile>> {
+    return match &share_store.file_paths {
+        Some(file_paths) => build_file_manifest(file_paths),
+        None => Ok(Vec::new()),
+    };
+}
+
+fn write_landing_page(tcp_stream: &mut TcpStream, share_store: &ShareStore) -> io::Result<()> {
+    if let Some(clipboard) = &share_store.clipboard {
+        let body = format!("<pre>{}</pre>", escape_html(clipboard));
+        return write_page(tcp_stream, "200 OK", &html_page(&body));
+    }
+
+    let manifest = match share_manifest(share_store) {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            error!("Failed to list shared files: {}", error);
+            return write_error_page(
+                tcp_stream,
+                "500 Internal Server Error",
+                "The shared files are not available anymore.",
+            );
+        }
+    };
+
+    let mut body = String::from("<ul>");
+
+    for archive_file in &manifest {
+        body.push_str(&format!(
+            "<li>{} <small>({})</small></li>",
+            escape_html(&archive_file.manifest_entry.relative_path),
+            format_size(archive_file.manifest_entry.size)
+        ));
+    }
+
+    body.push_str(&format!(
+        "</ul><a href=\"/{}/download\">Download</a>",
+        escape_html(&share_store.request_id)
+    ));
+
+    return write_page(tcp_stream, "200 OK", &html_page(&body));
+}
+
+fn write_download(tcp_stream: &mut TcpStream, share_store: &ShareStore) -> io::Result<()> {
+    if let Some(clipboard) = &share_store.clipboard {
+        write_response(
+            tcp_stream,
+            "200 OK",
+            "text/plain; charset=utf-8",
+            clipboard.len() as u64,
+            None,
+        )?;
+
+        return tcp_stream.write_all(clipboard.as_bytes());
+    }
+
+    let manifest = match share_manifest(share_store) {
+        Ok(manifest) if !manifest.is_empty() => manifest,
+        Ok(_) => return write_error_page(tcp_stream, "404 Not Found", "This share is empty."),
+        Err(error) => {
+            error!("Failed to list shared files: {}", error);
+            return write_error_page(
+                tcp_stream,
+                "500 Internal Server Error",
+                "The shared files are not available anymore.",
+            );
+        }
+    };
+
+    if let [archive_file] = manifest.as_slice() {
+        let file_name = archive_file
+            .manifest_entry
+            .relative_path
+            .rsplit('/')
+            .next()
+            .unwrap_or_default();
+        let file = File::open(&archive_file.source_path)?;
+
+        write_response(
+            tcp_stream,
+            "200 OK",
+            &archive_file.manifest_entry.mime_type,
+            file.metadata()?.len(),
+            Some(attachment(file_name)),
+        )?;
+
+        return io::copy(&mut BufReader::new(file), tcp_stream).map(|_| ());
+    }
+
+    // The zip writer needs to seek, so the archive is built in a temporary file first.
+    let mut archive = write_zip(&manifest)?;
+    let archive_size = archive.seek(SeekFrom::End(0))?;
+    archive.rewind()?;
+
+    write_response(
+        tcp_stream,
+        "200 OK",
+        "application/zip",
+        archive_size,
+        Some(attachment(ZIP_FILE_NAME)),
+    )?;
+
+    return io::copy(&mut BufReader::new(archive), tcp_stream).map(|_| ());
+}
+
+fn write_zip(manifest: &[ArchiveFile]) -> io::Result<File> {
+    let mut zip_writer = ZipWriter::new(tempfile::tempfile()?);
+    // The zip writer fails on duplicate names, the manifest should not have any though.
+    let mut entry_names = HashSet::new();
+
+    for archive_file in manifest {
+        let compression_method = if is_compressed_format(&archive_file.manifest_entry.mime_type) {
+            CompressionMethod::Stored
+        } else {
+            CompressionMethod::Deflated
+        };
+
+        let options = SimpleFileOptions::default()
+            .compression_method(compression_method)
+            .large_file(archive_file.manifest_entry.size >= u32::MAX as u64);
+
+        let entry_name = unique_relative_path(
+            archive_file.manifest_entry.relative_path.clone(),
+            &mut entry_names,
+        );
+
+        zip_writer.start_file(entry_name, options)?;
+        io::copy(
+            &mut BufReader::new(File::open(&archive_file.source_path)?),
+            &mut zip_writer,
+        )?;
+    }
+
+    return Ok(zip_writer.finish()?);
+}
+
+fn handle_upload(
+    mut tcp_stream: TcpStream,
+    request_head: &RequestHead,
+    upload_token: &str,
+    context: &HttpContext,
+) -> io::Result<()> {
+    let Some(delegate) = &context.nearby_connection_delegate else {
+        return write_error_page(&mut tcp_stream, "404 Not Found", "Not found");
+    };
+
+    if upload_token != context.upload_token {
+        return write_error_page(
+            &mut tcp_stream,
+            "404 Not Found",
+            "This upload link is not valid anymore.",
+        );
+    }
+
+    return match request_head.method.as_str() {
+        "GET" => write_page(&mut tcp_stream, "200 OK", &html_page(UPLOAD_FORM)),
+        "POST" => request_upload(tcp_stream, request_head, delegate, context),
+        _ => write_error_page(
+            &mut tcp_stream,
+            "405 Method Not Allowed",
+            "Method not allowed",
+        ),
+    };
+}
+
+const UPLOAD_FORM: &str = r#"<form method="post" enctype="multipart/form-data"><input type="hidden" name="manifest" id="manifest"><input type="file" name="files" id="files" multiple required> <button type="submit">Send</button></form><script>document.getElementById("files").addEventListener("change", function (event) { document.getElementById("manifest").value = Array.from(event.target.files).map(function (file) { return new URLSearchParams({ name: file.name, size: file.size }).toString(); }).join("\n"); });</script>"#;
+
+/// Reads the files a browser announced and asks the user to accept them.
+fn request_upload(
+    mut tcp_stream: TcpStream,
+    request_head: &RequestHead,
+    delegate: &Arc<RwLock<Box<dyn NearbyConnectionDelegate>>>,
+    context: &HttpContext,
+) -> io::Result<()> {
+    let Some(boundary) = request_head
+        .header("Content-Type")
+        .and_then(multipart_boundary)
+    else {
+        return write_error_page(&mut tcp_stream, "400 Bad Request", "Expected a form upload");
+    };
+
+    let Some(content_length) = request_head
+        .header("Content-Length")
+        .and_then(|content_length| content_length.parse::<u64>().ok())
+    else {
+        return write_error_page(&mut tcp_stream, "411 Length Required", "Length required");
+    };
+
+    // IPv4 peers of the dual stack listener have IPv4-mapped addresses.
+    let peer_ip = tcp_stream.peer_addr()?.ip().to_canonical();
+    let mut upload = HttpUpload::new(
+        tcp_stream,
+        &boundary,
+        request_head.body_start.clone(),
+        content_length,
+    );
+
+    let manifest = match upload.read_manifest() {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            let _ = write_error_page(&mut upload.stream, "400 Bad Request", "Bad request");
+            return Err(error);
+        }
+    };
+
+    info!(
+        "Received upload request of {} files from {}",
+        manifest.len(),
+        peer_ip
+    );
+
+    let request = Request {
+        r#type: RequestTypes::ShareRequest as i32,
+        device: Some(Device {
+            id: format!("browser-{}", peer_ip),
+            name: "Web browser".to_string(),
+            device_type: 0,
+            protocol_version: None,
+        }),
+        intent: Some(Intent::FileTransfer(FileTransferIntent {
+            file_name: manifest
+                .first()
+                .map(|manifest_entry| manifest_entry.relative_path.clone()),
+            file_size: if manifest.is_empty() {
+                content_length
+            } else {
+                manifest
+                    .iter()
+                    .map(|manifest_entry| manifest_entry.size)
+                    .sum()
+            },
+            file_count: manifest.len() as u64,
+            manifest,
+            transfer_id: None,
+        })),
+        share_id: None,
+    };
+
+    let connection_request = ConnectionRequest::new_upload(
+        request,
+        upload,
+        context.file_storage.clone(),
+        context.runtime.block_on(context.identity.read()).clone(),
+    );
+
+    context
+        .runtime
+        .block_on(delegate.read())
+        .received_connection_request(Arc::new(connection_request));
+
+    return Ok(());
+}
+
+/// Name to store an uploaded file as. Some browsers send the whole path of the file.
+fn upload_file_name(file_name: &str) -> Option<&str> {
+    let file_name = file_name.rsplit(['/', '\\']).next()?;
+
+    if file_name.is_empty() || file_name == "." || file_name == ".." {
+        return None;
+    }
+
+    return Some(file_name);
+}
+
+/// Files a browser is uploading, waiting for the user to accept or decline them.
+pub struct HttpUpload<S = TcpStream> {
+    stream: S,
+    multipart: MultipartReader,
+    content_length: u64,
+    /// Headers of the part after the manifest, if they were read already.
+    next_part: Option<PartHeaders>,
+    /// Files the upload page announced, empty if it couldn't add them to the form.
+    manifest: Vec<FileManifestEntry>,
+}
+
+impl<S: Read + Write> HttpUpload<S> {
+    /// `body_start` are the bytes of the body which were read together with the request head.
+    pub fn new(stream: S, boundary: &str, body_start: Vec<u8>, content_length: u64) -> Self {
+        return Self {
+            stream,
+            multipart: MultipartReader::new(boundary, body_start),
+            content_length,
+            next_part: None,
+            manifest: Vec::new(),
+        };
+    }
+
+    /// Reads the name and size of every file, if the upload page could add them to the form.
+    pub fn read_manifest(&mut self) -> io::Result<Vec<FileManifestEntry>> {
+        self.multipart.skip_preamble(&mut self.stream)?;
+
+        let Some(part) = self.multipart.next_part(&mut self.stream)? else {
+            return Ok(vec![]);
+        };
+
+        if part.name.as_deref() != Some(MANIFEST_FIELD) || part.file_name.is_some() {
+            self.next_part = Some(part);
+            return Ok(vec![]);
+        }
+
+        let mut manifest = vec![0u8; MAX_MANIFEST_SIZE];
+        let mut unused_manifest = manifest.as_mut_slice();
+        self.multipart
+            .copy_part(&mut self.stream, &mut unused_manifest)
+            .map_err(|error| {
+                if error.kind() == io::ErrorKind::WriteZero {
+                    return io::Error::new(io::ErrorKind::InvalidData, "Manifest is too large");
+                }
+
+                return error;
+            })?;
+        let manifest_length = MAX_MANIFEST_SIZE - unused_manifest.len();
+
+        self.manifest = String::from_utf8_lossy(&manifest[..manifest_length])
+            .lines()
+            .filter_map(|line| {
+                let mut relative_path = None;
+                let mut size = None;
+
+                for (key, value) in form_urlencoded::parse(line.as_bytes()) {
+                    match key.as_ref() {
+                        "name" => relative_path = upload_file_name(&value).map(str::to_string),
+                        "size" => size = value.parse::<u64>().ok(),
+                        _ => {}
+                    }
+                }
+
+                let relative_path = relative_path?;
+
+                return Some(FileManifestEntry {
+                    mime_type: mime_guess::from_path(&relative_path)
+                        .first_or_octet_stream()
+                        .to_string(),
+                    relative_path,
+                    size: size?,
+                    modified_at: None,
+                });
+            })
+            .collect();
+
+        return Ok(self.manifest.clone());
+    }
+
+    /// Stores the uploaded files in `dest_dir`. `selected_entries` are indices into the manifest,
+    /// an empty selection receives all files.
+    ///
+    /// Fails if the files don't match the manifest, nothing is kept then.
+    pub fn receive<T: FnMut(f64)>(
+        mut self,
+        dest_dir: &Path,
+        selected_entries: &[u32],
+        progress_cb: T,
+        cancel_flag: &AtomicBool,
+    ) -> io::Result<Vec<String>> {
+        let mut received_files = Vec::new();
+        let result = self.receive_files(
+            dest_dir,
+            selected_entries,
+            progress_cb,
+            cancel_flag,
+            &mut received_files,
+        );
+
+        let _ = match &result {
+            Ok(()) => write_page(
+                &mut self.stream,
+                "200 OK",
+                &html_page(&format!("<p>Sent {} files.</p>", received_files.len())),
+            ),
+            Err(_) => write_error_page(
+                &mut self.stream,
+                "500 Internal Server Error",
+                "The upload was cancelled.",
+            ),
+        };
+
+        if let Err(error) = result {
+            for file_path in received_files {
+                let _ = fs::remove_file(file_path);
+            }
+
+            return Err(error);
+        }
+
+        return Ok(received_files);
+    }
+
+    fn receive_files<T: FnMut(f64)>(
+        &mut self,
+        dest_dir: &Path,
+        selected_entries: &[u32],
+        progress_cb: T,
+        cancel_flag: &AtomicBool,
+        received_files: &mut Vec<String>,
+    ) -> io::Result<()> {
+        fs::create_dir_all(dest_dir)?;
+
+        let remaining_bytes = self
+            .content_length
+            .saturating_sub(self.multipart.bytes_read());
+        let mut reader =
+            receive_progress_reader(&mut self.stream, remaining_bytes, progress_cb, cancel_flag);
+        let mut file_index = 0;
+
+        loop {
+            let part = match self.next_part.take() {
+                Some(part) => part,
+                None => match self.multipart.next_part(&mut reader)? {
+                    Some(part) => part,
+                    None => break,
+                },
+            };
+
+            let Some(file_name) = part.file_name.as_deref().and_then(upload_file_name) else {
+                self.multipart.copy_part(&mut reader, &mut io::sink())?;
+                continue;
+            };
+
+            // Selected entries are indices into the manifest, the files have to be the announced ones.
+            let manifest_entry = self.manifest.get(file_index as usize);
+
+            if !self.manifest.is_empty()
+                && manifest_entry.map(|entry| entry.relative_path.as_str()) != Some(file_name)
+            {
+                return Err(manifest_mismatch());
+            }
+
+            let selected = selected_entries.is_empty() || selected_entries.contains(&file_index);
+            file_index += 1;
+
+            if !selected {
+                self.multipart.copy_part(&mut reader, &mut io::sink())?;
+                continue;
+            }
+
+            let file_path = get_unique_path(&dest_dir.join(file_name));
+            let mut file = BufWriter::new(File::create(&file_path)?);
+            received_files.push(file_path.to_string_lossy().into_owned());
+
+            let size = self
+                .multipart
+                .copy_part(&mut reader, &mut file)
+                .and_then(|size| file.flush().map(|_| size))?;
+
+            if manifest_entry.is_some_and(|entry| entry.size != size) {
+                return Err(manifest_mismatch());
+            }
+        }
+
+        if !self.manifest.is_empty() && file_index as usize != self.manifest.len() {
+            return Err(manifest_mismatch());
+        }
+
+        return Ok(());
+    }
+
+    /// Tells the browser that the upload was declined.
+    pub fn decline(mut self, reason: DeclineReason) {
+        let message = match reason {
+            DeclineReason::Busy => "The receiver is busy, try again later.",
+            DeclineReason::TooLarge => "The files are too large.",
+            DeclineReason::InsufficientStorage => "The receiver doesn't have enough storage.",
+            _ => "The receiver declined the upload.",
+        };
+
+        let _ = write_error_page(&mut self.stream, "403 Forbidden", message);
+
+        // Browsers only show the answer once they sent the whole body.
+        let remaining_bytes = self
+            .content_length
+            .saturating_sub(self.multipart.bytes_read());
+        let _ = io::copy(
+            &mut (&mut self.stream).take(remaining_bytes),
+            &mut io::sink(),
+        );
+    }
+}
+
+fn manifest_mismatch() -> io::Error {
+    return io::Error::new(
+        io::ErrorKind::InvalidData,
+        "The uploaded files don't match the manifest",
+    );
+}
+
+fn html_page(body: &str) -> String {
+    return format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\"><title>InterShare</title></head><body><h1>InterShare</h1>{}</body></html>",
+        body
+    );
+}
+
+fn escape_html(text: &str) -> String {
+    return text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;");
+}
+
+/// `Content-Disposition` header value, with an ASCII fallback and the UTF-8 name (RFC 6266).
+fn attachment(file_name: &str) -> String {
+    let ascii_name: String = file_name
+        .chars()
+        .map(|character| {
+            if character == ' ' || character.is_ascii_graphic() && !matches!(character, '"' | '\\')
+            {
+                character
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    let encoded_name: String = file_name
+        .bytes()
+        .map(|byte| {
+            if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+                (byte as char).to_string()
+            } else {
+                format!("%{:02X}", byte)
+            }
+        })
+        .collect();
+
+    return format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        ascii_name, encoded_name
+    );
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        return format!("{} {}", bytes, UNITS[unit]);
+    }
+
+    return format!("{:.1} {}", size, UNITS[unit]);
+}
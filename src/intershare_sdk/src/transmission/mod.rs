@@ -1,9 +1,51 @@
+use log::info;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
 use thiserror::Error;
 
+pub mod http;
+pub mod multipart;
 pub mod tcp;
 
 #[derive(Error, Debug, uniffi::Error)]
 pub enum TransmissionSetupError {
     #[error("Unable to start TCP server: {error}")]
     UnableToStartTcpServer { error: String },
+
+    #[error("Unable to start HTTP server: {error}")]
+    UnableToStartHttpServer { error: String },
+}
+
+/// Listens on the first free port of `ports`, for IPv6 and IPv4 connections alike.
+/// Falls back to IPv4 only if the device doesn't support IPv6.
+pub(crate) fn bind_dual_stack(ports: &[u16]) -> io::Result<TcpListener> {
+    for port in ports {
+        match bind_ipv6(*port) {
+            Ok(listener) => return Ok(listener),
+            Err(error) => info!("Unable to listen on [::]:{}: {}", port, error),
+        }
+    }
+
+    let addresses: Vec<SocketAddr> = ports
+        .iter()
+        .map(|port| SocketAddr::from((Ipv4Addr::UNSPECIFIED, *port)))
+        .collect();
+
+    return TcpListener::bind(&addresses[..]);
+}
+
+/// An IPv6 listener which also accepts IPv4 connections, as IPv4-mapped addresses.
+fn bind_ipv6(port: u16) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+
+    // Like `TcpListener::bind`, so a restarted server gets its port back right away.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.listen(128)?;
+
+    return Ok(socket.into());
 }
@@ -0,0 +1,219 @@
+use std::io::{self, Read, Write};
+
+/// Part headers larger than this are rejected.
+const MAX_PART_HEAD_SIZE: usize = 8 * 1024;
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The headers of a form field.
+pub struct PartHeaders {
+    pub name: Option<String>,
+    /// Set for file fields, empty if no file was selected.
+    pub file_name: Option<String>,
+}
+
+/// Reads the parts of a `multipart/form-data` body (RFC 7578) one after another,
+/// without keeping whole parts in memory.
+pub struct MultipartReader {
+    /// The boundary, including the line break in front of it.
+    delimiter: Vec<u8>,
+    buffer: Vec<u8>,
+    bytes_read: u64,
+}
+
+impl MultipartReader {
+    /// `body_start` are the bytes of the body which were read together with the request head.
+    pub fn new(boundary: &str, body_start: Vec<u8>) -> Self {
+        let bytes_read = body_start.len() as u64;
+
+        // The first boundary is at the start of the body, without a line break in front of it.
+        let mut buffer = b"\r\n".to_vec();
+        buffer.extend(body_start);
+
+        return Self {
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            buffer,
+            bytes_read,
+        };
+    }
+
+    /// Bytes of the body read so far.
+    pub fn bytes_read(&self) -> u64 {
+        return self.bytes_read;
+    }
+
+    fn fill(&mut self, reader: &mut impl Read) -> io::Result<()> {
+        let mut chunk = vec![0u8; READ_BUFFER_SIZE];
+        let read = reader.read(&mut chunk)?;
+
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Multipart body ended unexpectedly",
+            ));
+        }
+
+        self.bytes_read += read as u64;
+        self.buffer.extend_from_slice(&chunk[..read]);
+
+        return Ok(());
+    }
+
+    /// Skips everything in front of the first part.
+    pub fn skip_preamble(&mut self, reader: &mut impl Read) -> io::Result<()> {
+        self.copy_part(reader, &mut io::sink())?;
+        return Ok(());
+    }
+
+    /// Copies the content of the current part to `writer`, up to the next boundary.
+    pub fn copy_part(
+        &mut self,
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+    ) -> io::Result<u64> {
+        let mut written = 0;
+
+        loop {
+            if let Some(position) = find(&self.buffer, &self.delimiter) {
+                writer.write_all(&self.buffer[..position])?;
+                self.buffer.drain(..position + self.delimiter.len());
+
+                return Ok(written + position as u64);
+            }
+
+            // The end of the buffer may be the start of the delimiter.
+            let complete = self.buffer.len().saturating_sub(self.delimiter.len() - 1);
+
+            writer.write_all(&self.buffer[..complete])?;
+            self.buffer.drain(..complete);
+            written += complete as u64;
+
+            self.fill(reader)?;
+        }
+    }
+
+    /// Reads the headers of the next part. `None` if the previous part was the last one.
+    pub fn next_part(&mut self, reader: &mut impl Read) -> io::Result<Option<PartHeaders>> {
+        while self.buffer.len() < 2 {
+            self.fill(reader)?;
+        }
+
+        if self.buffer.starts_with(b"--") {
+            return Ok(None);
+        }
+
+        if !self.buffer.starts_with(b"\r\n") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Malformed multipart boundary",
+            ));
+        }
+
+        let head_end = loop {
+            if let Some(position) = find(&self.buffer, b"\r\n\r\n") {
+                break position;
+            }
+
+            if self.buffer.len() > MAX_PART_HEAD_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Multipart headers are too large",
+                ));
+            }
+
+            self.fill(reader)?;
+        };
+
+        let head = String::from_utf8_lossy(&self.buffer[2..head_end]).into_owned();
+        self.buffer.drain(..head_end + 4);
+
+        let mut part_headers = PartHeaders {
+            name: None,
+            file_name: None,
+        };
+
+        for line in head.lines() {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+
+            if !name.trim().eq_ignore_ascii_case("content-disposition") {
+                continue;
+            }
+
+            part_headers.name = header_parameter(value, "name");
+            part_headers.file_name = header_parameter(value, "filename");
+        }
+
+        return Ok(Some(part_headers));
+    }
+}
+
+/// Position of the first occurrence of `needle`. Looks for its first byte first,
+/// which is rare in file contents for a line break.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    let mut start = 0;
+
+    while let Some(offset) = haystack[start..].iter().position(|byte| *byte == needle[0]) {
+        let position = start + offset;
+
+        if haystack[position..].starts_with(needle) {
+            return Some(position);
+        }
+
+        start = position + 1;
+    }
+
+    return None;
+}
+
+/// Value of a parameter of a header like `form-data; name="files"; filename="a.txt"`.
+fn header_parameter(header_value: &str, parameter: &str) -> Option<String> {
+    let mut parameters = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+
+    for (index, character) in header_value.char_indices() {
+        match character {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                parameters.push(&header_value[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+
+    parameters.push(&header_value[start..]);
+
+    return parameters.into_iter().find_map(|candidate| {
+        let (key, value) = candidate.split_once('=')?;
+
+        if !key.trim().eq_ignore_ascii_case(parameter) {
+            return None;
+        }
+
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+
+        return Some(value.to_string());
+    });
+}
+
+/// Boundary of a `multipart/form-data` content type.
+pub fn multipart_boundary(content_type: &str) -> Option<String> {
+    let (media_type, _) = content_type.split_once(';')?;
+
+    if !media_type
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+
+    return header_parameter(content_type, "boundary")
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70);
+}
@@ -1,17 +1,21 @@
-use crate::communication::initiate_receiver_communication;
+use crate::communication::{initiate_receiver_communication, is_downgrade};
 use crate::connection_request::ConnectionRequest;
+use crate::identity::IdentityStore;
 use crate::nearby_server::{InternalNearbyServer, NearbyConnectionDelegate};
+use crate::share_registry::ShareRegistry;
 use crate::stream::Close;
-use log::info;
+use crate::transmission::bind_dual_stack;
+use log::{error, info};
 use prost_stream::Stream;
 use protocol::communication::request::RequestTypes;
 use protocol::communication::Request;
-use std::io;
+use std::io::{self, Write};
 use std::net::SocketAddr;
-use std::net::{TcpListener, TcpStream};
+use std::net::{Shutdown, TcpListener, TcpStream};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::runtime::Handle;
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
 
@@ -20,6 +24,7 @@ pub struct TcpServer {
     listener: Option<TcpListener>,
     delegate: Arc<RwLock<Box<dyn NearbyConnectionDelegate>>>,
     file_storage: String,
+    identity: Arc<RwLock<Arc<IdentityStore>>>,
     running: Arc<AtomicBool>,
     tcp_server_task: RwLock<Option<JoinHandle<()>>>,
 }
@@ -30,14 +35,7 @@ impl InternalNearbyServer {
         delegate: Arc<RwLock<Box<dyn NearbyConnectionDelegate>>>,
         file_storage: String,
     ) -> Result<TcpServer, io::Error> {
-        let addresses = [
-            SocketAddr::from(([0, 0, 0, 0], 4251)),
-            SocketAddr::from(([0, 0, 0, 0], 80)),
-            SocketAddr::from(([0, 0, 0, 0], 8080)),
-            SocketAddr::from(([0, 0, 0, 0], 0)),
-        ];
-
-        let listener = TcpListener::bind(&addresses[..])?;
+        let listener = bind_dual_stack(&[4251, 80, 8080, 0])?;
         listener
             .set_nonblocking(false)
             .expect("Failed to set non blocking");
@@ -50,6 +48,7 @@ impl InternalNearbyServer {
             listener: Some(listener),
             delegate,
             file_storage,
+            identity: self.identity.clone(),
             running: Arc::new(AtomicBool::new(true)),
             tcp_server_task: RwLock::new(None),
         });
@@ -77,6 +76,8 @@ impl InternalNearbyServer {
             .expect("Failed to set non blocking");
         let delegate = tcp_server.delegate.clone();
         let file_storage = tcp_server.file_storage.clone();
+        let identity = tcp_server.identity.clone();
+        let shares = self.shares.clone();
         let running = tcp_server.running.clone();
 
         let handle = tokio::spawn(async move {
@@ -90,37 +91,23 @@ impl InternalNearbyServer {
                     .set_nonblocking(false)
                     .expect("Failed to set non blocking");
 
-                let mut encrypted_stream = match initiate_receiver_communication(tcp_stream) {
-                    Ok(request) => request,
-                    Err(error) => {
-                        println!("Encryption error {:}", error);
-                        continue;
-                    }
-                };
-
-                let mut prost_stream = Stream::new(&mut encrypted_stream);
-                let transfer_request = match prost_stream.recv::<Request>() {
-                    Ok(message) => message,
-                    Err(error) => {
-                        println!("Error {:}", error);
-                        continue;
-                    }
-                };
-
-                if transfer_request.r#type == RequestTypes::ShareRequest as i32 {
-                    let connection_request = ConnectionRequest::new(
-                        transfer_request,
-                        Box::new(encrypted_stream),
-                        file_storage.clone(),
-                    );
-
-                    delegate
-                        .read()
-                        .await
-                        .received_connection_request(Arc::new(connection_request));
-                } else {
-                    // NearbyServer::received_convenience_download_request(transfer_request, current_share_store.clone()).await;
-                }
+                // Read for every connection, the identity store can be replaced at any time.
+                let identity = identity.read().await.clone();
+                let delegate = delegate.clone();
+                let file_storage = file_storage.clone();
+                let shares = shares.clone();
+
+                // The handshake and serving block until the peer is done, the loop keeps
+                // accepting connections.
+                tokio::task::spawn_blocking(move || {
+                    Handle::current().block_on(handle_connection(
+                        tcp_stream,
+                        identity,
+                        delegate,
+                        file_storage,
+                        shares,
+                    ))
+                });
             }
 
             info!("Stopped loop");
@@ -154,6 +141,67 @@ impl InternalNearbyServer {
     }
 }
 
+/// Performs the handshake of an accepted connection and passes its request on.
+async fn handle_connection(
+    tcp_stream: TcpStream,
+    identity: Arc<IdentityStore>,
+    delegate: Arc<RwLock<Box<dyn NearbyConnectionDelegate>>>,
+    file_storage: String,
+    shares: Arc<ShareRegistry>,
+) {
+    let mut encrypted_connection = match initiate_receiver_communication(tcp_stream, &identity) {
+        Ok(request) => request,
+        Err(error) => {
+            error!("Encryption error {:}", error);
+            return;
+        }
+    };
+
+    let mut prost_stream = Stream::new(&mut encrypted_connection.stream);
+    let transfer_request = match prost_stream.recv::<Request>() {
+        Ok(message) => message,
+        Err(error) => {
+            error!("Error {:}", error);
+            return;
+        }
+    };
+
+    let sender_id = transfer_request
+        .device
+        .as_ref()
+        .map(|device| device.id.as_str());
+
+    if is_downgrade(
+        &encrypted_connection,
+        &identity,
+        sender_id.unwrap_or_default(),
+    ) {
+        encrypted_connection.stream.close();
+        return;
+    }
+
+    if transfer_request.r#type == RequestTypes::ShareRequest as i32 {
+        let connection_request = ConnectionRequest::new(
+            transfer_request,
+            encrypted_connection,
+            file_storage,
+            identity,
+        );
+
+        delegate
+            .read()
+            .await
+            .received_connection_request(Arc::new(connection_request));
+    } else if transfer_request.r#type == RequestTypes::ConvenienceDownloadRequest as i32 {
+        InternalNearbyServer::received_convenience_download_request(
+            transfer_request,
+            encrypted_connection,
+            shares,
+        )
+        .await;
+    }
+}
+
 pub struct TcpClient {}
 
 impl TcpClient {
@@ -169,6 +217,28 @@ impl TcpClient {
 
 impl Close for TcpStream {
     fn close(&self) {
-        // Do nothing. TCPStream closes automatically.
+        // Shut down explicitly, the stream may be kept alive by the connection request.
+        // Only the sending side, so the peer can still read a cancel frame written before.
+        let _ = (&*self).flush();
+        let _ = self.shutdown(Shutdown::Write);
+    }
+
+    fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        self.set_read_timeout(Some(timeout))?;
+        let peek_result = self.peek(&mut [0u8; 1]);
+        self.set_read_timeout(None)?;
+
+        return match peek_result {
+            Ok(_) => Ok(true),
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(false)
+            }
+            Err(error) => Err(error),
+        };
     }
 }
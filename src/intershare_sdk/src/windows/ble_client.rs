@@ -80,6 +80,7 @@ impl InternalDiscovery {
                   args: &Option<BluetoothLEAdvertisementReceivedEventArgs>| {
                 let args = args.as_ref().unwrap();
                 let ble_address = args.BluetoothAddress()?;
+                let rssi = args.RawSignalStrengthInDBm().ok().map(i32::from);
                 let discovered_devices = discovered_devices_clone.clone();
                 let internal_discovery = internal_discovery_clone.clone();
 
@@ -94,6 +95,7 @@ impl InternalDiscovery {
                 runtime_handle.spawn(async move {
                     if let Err(e) = Self::connect_and_read_characteristic(
                         ble_address,
+                        rssi,
                         internal_discovery,
                         local_name,
                     )
@@ -125,6 +127,7 @@ impl InternalDiscovery {
 
     async fn connect_and_read_characteristic(
         ble_address: u64,
+        rssi: Option<i32>,
         internal_discovery: Arc<Self>,
         device_name: String,
     ) -> Result<()> {
@@ -194,7 +197,7 @@ impl InternalDiscovery {
         let mut buffer = vec![0u8; length];
         reader.ReadBytes(&mut buffer)?;
 
-        internal_discovery.parse_discovery_message(buffer, Some(device_id));
+        internal_discovery.parse_discovery_message_with_rssi(buffer, Some(device_id), rssi);
 
         Ok(())
     }
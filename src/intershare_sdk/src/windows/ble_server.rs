@@ -1,4 +1,4 @@
-use crate::nearby_server::InternalNearbyServer;
+use crate::nearby_server::{discovery_message, InternalNearbyServer};
 use crate::{BLE_DISCOVERY_CHARACTERISTIC_UUID, BLE_SERVICE_UUID};
 use log::{error, info, warn};
 use protocol::discovery::device_discovery_message::Content;
@@ -51,6 +51,7 @@ impl InternalNearbyServer {
             .get()?;
 
         let gatt_characteristic = characteristic_result.Characteristic()?;
+        let advertise = self.advertise.clone();
 
         let read_requested_handler = TypedEventHandler::new(
             move |_sender: &Option<GattLocalCharacteristic>,
@@ -59,12 +60,8 @@ impl InternalNearbyServer {
                     let deferral = args.GetDeferral()?;
                     let request: GattReadRequest = args.GetRequestAsync()?.get()?;
 
-                    let value = DeviceDiscoveryMessage {
-                        content: Some(Content::DeviceConnectionInfo(
-                            device_connection_info.clone(),
-                        )),
-                    }
-                    .encode_length_delimited_to_vec();
+                    let value =
+                        discovery_message(*advertise.blocking_read(), &device_connection_info);
 
                     let writer = DataWriter::new()?;
                     writer.WriteBytes(&value)?;
@@ -138,18 +135,19 @@ impl InternalNearbyServer {
             MAX_ADVERTISING_RETRIES
         );
     }
+}
 
-    pub(crate) fn stop_windows_server(&self) {
-        let gatt_service_provider = self
-            .gatt_service_provider
-            .read()
-            .expect("Failed to lock GattServiceProvider");
-
-        if let Some(gatt_service_provider) = gatt_service_provider.as_ref() {
-            match gatt_service_provider.StopAdvertising() {
-                Ok(_) => info!("Successfully stopped BLE advertising"),
-                Err(e) => error!("Failed to stop advertising: {:?}", e),
-            }
+pub(crate) fn stop_windows_server(
+    gatt_service_provider: &std::sync::RwLock<Option<GattServiceProvider>>,
+) {
+    let gatt_service_provider = gatt_service_provider
+        .read()
+        .expect("Failed to lock GattServiceProvider");
+
+    if let Some(gatt_service_provider) = gatt_service_provider.as_ref() {
+        match gatt_service_provider.StopAdvertising() {
+            Ok(_) => info!("Successfully stopped BLE advertising"),
+            Err(e) => error!("Failed to stop advertising: {:?}", e),
         }
     }
 }
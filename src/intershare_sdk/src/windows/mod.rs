@@ -1,2 +1,2 @@
 mod ble_client;
-mod ble_server;
+pub(crate) mod ble_server;
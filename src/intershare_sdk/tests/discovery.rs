@@ -0,0 +1,184 @@
+use intershare_sdk::discovery::{DeviceListUpdateDelegate, DiscoveryContext, InternalDiscovery};
+use intershare_sdk::protocol::discovery::device_discovery_message::Content;
+use intershare_sdk::protocol::discovery::{Device, DeviceConnectionInfo, DeviceDiscoveryMessage};
+use intershare_sdk::protocol::prost::Message;
+use intershare_sdk::{ConnectionRequest, InternalNearbyServer, NearbyConnectionDelegate};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Records the device list updates it receives.
+#[derive(Debug, Default, Clone)]
+struct DeviceListRecorder {
+    added: Arc<Mutex<Vec<String>>>,
+    removed: Arc<Mutex<Vec<String>>>,
+}
+
+impl DeviceListUpdateDelegate for DeviceListRecorder {
+    fn device_added(&self, value: Device) {
+        self.added.lock().unwrap().push(value.id);
+    }
+
+    fn device_removed(&self, device_id: String) {
+        self.removed.lock().unwrap().push(device_id);
+    }
+}
+
+#[derive(Debug)]
+struct IgnoreRequests;
+
+impl NearbyConnectionDelegate for IgnoreRequests {
+    fn received_connection_request(&self, _request: Arc<ConnectionRequest>) {}
+}
+
+fn discovery_message(device_id: &str) -> Vec<u8> {
+    let message = DeviceDiscoveryMessage {
+        content: Some(Content::DeviceConnectionInfo(DeviceConnectionInfo {
+            device: Some(Device {
+                id: device_id.to_string(),
+                name: "Test device".to_string(),
+                device_type: 0,
+                protocol_version: None,
+            }),
+            tcp: None,
+            ble: None,
+        })),
+    };
+
+    return message.encode_length_delimited_to_vec();
+}
+
+#[test]
+pub fn discovery_device_ttl() {
+    let recorder = DeviceListRecorder::default();
+    let discovery =
+        InternalDiscovery::with_context(Some(Box::new(recorder.clone())), DiscoveryContext::new())
+            .unwrap();
+
+    discovery.clone().parse_discovery_message_with_rssi(
+        discovery_message("device"),
+        Some("ble-uuid".to_string()),
+        Some(-60),
+    );
+
+    let discovered_devices = discovery.clone().get_discovered_devices();
+    assert_eq!(discovered_devices.len(), 1);
+    assert_eq!(discovered_devices[0].rssi, Some(-60));
+    assert_eq!(*recorder.added.lock().unwrap(), vec!["device".to_string()]);
+
+    // Devices don't expire until a TTL is set.
+    thread::sleep(Duration::from_millis(10));
+    discovery.clone().expire_devices();
+    assert_eq!(discovery.clone().get_devices().len(), 1);
+    assert!(recorder.removed.lock().unwrap().is_empty());
+
+    discovery.clone().set_device_ttl(0);
+    discovery.clone().expire_devices();
+
+    assert!(discovery.clone().get_devices().is_empty());
+    assert_eq!(
+        *recorder.removed.lock().unwrap(),
+        vec!["device".to_string()]
+    );
+}
+
+#[test]
+pub fn discovery_contexts_are_separate() {
+    let first_recorder = DeviceListRecorder::default();
+    let second_recorder = DeviceListRecorder::default();
+    let first_context = DiscoveryContext::new();
+    let second_context = DiscoveryContext::new();
+
+    let first_discovery = InternalDiscovery::with_context(
+        Some(Box::new(first_recorder.clone())),
+        first_context.clone(),
+    )
+    .unwrap();
+    let second_discovery = InternalDiscovery::with_context(
+        Some(Box::new(second_recorder.clone())),
+        second_context.clone(),
+    )
+    .unwrap();
+
+    first_discovery.parse_discovery_message(discovery_message("first"), None);
+    second_discovery.parse_discovery_message(discovery_message("second"), None);
+
+    assert!(first_context.get_connection_details("first").is_some());
+    assert!(first_context.get_connection_details("second").is_none());
+    assert!(second_context.get_connection_details("second").is_some());
+    assert!(second_context.get_connection_details("first").is_none());
+
+    assert_eq!(
+        *first_recorder.added.lock().unwrap(),
+        vec!["first".to_string()]
+    );
+    assert_eq!(
+        *second_recorder.added.lock().unwrap(),
+        vec!["second".to_string()]
+    );
+}
+
+#[test]
+pub fn discovery_offline_announcement() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+    let file_storage = TempDir::new().unwrap();
+    let server = InternalNearbyServer::new(
+        Device {
+            id: "device".to_string(),
+            name: "Test device".to_string(),
+            device_type: 0,
+            protocol_version: None,
+        },
+        file_storage.path().to_string_lossy().to_string(),
+        Some(Box::new(IgnoreRequests)),
+    );
+
+    let recorder = DeviceListRecorder::default();
+    let discovery =
+        InternalDiscovery::with_context(Some(Box::new(recorder.clone())), DiscoveryContext::new())
+            .unwrap();
+
+    runtime.block_on(server.start());
+    let advertisement_data = runtime.block_on(server.get_advertisement_data());
+    discovery
+        .clone()
+        .parse_discovery_message(advertisement_data, None);
+    assert_eq!(discovery.clone().get_devices().len(), 1);
+
+    // Scanners reading the device after it stopped are told that it went offline.
+    runtime.block_on(server.stop());
+    let advertisement_data = runtime.block_on(server.get_advertisement_data());
+    let message =
+        DeviceDiscoveryMessage::decode_length_delimited(advertisement_data.as_slice()).unwrap();
+    assert_eq!(
+        message.content,
+        Some(Content::OfflineDeviceId("device".to_string()))
+    );
+
+    // Reported once, although the device keeps announcing it.
+    discovery
+        .clone()
+        .parse_discovery_message(advertisement_data.clone(), None);
+    discovery
+        .clone()
+        .parse_discovery_message(advertisement_data, None);
+
+    assert!(discovery.clone().get_devices().is_empty());
+    assert_eq!(
+        *recorder.removed.lock().unwrap(),
+        vec!["device".to_string()]
+    );
+
+    // Starting again advertises the device right away.
+    runtime.block_on(server.start());
+    let advertisement_data = runtime.block_on(server.get_advertisement_data());
+    discovery.parse_discovery_message(advertisement_data, None);
+    assert_eq!(recorder.added.lock().unwrap().len(), 2);
+
+    runtime.block_on(server.stop());
+}
@@ -1,8 +1,28 @@
 use crate::helper::MemoryStream;
 use chacha20::cipher::StreamCipherSeek;
-use intershare_sdk::encryption::{generate_iv, generate_key, EncryptedStream};
+use intershare_sdk::communication::{
+    initiate_receiver_communication, initiate_sender_communication, is_downgrade,
+    negotiate_protocol_version,
+};
+use intershare_sdk::encryption::{
+    derive_session_keys, generate_iv, generate_key, generate_verification_nonce,
+    handshake_transcript, verification_code, verification_commitment, AuthenticatedStream,
+    EncryptedReadWrite, EncryptedStream, HandshakeRole, SessionKeys, STREAM_NONCE_LENGTH,
+};
+use intershare_sdk::errors::{peer_cancel_reason, StreamIntegrityError};
+use intershare_sdk::identity::{DeviceTrust, IdentityStore, IdentityStoreDelegate};
+use intershare_sdk::protocol::communication::{
+    CancelReason, EncryptionMode, EncryptionRequest, EncryptionResponse,
+};
+use intershare_sdk::stream::Close;
+use intershare_sdk::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use prost_stream::Stream;
 use rand_core::{OsRng, RngCore};
+use std::collections::HashMap;
 use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::thread;
 use x25519_dalek::{EphemeralSecret, PublicKey};
 
 mod helper;
@@ -53,7 +73,7 @@ pub fn stream_encryption() {
     let write_data = &vec![1, 2, 3];
 
     encrypted_stream
-        .write(write_data)
+        .write_all(write_data)
         .expect("Something went wrong, while trying to write to EncryptedStream");
 
     encrypted_stream.raw_stream.set_position(0);
@@ -61,7 +81,7 @@ pub fn stream_encryption() {
 
     let mut decrypted = [0u8; 3];
     encrypted_stream
-        .read(&mut decrypted)
+        .read_exact(&mut decrypted)
         .expect("Error decrypting memory_stream");
 
     assert_eq!(write_data, &decrypted);
@@ -106,3 +126,470 @@ pub fn large_stream_encryption() {
 
     assert_eq!(write_data, &decrypted_buffer[..read_bytes]);
 }
+
+impl Close for MemoryStream {
+    fn close(&self) {}
+}
+
+/// Keys which read back what was written, so a single `MemoryStream` can be used for both directions.
+fn loopback_session_keys() -> SessionKeys {
+    let key = generate_key();
+    let mut nonce = [0u8; STREAM_NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+
+    return SessionKeys {
+        send_key: key,
+        send_nonce: nonce,
+        receive_key: key,
+        receive_nonce: nonce,
+    };
+}
+
+fn integrity_error(error: &std::io::Error) -> Option<&StreamIntegrityError> {
+    error.get_ref()?.downcast_ref::<StreamIntegrityError>()
+}
+
+#[test]
+pub fn authenticated_stream_encryption() {
+    let session_keys = loopback_session_keys();
+    let mut authenticated_stream = AuthenticatedStream::new(&session_keys, MemoryStream::new());
+
+    let mut write_data: [u8; 100000] = [0; 100000];
+    OsRng.fill_bytes(&mut write_data);
+
+    authenticated_stream
+        .write_all(&write_data)
+        .expect("Something went wrong, while trying to write to AuthenticatedStream");
+    authenticated_stream
+        .finish()
+        .expect("Failed to finish AuthenticatedStream");
+
+    authenticated_stream.raw_stream.set_position(0);
+
+    let mut decrypted = Vec::new();
+    authenticated_stream
+        .read_to_end(&mut decrypted)
+        .expect("Something went wrong, while trying to decrypt the stream");
+
+    assert_eq!(write_data.as_slice(), decrypted.as_slice());
+}
+
+#[test]
+pub fn authenticated_stream_detects_tampering() {
+    let session_keys = loopback_session_keys();
+    let mut authenticated_stream = AuthenticatedStream::new(&session_keys, MemoryStream::new());
+
+    authenticated_stream
+        .write_all(&[1, 2, 3, 4, 5, 6])
+        .expect("Failed to write to AuthenticatedStream");
+
+    authenticated_stream.raw_stream.set_position(0);
+    let mut frame = Vec::new();
+    authenticated_stream
+        .raw_stream
+        .read_to_end(&mut frame)
+        .expect("Error reading memory_stream");
+
+    // Flip a single bit of the ciphertext
+    frame[5] ^= 0b0000_0001;
+
+    let mut tampered_stream = MemoryStream::new();
+    tampered_stream
+        .write_all(&frame)
+        .expect("Failed to write memory_stream");
+    tampered_stream.set_position(0);
+
+    let mut authenticated_stream = AuthenticatedStream::new(&session_keys, tampered_stream);
+    let mut decrypted = [0u8; 6];
+    let error = authenticated_stream
+        .read(&mut decrypted)
+        .expect_err("Tampered frame was accepted");
+
+    assert!(matches!(
+        integrity_error(&error),
+        Some(StreamIntegrityError::AuthenticationFailed)
+    ));
+}
+
+#[test]
+pub fn authenticated_stream_detects_truncation() {
+    let session_keys = loopback_session_keys();
+    let mut authenticated_stream = AuthenticatedStream::new(&session_keys, MemoryStream::new());
+
+    authenticated_stream
+        .write_all(&[1, 2, 3])
+        .expect("Failed to write to AuthenticatedStream");
+
+    // The stream ends without the final frame
+    authenticated_stream.raw_stream.set_position(0);
+
+    let mut decrypted = Vec::new();
+    let error = authenticated_stream
+        .read_to_end(&mut decrypted)
+        .expect_err("Truncated stream was accepted");
+
+    assert_eq!(decrypted, vec![1, 2, 3]);
+    assert!(matches!(
+        integrity_error(&error),
+        Some(StreamIntegrityError::Truncated)
+    ));
+}
+
+#[test]
+pub fn authenticated_stream_cancel() {
+    let session_keys = loopback_session_keys();
+    let mut authenticated_stream = AuthenticatedStream::new(&session_keys, MemoryStream::new());
+
+    authenticated_stream
+        .write_all(&[1, 2, 3])
+        .expect("Failed to write to AuthenticatedStream");
+    authenticated_stream
+        .cancel(CancelReason::UserCancelled)
+        .expect("Failed to cancel AuthenticatedStream");
+
+    assert!(authenticated_stream.write_all(&[4]).is_err());
+
+    authenticated_stream.raw_stream.set_position(0);
+
+    let mut decrypted = Vec::new();
+    let error = authenticated_stream
+        .read_to_end(&mut decrypted)
+        .expect_err("Cancelled stream was read to the end");
+
+    assert_eq!(decrypted, vec![1, 2, 3]);
+    assert_eq!(
+        peer_cancel_reason(&error),
+        Some(CancelReason::UserCancelled)
+    );
+
+    // Every following read reports the cancel again
+    let error = authenticated_stream
+        .read(&mut [0u8; 1])
+        .expect_err("Read after cancel succeeded");
+
+    assert_eq!(
+        peer_cancel_reason(&error),
+        Some(CancelReason::UserCancelled)
+    );
+}
+
+fn handshake_session_keys() -> (SessionKeys, SessionKeys) {
+    let initiator_secret = EphemeralSecret::random_from_rng(OsRng);
+    let initiator_public_key = PublicKey::from(&initiator_secret);
+
+    let responder_secret = EphemeralSecret::random_from_rng(OsRng);
+    let responder_public_key = PublicKey::from(&responder_secret);
+
+    let iv = generate_iv();
+    let transcript = handshake_transcript(
+        initiator_public_key.as_bytes(),
+        responder_public_key.as_bytes(),
+        &iv,
+        1,
+    );
+
+    let initiator_shared_secret = initiator_secret.diffie_hellman(&responder_public_key);
+    let responder_shared_secret = responder_secret.diffie_hellman(&initiator_public_key);
+
+    return (
+        derive_session_keys(
+            initiator_shared_secret.as_bytes(),
+            &transcript,
+            HandshakeRole::Initiator,
+        ),
+        derive_session_keys(
+            responder_shared_secret.as_bytes(),
+            &transcript,
+            HandshakeRole::Responder,
+        ),
+    );
+}
+
+#[test]
+pub fn session_keys_per_direction() {
+    let (initiator, responder) = handshake_session_keys();
+
+    assert_eq!(initiator.send_key, responder.receive_key);
+    assert_eq!(initiator.send_nonce, responder.receive_nonce);
+    assert_eq!(initiator.receive_key, responder.send_key);
+    assert_eq!(initiator.receive_nonce, responder.send_nonce);
+
+    assert_ne!(initiator.send_key, initiator.receive_key);
+    assert_ne!(initiator.send_nonce, initiator.receive_nonce);
+}
+
+#[test]
+pub fn session_keys_depend_on_transcript() {
+    let shared_secret = generate_key();
+    let iv = generate_iv();
+
+    let transcript = handshake_transcript(&[1; 32], &[2; 32], &iv, 1);
+    let swapped_transcript = handshake_transcript(&[2; 32], &[1; 32], &iv, 1);
+
+    let session_keys = derive_session_keys(&shared_secret, &transcript, HandshakeRole::Initiator);
+    let swapped_session_keys = derive_session_keys(
+        &shared_secret,
+        &swapped_transcript,
+        HandshakeRole::Initiator,
+    );
+
+    assert_ne!(session_keys.send_key, swapped_session_keys.send_key);
+    assert_ne!(session_keys.receive_key, swapped_session_keys.receive_key);
+}
+
+#[test]
+pub fn session_directions_never_share_keystream() {
+    let (initiator, responder) = handshake_session_keys();
+
+    let mut initiator_stream = AuthenticatedStream::new(&initiator, MemoryStream::new());
+    let mut responder_stream = AuthenticatedStream::new(&responder, MemoryStream::new());
+
+    // Encrypting zeros exposes the raw keystream of each direction
+    let plaintext = [0u8; 4096];
+
+    initiator_stream
+        .write_all(&plaintext)
+        .expect("Failed to write initiator stream");
+    responder_stream
+        .write_all(&plaintext)
+        .expect("Failed to write responder stream");
+
+    initiator_stream.raw_stream.set_position(0);
+    responder_stream.raw_stream.set_position(0);
+
+    let mut initiator_ciphertext = Vec::new();
+    initiator_stream
+        .raw_stream
+        .read_to_end(&mut initiator_ciphertext)
+        .expect("Error reading memory_stream");
+
+    let mut responder_ciphertext = Vec::new();
+    responder_stream
+        .raw_stream
+        .read_to_end(&mut responder_ciphertext)
+        .expect("Error reading memory_stream");
+
+    // Skip the unencrypted frame headers
+    let initiator_keystream = &initiator_ciphertext[4..4 + plaintext.len()];
+    let responder_keystream = &responder_ciphertext[4..4 + plaintext.len()];
+
+    assert_ne!(initiator_keystream, plaintext.as_slice());
+    assert!(initiator_keystream
+        .windows(16)
+        .all(|window| !responder_keystream.windows(16).any(|other| other == window)));
+
+    // Each side can decrypt what the other one sent
+    let mut received_by_responder = AuthenticatedStream::new(&responder, MemoryStream::new());
+    received_by_responder
+        .raw_stream
+        .write_all(&initiator_ciphertext)
+        .expect("Failed to write memory_stream");
+    received_by_responder.raw_stream.set_position(0);
+
+    let mut decrypted = [1u8; 4096];
+    received_by_responder
+        .read_exact(&mut decrypted)
+        .expect("Responder failed to decrypt initiator frame");
+
+    assert_eq!(decrypted, plaintext);
+
+    // ...but not what it sent itself
+    let mut received_own = AuthenticatedStream::new(&initiator, MemoryStream::new());
+    received_own
+        .raw_stream
+        .write_all(&initiator_ciphertext)
+        .expect("Failed to write memory_stream");
+    received_own.raw_stream.set_position(0);
+
+    let error = received_own
+        .read_exact(&mut decrypted)
+        .expect_err("Initiator decrypted its own frame");
+
+    assert!(matches!(
+        integrity_error(&error),
+        Some(StreamIntegrityError::AuthenticationFailed)
+    ));
+}
+
+#[test]
+pub fn verification_code_format() {
+    let transcript = handshake_transcript(&[1; 32], &[2; 32], &generate_iv(), 1);
+    let initiator_nonce = generate_verification_nonce();
+    let responder_nonce = generate_verification_nonce();
+
+    let code = verification_code(&transcript, &initiator_nonce, &responder_nonce);
+
+    assert_eq!(code.len(), 6);
+    assert!(code.chars().all(|character| character.is_ascii_digit()));
+    assert_eq!(
+        code,
+        verification_code(&transcript, &initiator_nonce, &responder_nonce)
+    );
+
+    assert_ne!(
+        verification_commitment(&transcript, &responder_nonce),
+        verification_commitment(&transcript, &initiator_nonce)
+    );
+}
+
+#[tokio::test]
+pub async fn handshake_verification_code() {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind listener");
+    let address = listener.local_addr().expect("Failed to get local address");
+
+    let sender_identity = IdentityStore::new(None);
+    let receiver_identity = IdentityStore::new(None);
+    let sender_public_key = sender_identity.public_key();
+    let receiver_public_key = receiver_identity.public_key();
+
+    let receiver = thread::spawn(move || {
+        let (tcp_stream, _) = listener.accept().expect("Failed to accept connection");
+        let mut connection = initiate_receiver_communication(tcp_stream, &receiver_identity)
+            .expect("Receiver handshake failed");
+
+        let mut received = [0u8; 5];
+        connection
+            .stream
+            .read_exact(&mut received)
+            .expect("Failed to read from sender");
+
+        return (
+            connection.verification_code,
+            connection.peer_identity_key,
+            received,
+        );
+    });
+
+    let tcp_stream = TcpStream::connect(address).expect("Failed to connect");
+    let mut connection = initiate_sender_communication(tcp_stream, &sender_identity)
+        .await
+        .expect("Sender handshake failed");
+
+    connection
+        .stream
+        .write_all(b"hello")
+        .expect("Failed to write to receiver");
+
+    let (receiver_code, receiver_peer_key, received) =
+        receiver.join().expect("Receiver thread panicked");
+
+    assert!(connection.verification_code.is_some());
+    assert_eq!(connection.verification_code, receiver_code);
+    assert_eq!(connection.peer_identity_key, Some(receiver_public_key));
+    assert_eq!(receiver_peer_key, Some(sender_public_key));
+    assert_eq!(&received, b"hello");
+}
+
+#[test]
+pub fn protocol_version_negotiation() {
+    assert_eq!(
+        negotiate_protocol_version(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION),
+        Some(PROTOCOL_VERSION)
+    );
+    assert_eq!(
+        negotiate_protocol_version(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION + 1),
+        Some(PROTOCOL_VERSION)
+    );
+    assert_eq!(
+        negotiate_protocol_version(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2),
+        None
+    );
+}
+
+#[derive(Debug, Default)]
+struct MemoryIdentityStore {
+    identity_key: Mutex<Option<Vec<u8>>>,
+    trusted_devices: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl IdentityStoreDelegate for MemoryIdentityStore {
+    fn load_identity_key(&self) -> Option<Vec<u8>> {
+        return self.identity_key.lock().unwrap().clone();
+    }
+
+    fn store_identity_key(&self, private_key: Vec<u8>) {
+        *self.identity_key.lock().unwrap() = Some(private_key);
+    }
+
+    fn load_trusted_device_key(&self, device_id: String) -> Option<Vec<u8>> {
+        return self
+            .trusted_devices
+            .lock()
+            .unwrap()
+            .get(&device_id)
+            .cloned();
+    }
+
+    fn store_trusted_device_key(&self, device_id: String, public_key: Vec<u8>) {
+        self.trusted_devices
+            .lock()
+            .unwrap()
+            .insert(device_id, public_key);
+    }
+}
+
+#[test]
+pub fn identity_trust_on_first_use() {
+    let identity = IdentityStore::new(Some(Box::new(MemoryIdentityStore::default())));
+    let known_key = IdentityStore::new(None).public_key();
+    let other_key = IdentityStore::new(None).public_key();
+
+    assert_eq!(identity.get_trust("device", None), DeviceTrust::Unverified);
+    assert_eq!(
+        identity.get_trust("device", Some(&known_key)),
+        DeviceTrust::New
+    );
+
+    identity.pin("device", &known_key);
+
+    assert_eq!(
+        identity.get_trust("device", Some(&known_key)),
+        DeviceTrust::Known
+    );
+    assert_eq!(
+        identity.get_trust("device", Some(&other_key)),
+        DeviceTrust::KeyChanged
+    );
+    assert_eq!(identity.get_trust("device", None), DeviceTrust::KeyChanged);
+}
+
+#[test]
+pub fn stripped_encryption_modes_are_a_downgrade() {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind listener");
+    let address = listener.local_addr().expect("Failed to get local address");
+
+    let receiver_identity = IdentityStore::new(Some(Box::new(MemoryIdentityStore::default())));
+    receiver_identity.pin("known-device", &IdentityStore::new(None).public_key());
+
+    let receiver = thread::spawn(move || {
+        let (tcp_stream, _) = listener.accept().expect("Failed to accept connection");
+        let connection = initiate_receiver_communication(tcp_stream, &receiver_identity)
+            .expect("Receiver handshake failed");
+
+        return (
+            is_downgrade(&connection, &receiver_identity, "known-device"),
+            is_downgrade(&connection, &receiver_identity, "other-device"),
+        );
+    });
+
+    // What a man-in-the-middle forwards after removing the supported modes.
+    let mut tcp_stream = TcpStream::connect(address).expect("Failed to connect");
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let mut prost_stream = Stream::new(&mut tcp_stream);
+
+    let _ = prost_stream.send(&EncryptionRequest {
+        public_key: PublicKey::from(&secret).as_bytes().to_vec(),
+        supported_modes: vec![],
+    });
+
+    let response = prost_stream
+        .recv::<EncryptionResponse>()
+        .expect("Failed to receive EncryptionResponse");
+
+    let (known_device_downgraded, other_device_downgraded) =
+        receiver.join().expect("Receiver thread panicked");
+
+    assert_eq!(response.mode(), EncryptionMode::Xchacha20);
+    assert!(known_device_downgraded);
+    assert!(!other_device_downgraded);
+}
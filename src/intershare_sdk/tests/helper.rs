@@ -1,5 +1,6 @@
 use std::io::{Cursor, Read, Write};
 
+#[derive(Default)]
 pub struct MemoryStream {
     last_written_byte_length: usize,
     cursor: Cursor<Vec<u8>>,
@@ -50,7 +51,7 @@ pub fn memory_stream() {
     let mut memory_stream = MemoryStream::new();
 
     memory_stream
-        .write(&[4u8, 5u8, 6u8])
+        .write_all(&[4u8, 5u8, 6u8])
         .expect("Failed to write memory_stream");
 
     memory_stream.set_position(0);
@@ -65,7 +66,7 @@ pub fn memory_stream() {
     // ====
 
     memory_stream
-        .write(&[2u8, 7u8, 9u8])
+        .write_all(&[2u8, 7u8, 9u8])
         .expect("Failed to write memory_stream");
     memory_stream
         .set_position(memory_stream.position() - memory_stream.last_written_byte_length as u64);
@@ -80,11 +81,11 @@ pub fn memory_stream() {
     // ====
 
     memory_stream
-        .write(&[2u8, 7u8, 9u8])
+        .write_all(&[2u8, 7u8, 9u8])
         .expect("Failed to write memory_stream");
 
     memory_stream
-        .write(&[1u8, 2u8, 0u8])
+        .write_all(&[1u8, 2u8, 0u8])
         .expect("Failed to write memory_stream");
 
     memory_stream.set_position(memory_stream.position() - 6);
@@ -0,0 +1,143 @@
+use intershare_sdk::nearby_server::InternalNearbyServer;
+use intershare_sdk::protocol::discovery::Device;
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::net::TcpStream;
+use tempfile::TempDir;
+use url::Url;
+use zip::ZipArchive;
+
+fn nearby_server(file_storage: &TempDir) -> InternalNearbyServer {
+    let device = Device {
+        id: "7d3bd4b0-41b2-4d43-a2ad-1c0b7a6a3e0f".to_string(),
+        name: "Test device".to_string(),
+        device_type: 0,
+        protocol_version: None,
+    };
+
+    return InternalNearbyServer::new(
+        device,
+        file_storage.path().to_string_lossy().to_string(),
+        None,
+    );
+}
+
+/// Sends a `GET` request to the server of `link`, returns the head and the body of the response.
+fn get(link: &str, path: &str) -> (String, Vec<u8>) {
+    return get_from("127.0.0.1", link, path);
+}
+
+/// Like `get`, connecting to the port of `link` on `host`.
+fn get_from(host: &str, link: &str, path: &str) -> (String, Vec<u8>) {
+    let link = Url::parse(link).unwrap();
+    let mut tcp_stream = TcpStream::connect((host, link.port().unwrap())).unwrap();
+
+    write!(
+        tcp_stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    )
+    .unwrap();
+
+    let mut response = Vec::new();
+    tcp_stream.read_to_end(&mut response).unwrap();
+
+    let head_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .unwrap();
+    let body = response.split_off(head_end + 4);
+
+    return (String::from_utf8_lossy(&response).into_owned(), body);
+}
+
+#[tokio::test]
+pub async fn http_landing_page_and_download() {
+    let file_storage = TempDir::new().unwrap();
+    let source_dir = TempDir::new().unwrap();
+    let server = nearby_server(&file_storage);
+    server.start_http_server().await.unwrap();
+
+    let first_path = source_dir.path().join("a").join("x.txt");
+    let second_path = source_dir.path().join("b").join("x.txt");
+    fs::create_dir_all(first_path.parent().unwrap()).unwrap();
+    fs::create_dir_all(second_path.parent().unwrap()).unwrap();
+    fs::write(&first_path, b"first").unwrap();
+    fs::write(&second_path, b"second").unwrap();
+
+    let share_store = server
+        .share_files(
+            vec![
+                first_path.to_string_lossy().to_string(),
+                second_path.to_string_lossy().to_string(),
+            ],
+            true,
+        )
+        .await;
+    let link = server
+        .generate_http_link(share_store.request_id.clone())
+        .await
+        .unwrap();
+    let share_path = Url::parse(&link).unwrap().path().to_string();
+
+    let (head, body) = get(&link, &share_path);
+    let body = String::from_utf8(body).unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert!(body.contains("x.txt"));
+    assert!(body.contains("x (1).txt"));
+    assert!(body.contains(&format!("{}/download", share_path)));
+
+    let (head, body) = get(&link, &format!("{}/download", share_path));
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert!(head.contains("Content-Type: application/zip"));
+
+    let mut archive = ZipArchive::new(Cursor::new(body)).unwrap();
+    let mut entries = Vec::new();
+
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index).unwrap();
+        let mut content = String::new();
+        entry.read_to_string(&mut content).unwrap();
+        entries.push((entry.name().to_string(), content));
+    }
+
+    assert_eq!(
+        entries,
+        vec![
+            ("x.txt".to_string(), "first".to_string()),
+            ("x (1).txt".to_string(), "second".to_string()),
+        ]
+    );
+
+    let (head, _) = get(&link, "/unknown");
+    assert!(head.starts_with("HTTP/1.1 404 Not Found"));
+
+    server.stop_http_server().await;
+}
+
+#[tokio::test]
+pub async fn http_server_accepts_ipv4_and_ipv6() {
+    let file_storage = TempDir::new().unwrap();
+    let source_dir = TempDir::new().unwrap();
+    let server = nearby_server(&file_storage);
+    server.start_http_server().await.unwrap();
+
+    let source_path = source_dir.path().join("x.txt");
+    fs::write(&source_path, b"first").unwrap();
+
+    let share_store = server
+        .share_files(vec![source_path.to_string_lossy().to_string()], true)
+        .await;
+    let link = server
+        .generate_http_link(share_store.request_id.clone())
+        .await
+        .unwrap();
+    let share_path = Url::parse(&link).unwrap().path().to_string();
+
+    for host in ["127.0.0.1", "::1"] {
+        let (head, _) = get_from(host, &link, &share_path);
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    server.stop_http_server().await;
+}
@@ -0,0 +1,209 @@
+use intershare_sdk::transmission::http::HttpUpload;
+use intershare_sdk::transmission::multipart::MultipartReader;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::sync::atomic::AtomicBool;
+use tempfile::TempDir;
+
+const BOUNDARY: &str = "----InterShareBoundary";
+
+/// A browser connection which sends the request in small chunks.
+struct BrowserStream {
+    request: Vec<u8>,
+    position: usize,
+    read_size: usize,
+    response: Vec<u8>,
+}
+
+impl BrowserStream {
+    fn new(request: Vec<u8>, read_size: usize) -> Self {
+        return Self {
+            request,
+            position: 0,
+            read_size,
+            response: Vec::new(),
+        };
+    }
+}
+
+impl Read for BrowserStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_bytes = buf
+            .len()
+            .min(self.read_size)
+            .min(self.request.len() - self.position);
+
+        buf[..read_bytes].copy_from_slice(&self.request[self.position..self.position + read_bytes]);
+        self.position += read_bytes;
+
+        return Ok(read_bytes);
+    }
+}
+
+impl Write for BrowserStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.response.extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+/// A form as the upload page sends it, without the closing delimiter.
+fn form_body(manifest: Option<&str>, files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    if let Some(manifest) = manifest {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"manifest\"\r\n\r\n{}\r\n",
+                BOUNDARY, manifest
+            )
+            .as_bytes(),
+        );
+    }
+
+    for (file_name, content) in files {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"files\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                BOUNDARY, file_name
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(content);
+        body.extend_from_slice(b"\r\n");
+    }
+
+    return body;
+}
+
+fn close_form(mut body: Vec<u8>) -> Vec<u8> {
+    body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+    return body;
+}
+
+fn manifest_line(file_name: &str, size: usize) -> String {
+    return format!("name={}&size={}", file_name, size);
+}
+
+fn browser_upload(body: Vec<u8>, read_size: usize) -> HttpUpload<BrowserStream> {
+    let content_length = body.len() as u64;
+    return HttpUpload::new(
+        BrowserStream::new(body, read_size),
+        BOUNDARY,
+        Vec::new(),
+        content_length,
+    );
+}
+
+#[test]
+pub fn multipart_boundary_split_across_reads() {
+    // Looks like the start of a boundary, but isn't one.
+    let content = format!("first line\r\n--{}X\r\nlast line", &BOUNDARY[..10]);
+    let body = close_form(form_body(
+        None,
+        &[("a.txt", content.as_bytes()), ("b.txt", b"second")],
+    ));
+
+    for read_size in 1..=3 {
+        let mut stream = BrowserStream::new(body.clone(), read_size);
+        let mut multipart = MultipartReader::new(BOUNDARY, Vec::new());
+        multipart.skip_preamble(&mut stream).unwrap();
+
+        let mut parts = Vec::new();
+
+        while let Some(part) = multipart.next_part(&mut stream).unwrap() {
+            let mut part_content = Vec::new();
+            multipart.copy_part(&mut stream, &mut part_content).unwrap();
+            parts.push((part.file_name.unwrap(), part_content));
+        }
+
+        assert_eq!(
+            parts,
+            vec![
+                ("a.txt".to_string(), content.as_bytes().to_vec()),
+                ("b.txt".to_string(), b"second".to_vec()),
+            ]
+        );
+    }
+}
+
+#[test]
+pub fn multipart_missing_closing_delimiter() {
+    let dest_dir = TempDir::new().unwrap();
+    let manifest = manifest_line("a.txt", 5);
+    let body = form_body(Some(&manifest), &[("a.txt", b"hello")]);
+
+    let mut upload = browser_upload(body, 3);
+    upload.read_manifest().unwrap();
+
+    let result = upload.receive(dest_dir.path(), &[], |_| {}, &AtomicBool::new(false));
+
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    assert_eq!(fs::read_dir(dest_dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+pub fn multipart_oversized_manifest() {
+    let manifest = manifest_line("a.txt", 5).repeat(100_000);
+    let body = close_form(form_body(Some(&manifest), &[("a.txt", b"hello")]));
+
+    let error = browser_upload(body, 64 * 1024).read_manifest().unwrap_err();
+
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+pub fn multipart_selective_accept() {
+    let dest_dir = TempDir::new().unwrap();
+    let files: [(&str, &[u8]); 3] = [
+        ("a.txt", b"first"),
+        ("b.txt", b"second"),
+        ("c.txt", b"third"),
+    ];
+    let manifest = files
+        .iter()
+        .map(|(file_name, content)| manifest_line(file_name, content.len()))
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    let mut upload = browser_upload(close_form(form_body(Some(&manifest), &files)), 7);
+    assert_eq!(upload.read_manifest().unwrap().len(), 3);
+
+    let received_files = upload
+        .receive(dest_dir.path(), &[0, 2], |_| {}, &AtomicBool::new(false))
+        .unwrap();
+
+    assert_eq!(received_files.len(), 2);
+    assert_eq!(fs::read(&received_files[0]).unwrap(), b"first");
+    assert_eq!(fs::read(&received_files[1]).unwrap(), b"third");
+    assert!(!dest_dir.path().join("b.txt").exists());
+}
+
+#[test]
+pub fn multipart_files_must_match_manifest() {
+    let dest_dir = TempDir::new().unwrap();
+    let manifest = [manifest_line("a.txt", 5), manifest_line("b.txt", 6)].join("\n");
+
+    // A file which wasn't announced.
+    let body = close_form(form_body(
+        Some(&manifest),
+        &[("a.txt", b"hello"), ("other.txt", b"second")],
+    ));
+    let mut upload = browser_upload(body, 64 * 1024);
+    upload.read_manifest().unwrap();
+    let result = upload.receive(dest_dir.path(), &[], |_| {}, &AtomicBool::new(false));
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    // Fewer files than announced.
+    let body = close_form(form_body(Some(&manifest), &[("a.txt", b"hello")]));
+    let mut upload = browser_upload(body, 64 * 1024);
+    upload.read_manifest().unwrap();
+    let result = upload.receive(dest_dir.path(), &[], |_| {}, &AtomicBool::new(false));
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    assert_eq!(fs::read_dir(dest_dir.path()).unwrap().count(), 0);
+}
@@ -0,0 +1,66 @@
+use intershare_sdk::qr_code::{
+    generate_qr_code, generate_qr_code_svg, QrCodeColor, QrCodeErrorCorrection, QrCodeModuleShape,
+    QrCodeOptions,
+};
+
+const LINK: &str = "https://s.intershare.app/?v=1&i=4b0f6b3e";
+
+const LOGO: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect width="10" height="10" fill="red"/></svg>"#;
+
+fn styled_options() -> QrCodeOptions {
+    return QrCodeOptions {
+        size: 512,
+        module_color: QrCodeColor {
+            red: 20,
+            green: 40,
+            blue: 200,
+            alpha: 255,
+        },
+        background_color: QrCodeColor::WHITE,
+        quiet_zone: 2,
+        error_correction: QrCodeErrorCorrection::High,
+        shape: QrCodeModuleShape::RoundedSquare,
+        logo: Some(LOGO.to_vec()),
+    };
+}
+
+#[test]
+pub fn qr_code_png_with_options() {
+    let png = generate_qr_code(LINK.to_string(), &styled_options()).unwrap();
+
+    assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+
+    // The width is the first field of the IHDR chunk.
+    let width = u32::from_be_bytes(png[16..20].try_into().unwrap());
+    assert_eq!(width, 512);
+}
+
+#[test]
+pub fn qr_code_png_rejects_zero_size() {
+    let options = QrCodeOptions {
+        size: 0,
+        ..QrCodeOptions::default()
+    };
+
+    assert_eq!(generate_qr_code(LINK.to_string(), &options), None);
+}
+
+#[test]
+pub fn qr_code_svg_with_options() {
+    let svg = generate_qr_code_svg(LINK.to_string(), &styled_options()).unwrap();
+
+    assert!(svg.starts_with("<svg"));
+    assert!(svg.contains("data:image/svg+xml;base64,"));
+
+    let svg_without_logo = generate_qr_code_svg(
+        LINK.to_string(),
+        &QrCodeOptions {
+            logo: None,
+            ..styled_options()
+        },
+    )
+    .unwrap();
+
+    assert!(!svg_without_logo.contains("data:image"));
+}
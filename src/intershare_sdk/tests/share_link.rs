@@ -0,0 +1,88 @@
+use intershare_sdk::share_link::{interface_addresses, key_fingerprint, ShareLink, ShareLinkError};
+use intershare_sdk::{TcpConnectionInfo, PROTOCOL_VERSION};
+use std::net::{IpAddr, SocketAddr};
+
+#[test]
+pub fn share_link_round_trip() {
+    let public_key = [7u8; 32];
+    let addresses: Vec<SocketAddr> = vec![
+        "192.168.12.13:5200".parse().unwrap(),
+        "[fd00::12]:5200".parse().unwrap(),
+    ];
+
+    let share_link = ShareLink::new(
+        "hgf8o47fdsb394mv385".to_string(),
+        addresses.clone(),
+        "9A403351-A926-4D1C-855F-432A6ED51E0E".to_string(),
+        &public_key,
+    );
+
+    let parsed = ShareLink::parse(&share_link.to_url()).unwrap();
+
+    assert_eq!(parsed, share_link);
+    assert_eq!(parsed.addresses, addresses);
+    assert_eq!(parsed.protocol_version, PROTOCOL_VERSION);
+    assert_eq!(parsed.key_fingerprint, Some(key_fingerprint(&public_key)));
+    assert!(parsed.matches_key(Some(&public_key)));
+    assert!(!parsed.matches_key(Some(&[8u8; 32])));
+    assert!(!parsed.matches_key(None));
+}
+
+#[test]
+pub fn share_link_parses_legacy_links() {
+    let parsed = ShareLink::parse(
+        "https://s.intershare.app?i=hgf8o47fdsb394mv385&ip=192.168.12.13&p=5200&d=9A403351",
+    )
+    .unwrap();
+
+    assert_eq!(parsed.share_id, "hgf8o47fdsb394mv385");
+    assert_eq!(
+        parsed.addresses,
+        vec!["192.168.12.13:5200".parse::<SocketAddr>().unwrap()]
+    );
+    assert_eq!(parsed.device_id.as_deref(), Some("9A403351"));
+    assert_eq!(parsed.key_fingerprint, None);
+    assert!(parsed.matches_key(None));
+
+    assert_eq!(
+        ShareLink::parse("https://s.intershare.app/3?i=abc"),
+        Err(ShareLinkError::UnsupportedFormat)
+    );
+    assert_eq!(
+        ShareLink::parse("https://example.com?i=abc&ip=192.168.12.13&p=5200"),
+        Err(ShareLinkError::Invalid)
+    );
+}
+
+#[test]
+pub fn share_link_includes_ipv6_addresses() {
+    let tcp_connection_info = TcpConnectionInfo {
+        hostname: "192.168.12.13".to_string(),
+        port: 5200,
+    };
+    let interface = |name: &str, ip: &str| (name.to_string(), ip.parse::<IpAddr>().unwrap());
+
+    let addresses = interface_addresses(
+        &tcp_connection_info,
+        &[
+            interface("lo", "fe80::12"),
+            interface("lo", "::1"),
+            interface("lo", "fd00::12"),
+            interface("lo", "10.0.0.2"),
+            interface("lo", "192.168.12.13"),
+            interface("unknown-interface", "fe80::13"),
+        ],
+    );
+
+    let addresses: Vec<String> = addresses.iter().map(SocketAddr::to_string).collect();
+    assert_eq!(
+        addresses[..3],
+        ["192.168.12.13:5200", "10.0.0.2:5200", "[fd00::12]:5200"]
+    );
+
+    // Link-local addresses need the interface as scope, which is only known for existing ones.
+    assert_eq!(addresses.len(), 4);
+    assert!(addresses[3].starts_with("[fe80::12%"));
+    assert!(addresses[3].ends_with("]:5200"));
+    assert!(!addresses[3].starts_with("[fe80::12%0]"));
+}
@@ -0,0 +1,104 @@
+use intershare_sdk::discovery::DiscoveryContext;
+use intershare_sdk::errors::ConvenienceDownloadError;
+use intershare_sdk::identity::IdentityStore;
+use intershare_sdk::protocol::discovery::DeviceConnectionInfo;
+use intershare_sdk::share_registry::{
+    ShareDelegate, ShareInvalidationReason, ShareLimits, ShareRegistry,
+};
+use intershare_sdk::ShareStore;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::RwLock;
+
+/// Records the shares it is told were invalidated.
+#[derive(Debug, Default, Clone)]
+struct InvalidationRecorder {
+    invalidated: Arc<Mutex<Vec<(String, ShareInvalidationReason)>>>,
+}
+
+impl ShareDelegate for InvalidationRecorder {
+    fn share_invalidated(&self, share_id: String, reason: ShareInvalidationReason) {
+        self.invalidated.lock().unwrap().push((share_id, reason));
+    }
+}
+
+fn share_store() -> Arc<ShareStore> {
+    return Arc::new(ShareStore::new(
+        None,
+        Some("Shared text".to_string()),
+        true,
+        Arc::new(RwLock::new(None)),
+        DeviceConnectionInfo::default(),
+        Arc::new(IdentityStore::new(None)),
+        DiscoveryContext::new(),
+    ));
+}
+
+#[test]
+pub fn share_limits_expiry() {
+    let limits = ShareLimits {
+        expires_in_seconds: Some(60),
+        max_downloads: None,
+    };
+    assert!(limits
+        .expires_at()
+        .is_some_and(|expires_at| expires_at > Instant::now()));
+
+    let limits = ShareLimits {
+        expires_in_seconds: Some(u64::MAX),
+        max_downloads: None,
+    };
+    assert_eq!(limits.expires_at(), None);
+
+    assert_eq!(ShareLimits::default().expires_at(), None);
+}
+
+#[test]
+pub fn share_downloads_count_once_completed() {
+    let registry = ShareRegistry::default();
+    let recorder = InvalidationRecorder::default();
+    registry.set_delegate(Box::new(recorder.clone()));
+
+    let share = share_store();
+    let share_id = share.request_id.clone();
+    registry.insert(
+        share,
+        &ShareLimits {
+            expires_in_seconds: None,
+            max_downloads: Some(1),
+        },
+    );
+
+    // The running download holds the only allowed one, a failed one doesn't use it up.
+    assert!(registry.start_download(Some(&share_id)).is_ok());
+    assert!(matches!(
+        registry.start_download(Some(&share_id)),
+        Err(ConvenienceDownloadError::DownloadLimitReached)
+    ));
+    registry.finish_download(&share_id, false);
+    assert!(recorder.invalidated.lock().unwrap().is_empty());
+
+    assert!(registry.start_download(Some(&share_id)).is_ok());
+    registry.finish_download(&share_id, true);
+    assert!(matches!(
+        registry.start_download(Some(&share_id)),
+        Err(ConvenienceDownloadError::DownloadLimitReached)
+    ));
+
+    let revoked_share = share_store();
+    let revoked_share_id = revoked_share.request_id.clone();
+    registry.insert(revoked_share, &ShareLimits::default());
+    assert!(registry.revoke(&revoked_share_id));
+    assert!(matches!(
+        registry.find(Some(&revoked_share_id)),
+        Err(ConvenienceDownloadError::Expired)
+    ));
+
+    assert_eq!(
+        *recorder.invalidated.lock().unwrap(),
+        vec![
+            (share_id, ShareInvalidationReason::DownloadLimitReached),
+            (revoked_share_id, ShareInvalidationReason::Revoked),
+        ]
+    );
+}
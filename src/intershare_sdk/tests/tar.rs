@@ -0,0 +1,444 @@
+use intershare_sdk::encryption::{
+    generate_key, AuthenticatedStream, EncryptedReadWrite, SessionKeys, STREAM_NONCE_LENGTH,
+};
+use intershare_sdk::errors::{ArchiveEntryError, FileIntegrityError};
+use intershare_sdk::protocol::communication::FileManifestEntry;
+use intershare_sdk::stream::Close;
+use intershare_sdk::tar::{
+    build_file_manifest, partial_file_offset, partial_transfer_dir, should_compress, stream_tar,
+    untar_stream_resumable, ArchiveEntry, ArchiveFile, ArchiveOptions,
+};
+use rand_core::{OsRng, RngCore};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+
+const TRANSFER_ID: &str = "4b0f6b3e-2c1d-4c55-9a57-1f3e7d0c9a21";
+
+/// In-memory transport, shared by the sending and the receiving stream.
+#[derive(Clone)]
+struct SharedBuffer {
+    data: Arc<Mutex<Vec<u8>>>,
+    position: usize,
+}
+
+impl SharedBuffer {
+    fn new() -> Self {
+        return Self {
+            data: Arc::new(Mutex::new(Vec::new())),
+            position: 0,
+        };
+    }
+
+    fn truncate(&self, length: usize) {
+        self.data.lock().unwrap().truncate(length);
+    }
+
+    fn len(&self) -> usize {
+        return self.data.lock().unwrap().len();
+    }
+}
+
+impl Read for SharedBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.data.lock().unwrap();
+        let read_bytes = buf.len().min(data.len() - self.position);
+
+        buf[..read_bytes].copy_from_slice(&data[self.position..self.position + read_bytes]);
+        self.position += read_bytes;
+
+        return Ok(read_bytes);
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.lock().unwrap().extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl Close for SharedBuffer {
+    fn close(&self) {}
+}
+
+/// Keys which read back what was written, so both ends can share one buffer.
+fn loopback_session_keys() -> SessionKeys {
+    let key = generate_key();
+    let mut nonce = [0u8; STREAM_NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+
+    return SessionKeys {
+        send_key: key,
+        send_nonce: nonce,
+        receive_key: key,
+        receive_nonce: nonce,
+    };
+}
+
+fn archive_options(compression: bool) -> ArchiveOptions {
+    return ArchiveOptions {
+        file_hashes: true,
+        compression,
+    };
+}
+
+fn write_random_file(path: &Path, size: usize) -> Vec<u8> {
+    let mut content = vec![0u8; size];
+    OsRng.fill_bytes(&mut content);
+
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, &content).unwrap();
+
+    return content;
+}
+
+fn send(
+    session_keys: &SessionKeys,
+    files: &[ArchiveFile],
+    offsets: &[u64],
+    options: &ArchiveOptions,
+) -> SharedBuffer {
+    let buffer = SharedBuffer::new();
+    let mut stream: Box<dyn EncryptedReadWrite> =
+        Box::new(AuthenticatedStream::new(session_keys, buffer.clone()));
+
+    let entries: Vec<ArchiveEntry> = files
+        .iter()
+        .zip(offsets)
+        .map(|(file, offset)| ArchiveEntry {
+            file,
+            offset: *offset,
+        })
+        .collect();
+
+    let total_bytes = entries
+        .iter()
+        .map(|entry| entry.file.manifest_entry.size - entry.offset)
+        .sum();
+
+    stream_tar(
+        &mut stream,
+        &entries,
+        total_bytes,
+        &None,
+        &AtomicBool::new(false),
+        options,
+    )
+    .expect("Failed to stream archive");
+    stream.finish().expect("Failed to finish stream");
+
+    return buffer;
+}
+
+fn receive(
+    session_keys: &SessionKeys,
+    buffer: &SharedBuffer,
+    dest_dir: &Path,
+    accepted_files: &[ArchiveFile],
+    options: &ArchiveOptions,
+) -> io::Result<Vec<String>> {
+    let mut stream: Box<dyn EncryptedReadWrite> =
+        Box::new(AuthenticatedStream::new(session_keys, buffer.clone()));
+    let staging_dir = partial_transfer_dir(dest_dir, TRANSFER_ID).unwrap();
+    let accepted_entries: Vec<FileManifestEntry> = accepted_files
+        .iter()
+        .map(|file| file.manifest_entry.clone())
+        .collect();
+
+    return untar_stream_resumable(
+        &mut stream,
+        dest_dir,
+        &staging_dir,
+        &accepted_entries,
+        0,
+        |_| {},
+        &AtomicBool::new(false),
+        options,
+    );
+}
+
+#[test]
+pub fn tar_round_trip_with_resume_offset() {
+    let source_dir = TempDir::new().unwrap();
+    let dest_dir = TempDir::new().unwrap();
+    let session_keys = loopback_session_keys();
+
+    let source_path = source_dir.path().join("document.bin");
+    let content = write_random_file(&source_path, 200_000);
+    let files = build_file_manifest(&[source_path.to_string_lossy().to_string()]).unwrap();
+
+    // A previous attempt received the first part of the file.
+    let staging_dir = partial_transfer_dir(dest_dir.path(), TRANSFER_ID).unwrap();
+    fs::create_dir_all(&staging_dir).unwrap();
+    fs::write(staging_dir.join("document.bin"), &content[..70_000]).unwrap();
+
+    let offset = partial_file_offset(&staging_dir, &files[0].manifest_entry);
+    assert_eq!(offset, 70_000);
+
+    let buffer = send(&session_keys, &files, &[offset], &archive_options(false));
+    let received_files = receive(
+        &session_keys,
+        &buffer,
+        dest_dir.path(),
+        &files,
+        &archive_options(false),
+    )
+    .unwrap();
+
+    assert_eq!(received_files.len(), 1);
+    assert_eq!(fs::read(&received_files[0]).unwrap(), content);
+    assert!(!staging_dir.exists());
+}
+
+#[test]
+pub fn tar_files_with_the_same_name() {
+    let source_dir = TempDir::new().unwrap();
+    let dest_dir = TempDir::new().unwrap();
+    let session_keys = loopback_session_keys();
+
+    let first_path = source_dir.path().join("a").join("x.txt");
+    let second_path = source_dir.path().join("b").join("x.txt");
+    let first_content = write_random_file(&first_path, 1_000);
+    let second_content = write_random_file(&second_path, 2_000);
+
+    let files = build_file_manifest(&[
+        first_path.to_string_lossy().to_string(),
+        second_path.to_string_lossy().to_string(),
+    ])
+    .unwrap();
+
+    assert_eq!(files[0].manifest_entry.relative_path, "x.txt");
+    assert_eq!(files[1].manifest_entry.relative_path, "x (1).txt");
+
+    let buffer = send(&session_keys, &files, &[0, 0], &archive_options(false));
+    let received_files = receive(
+        &session_keys,
+        &buffer,
+        dest_dir.path(),
+        &files,
+        &archive_options(false),
+    )
+    .unwrap();
+
+    assert_eq!(received_files.len(), 2);
+    assert_eq!(fs::read(&received_files[0]).unwrap(), first_content);
+    assert_eq!(fs::read(&received_files[1]).unwrap(), second_content);
+}
+
+#[test]
+pub fn tar_resume_interrupted_transfer() {
+    let source_dir = TempDir::new().unwrap();
+    let dest_dir = TempDir::new().unwrap();
+    let session_keys = loopback_session_keys();
+
+    let first_path = source_dir.path().join("first.bin");
+    let second_path = source_dir.path().join("second.bin");
+    let first_content = write_random_file(&first_path, 300_000);
+    let second_content = write_random_file(&second_path, 300_000);
+
+    let files = build_file_manifest(&[
+        first_path.to_string_lossy().to_string(),
+        second_path.to_string_lossy().to_string(),
+    ])
+    .unwrap();
+
+    // The connection drops in the middle of the second file.
+    let buffer = send(&session_keys, &files, &[0, 0], &archive_options(false));
+    buffer.truncate(buffer.len() * 3 / 4);
+    assert!(receive(
+        &session_keys,
+        &buffer,
+        dest_dir.path(),
+        &files,
+        &archive_options(false),
+    )
+    .is_err());
+
+    let staging_dir = partial_transfer_dir(dest_dir.path(), TRANSFER_ID).unwrap();
+    let offsets: Vec<u64> = files
+        .iter()
+        .map(|file| partial_file_offset(&staging_dir, &file.manifest_entry))
+        .collect();
+
+    assert_eq!(offsets[0], 300_000);
+    assert!(offsets[1] > 0 && offsets[1] < 300_000);
+
+    let buffer = send(&session_keys, &files, &offsets, &archive_options(false));
+    let received_files = receive(
+        &session_keys,
+        &buffer,
+        dest_dir.path(),
+        &files,
+        &archive_options(false),
+    )
+    .unwrap();
+
+    assert_eq!(received_files.len(), 2);
+    assert_eq!(fs::read(&received_files[0]).unwrap(), first_content);
+    assert_eq!(fs::read(&received_files[1]).unwrap(), second_content);
+    assert!(!staging_dir.exists());
+}
+
+#[test]
+pub fn tar_detects_corrupted_partial_file() {
+    let source_dir = TempDir::new().unwrap();
+    let dest_dir = TempDir::new().unwrap();
+    let session_keys = loopback_session_keys();
+
+    let source_path = source_dir.path().join("document.bin");
+    write_random_file(&source_path, 100_000);
+    let files = build_file_manifest(&[source_path.to_string_lossy().to_string()]).unwrap();
+
+    // The staged part of the file doesn't match the file of the sender.
+    let staging_dir = partial_transfer_dir(dest_dir.path(), TRANSFER_ID).unwrap();
+    let partial_path = staging_dir.join("document.bin");
+    write_random_file(&partial_path, 40_000);
+
+    let buffer = send(&session_keys, &files, &[40_000], &archive_options(false));
+    let error = receive(
+        &session_keys,
+        &buffer,
+        dest_dir.path(),
+        &files,
+        &archive_options(false),
+    )
+    .unwrap_err();
+
+    assert!(matches!(
+        error.get_ref().unwrap().downcast_ref::<FileIntegrityError>(),
+        Some(FileIntegrityError::HashMismatch { files }) if files == &["document.bin"]
+    ));
+
+    // Removed, so the next attempt doesn't resume from it.
+    assert!(!partial_path.exists());
+    assert!(!dest_dir.path().join("document.bin").exists());
+}
+
+#[test]
+pub fn tar_rejects_files_which_were_not_accepted() {
+    let source_dir = TempDir::new().unwrap();
+    let dest_dir = TempDir::new().unwrap();
+    let session_keys = loopback_session_keys();
+
+    let first_path = source_dir.path().join("first.bin");
+    let second_path = source_dir.path().join("second.bin");
+    write_random_file(&first_path, 1_000);
+    write_random_file(&second_path, 1_000);
+
+    let files = build_file_manifest(&[
+        first_path.to_string_lossy().to_string(),
+        second_path.to_string_lossy().to_string(),
+    ])
+    .unwrap();
+
+    // Only the first file was accepted, but the sender sends both.
+    let buffer = send(&session_keys, &files, &[0, 0], &archive_options(false));
+    let error = receive(
+        &session_keys,
+        &buffer,
+        dest_dir.path(),
+        &files[..1],
+        &archive_options(false),
+    )
+    .unwrap_err();
+
+    assert!(matches!(
+        error.get_ref().unwrap().downcast_ref::<ArchiveEntryError>(),
+        Some(ArchiveEntryError::NotAccepted { .. })
+    ));
+    assert!(!dest_dir.path().join("second.bin").exists());
+
+    // The manifest announced a different size than the sender sends.
+    let announced_files: Vec<ArchiveFile> = files
+        .iter()
+        .map(|file| ArchiveFile {
+            source_path: file.source_path.clone(),
+            manifest_entry: FileManifestEntry {
+                size: 500,
+                ..file.manifest_entry.clone()
+            },
+        })
+        .collect();
+
+    let buffer = send(&session_keys, &files, &[0, 0], &archive_options(false));
+    let error = receive(
+        &session_keys,
+        &buffer,
+        dest_dir.path(),
+        &announced_files,
+        &archive_options(false),
+    )
+    .unwrap_err();
+
+    assert!(matches!(
+        error.get_ref().unwrap().downcast_ref::<ArchiveEntryError>(),
+        Some(ArchiveEntryError::SizeMismatch { .. })
+    ));
+}
+
+#[test]
+pub fn tar_compresses_only_compressible_files() {
+    let source_dir = TempDir::new().unwrap();
+    let dest_dir = TempDir::new().unwrap();
+    let session_keys = loopback_session_keys();
+
+    let text_path = source_dir.path().join("notes.txt");
+    let text_content = "All work and no play makes Jack a dull boy.\n".repeat(5_000);
+    fs::write(&text_path, &text_content).unwrap();
+    let photo_path = source_dir.path().join("photo.jpg");
+    let photo_content = write_random_file(&photo_path, 100_000);
+    let data_path = source_dir.path().join("data.bin");
+    let data_content = write_random_file(&data_path, 100_000);
+
+    let files = build_file_manifest(&[
+        text_path.to_string_lossy().to_string(),
+        photo_path.to_string_lossy().to_string(),
+        data_path.to_string_lossy().to_string(),
+    ])
+    .unwrap();
+
+    let compressed_files: Vec<bool> = files
+        .iter()
+        .map(|file| should_compress(&ArchiveEntry { file, offset: 0 }))
+        .collect();
+    assert_eq!(compressed_files, [true, false, false]);
+
+    let uncompressed_buffer = send(&session_keys, &files, &[0, 0, 0], &archive_options(false));
+    let buffer = send(&session_keys, &files, &[0, 0, 0], &archive_options(true));
+    assert!(buffer.len() + text_content.len() / 2 < uncompressed_buffer.len());
+
+    // A receiver which didn't negotiate compression refuses compressed entries.
+    assert!(receive(
+        &session_keys,
+        &buffer,
+        dest_dir.path(),
+        &files,
+        &archive_options(false),
+    )
+    .is_err());
+
+    let received_files = receive(
+        &session_keys,
+        &buffer,
+        dest_dir.path(),
+        &files,
+        &archive_options(true),
+    )
+    .unwrap();
+
+    assert_eq!(received_files.len(), 3);
+    assert_eq!(
+        fs::read(&received_files[0]).unwrap(),
+        text_content.as_bytes()
+    );
+    assert_eq!(fs::read(&received_files[1]).unwrap(), photo_content);
+    assert_eq!(fs::read(&received_files[2]).unwrap(), data_content);
+}
@@ -0,0 +1,319 @@
+use intershare_sdk::discovery::{DiscoveryContext, InternalDiscovery};
+use intershare_sdk::protocol::discovery::device_discovery_message::Content;
+use intershare_sdk::protocol::discovery::{
+    Device, DeviceConnectionInfo, DeviceDiscoveryMessage, TcpConnectionInfo,
+};
+use intershare_sdk::protocol::prost::Message;
+use intershare_sdk::tar::partial_transfer_dir;
+use intershare_sdk::{
+    CancelReason, ConnectErrors, ConnectionRequest, InternalNearbyServer, NearbyConnectionDelegate,
+    ReceiveProgressDelegate, ReceiveProgressState, SendProgressDelegate, SendProgressState,
+};
+use rand_core::{OsRng, RngCore};
+use std::fs;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::runtime::Runtime;
+
+/// Passes every incoming request on to the test.
+#[derive(Debug)]
+struct RequestForwarder {
+    requests: Mutex<Sender<Arc<ConnectionRequest>>>,
+}
+
+impl NearbyConnectionDelegate for RequestForwarder {
+    fn received_connection_request(&self, request: Arc<ConnectionRequest>) {
+        let _ = self.requests.lock().unwrap().send(request);
+    }
+}
+
+/// Records the verification codes the sender shows.
+#[derive(Debug, Default, Clone)]
+struct SendProgressRecorder {
+    verification_codes: Arc<Mutex<Vec<String>>>,
+}
+
+impl SendProgressDelegate for SendProgressRecorder {
+    fn progress_changed(&self, progress: SendProgressState) {
+        if let SendProgressState::VerificationCode { code } = progress {
+            self.verification_codes.lock().unwrap().push(code);
+        }
+    }
+}
+
+/// Records why the peer cancelled the transfer, if it did.
+#[derive(Debug, Default, Clone)]
+struct ReceiveProgressRecorder {
+    peer_cancel: Arc<Mutex<Option<CancelReason>>>,
+}
+
+impl ReceiveProgressDelegate for ReceiveProgressRecorder {
+    fn progress_changed(&self, progress: ReceiveProgressState) {
+        if let ReceiveProgressState::CancelledByPeer { reason } = progress {
+            *self.peer_cancel.lock().unwrap() = Some(reason);
+        }
+    }
+}
+
+fn device(id: &str) -> Device {
+    return Device {
+        id: id.to_string(),
+        name: "Test device".to_string(),
+        device_type: 0,
+        protocol_version: None,
+    };
+}
+
+/// A receiving and a sending server, connected over TCP on the loopback interface.
+struct Peers {
+    runtime: Runtime,
+    receiver_device: Device,
+    sender: InternalNearbyServer,
+    requests: Receiver<Arc<ConnectionRequest>>,
+    receiver: InternalNearbyServer,
+    receiver_storage: TempDir,
+    _sender_storage: TempDir,
+}
+
+impl Peers {
+    fn start() -> Self {
+        // The accept loop of the receiver occupies a worker of its own.
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(4)
+            .enable_all()
+            .build()
+            .unwrap();
+        let receiver_storage = TempDir::new().unwrap();
+        let sender_storage = TempDir::new().unwrap();
+        let (request_sender, requests) = channel();
+
+        let receiver_device = device("c6f0a8a4-6f64-4cf5-9b0e-2d1ee2a4f1d1");
+        let receiver = InternalNearbyServer::new(
+            receiver_device.clone(),
+            receiver_storage.path().to_string_lossy().to_string(),
+            Some(Box::new(RequestForwarder {
+                requests: Mutex::new(request_sender),
+            })),
+        );
+        runtime.block_on(receiver.start());
+
+        let port = runtime
+            .block_on(receiver.device_connection_info.read())
+            .tcp
+            .as_ref()
+            .expect("TCP server did not start")
+            .port;
+
+        let discovery_message = DeviceDiscoveryMessage {
+            content: Some(Content::DeviceConnectionInfo(DeviceConnectionInfo {
+                device: Some(receiver_device.clone()),
+                tcp: Some(TcpConnectionInfo {
+                    hostname: "127.0.0.1".to_string(),
+                    port,
+                }),
+                ble: None,
+            })),
+        };
+
+        let discovery_context = DiscoveryContext::new();
+        InternalDiscovery::with_context(None, discovery_context.clone())
+            .unwrap()
+            .parse_discovery_message(discovery_message.encode_length_delimited_to_vec(), None);
+
+        // Started as well, so the receiver can download shares of the sender.
+        let (sender_request_sender, _) = channel();
+        let sender = InternalNearbyServer::new(
+            device("5b1d7f0e-3c2a-4f7b-8e59-9a4c0d6e2b13"),
+            sender_storage.path().to_string_lossy().to_string(),
+            Some(Box::new(RequestForwarder {
+                requests: Mutex::new(sender_request_sender),
+            })),
+        );
+        sender.set_discovery_context(discovery_context);
+        runtime.block_on(sender.start());
+
+        return Self {
+            runtime,
+            receiver_device,
+            sender,
+            requests,
+            receiver,
+            receiver_storage,
+            _sender_storage: sender_storage,
+        };
+    }
+
+    fn next_request(&self) -> Arc<ConnectionRequest> {
+        return self.requests.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+}
+
+impl Drop for Peers {
+    fn drop(&mut self) {
+        self.runtime.block_on(self.receiver.stop());
+        self.runtime.block_on(self.sender.stop());
+    }
+}
+
+#[test]
+pub fn text_is_sent_after_accept() {
+    let peers = Peers::start();
+    let share = peers
+        .runtime
+        .block_on(peers.sender.share_text("Shared text".to_string(), false));
+
+    let sending_share = share.clone();
+    let receiver_device = peers.receiver_device.clone();
+    let send_task = peers.runtime.spawn(async move {
+        sending_share
+            .send_to(
+                receiver_device,
+                Some(Box::new(SendProgressRecorder::default())),
+            )
+            .await
+    });
+
+    let request = peers.next_request();
+    assert_eq!(
+        request.get_clipboard_intent().unwrap().clipboard_content,
+        ""
+    );
+
+    assert_eq!(request.accept(), Some(vec![]));
+    assert_eq!(
+        request.get_clipboard_intent().unwrap().clipboard_content,
+        "Shared text"
+    );
+    assert!(peers.runtime.block_on(send_task).unwrap().is_ok());
+}
+
+#[test]
+pub fn rejected_verification_code_sends_no_text() {
+    let peers = Peers::start();
+    let share = peers
+        .runtime
+        .block_on(peers.sender.share_text("Shared text".to_string(), false));
+    let send_progress = SendProgressRecorder::default();
+
+    let sending_share = share.clone();
+    let receiver_device = peers.receiver_device.clone();
+    let sending_progress = send_progress.clone();
+    let send_task = peers.runtime.spawn(async move {
+        sending_share
+            .send_to(receiver_device, Some(Box::new(sending_progress)))
+            .await
+    });
+
+    let request = peers.next_request();
+    let verification_code = send_progress.verification_codes.lock().unwrap()[0].clone();
+    assert_eq!(
+        request.get_verification_code(),
+        Some(verification_code.clone())
+    );
+
+    share.reject_verification_code(verification_code);
+    let send_result = peers.runtime.block_on(send_task).unwrap();
+    assert!(matches!(
+        send_result,
+        Err(ConnectErrors::VerificationRejected)
+    ));
+
+    let receive_progress = ReceiveProgressRecorder::default();
+    request.set_progress_delegate(Box::new(receive_progress.clone()));
+
+    assert_eq!(request.accept(), None);
+    assert_eq!(
+        request.get_clipboard_intent().unwrap().clipboard_content,
+        ""
+    );
+    assert_eq!(
+        *receive_progress.peer_cancel.lock().unwrap(),
+        Some(CancelReason::VerificationCodeRejected)
+    );
+}
+
+#[test]
+pub fn receiver_resumes_download() {
+    let peers = Peers::start();
+    let source_dir = TempDir::new().unwrap();
+    let source_path = source_dir.path().join("video.bin");
+    let mut content = vec![0u8; 300_000];
+    OsRng.fill_bytes(&mut content);
+    fs::write(&source_path, &content).unwrap();
+
+    let share = peers.runtime.block_on(
+        peers
+            .sender
+            .share_files(vec![source_path.to_string_lossy().to_string()], true),
+    );
+    let link = share.generate_link().unwrap();
+
+    let request = peers
+        .runtime
+        .block_on(peers.receiver.request_download(link.clone()))
+        .unwrap();
+    let transfer_id = request
+        .get_file_transfer_intent()
+        .unwrap()
+        .transfer_id
+        .unwrap();
+    drop(request);
+
+    // The connection dropped after the first part of the file was received.
+    let staging_dir = partial_transfer_dir(peers.receiver_storage.path(), &transfer_id).unwrap();
+    fs::create_dir_all(&staging_dir).unwrap();
+    fs::write(staging_dir.join("video.bin"), &content[..100_000]).unwrap();
+
+    let request = peers
+        .runtime
+        .block_on(peers.receiver.request_download(link))
+        .unwrap();
+    assert_eq!(request.get_resumable_bytes(), 100_000);
+
+    let received_files = request.accept().unwrap();
+    assert_eq!(received_files.len(), 1);
+    assert_eq!(fs::read(&received_files[0]).unwrap(), content);
+    assert!(!staging_dir.exists());
+}
+
+#[test]
+pub fn only_selected_files_are_sent() {
+    let peers = Peers::start();
+    let source_dir = TempDir::new().unwrap();
+    let first_path = source_dir.path().join("first.txt");
+    let second_path = source_dir.path().join("second.txt");
+    fs::write(&first_path, "First file").unwrap();
+    fs::write(&second_path, "Second file").unwrap();
+
+    let share = peers.runtime.block_on(peers.sender.share_files(
+        vec![
+            first_path.to_string_lossy().to_string(),
+            second_path.to_string_lossy().to_string(),
+        ],
+        false,
+    ));
+
+    let receiver_device = peers.receiver_device.clone();
+    let send_task = peers.runtime.spawn(async move {
+        share
+            .send_to(
+                receiver_device,
+                Some(Box::new(SendProgressRecorder::default())),
+            )
+            .await
+    });
+
+    let request = peers.next_request();
+    assert_eq!(request.get_file_manifest().len(), 2);
+
+    let received_files = request.accept_selected(vec![1]).unwrap();
+    assert_eq!(received_files.len(), 1);
+    assert_eq!(
+        fs::read_to_string(&received_files[0]).unwrap(),
+        "Second file"
+    );
+    assert!(!peers.receiver_storage.path().join("first.txt").exists());
+    assert!(peers.runtime.block_on(send_task).unwrap().is_ok());
+}